@@ -0,0 +1,36 @@
+-- migrations/20240727000001_unique_live_names.sql
+
+-- The table-level UNIQUE (owner_id, parent_id, name) never fires for top-level nodes (NULL
+-- parent_id values are all distinct) and wrongly blocks reusing the name of a trashed node.
+-- Rebuild the table without it and enforce unique names among live siblings with a partial index.
+PRAGMA defer_foreign_keys = ON;
+
+ALTER TABLE files RENAME TO files_old;
+
+CREATE TABLE files (
+    id INTEGER PRIMARY KEY,
+    owner_id INTEGER NOT NULL,
+    parent_id INTEGER,
+    name TEXT NOT NULL,
+    node_type TEXT NOT NULL CHECK(node_type IN ('dir', 'file')),
+    disk_path TEXT UNIQUE,
+    size INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    original_path TEXT NOT NULL,
+    is_trashed BOOLEAN NOT NULL DEFAULT FALSE,
+    trashed_at TIMESTAMP,
+    FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (parent_id) REFERENCES files (id) ON DELETE CASCADE
+);
+
+INSERT INTO files (id, owner_id, parent_id, name, node_type, disk_path, size, created_at, updated_at, original_path, is_trashed, trashed_at)
+SELECT id, owner_id, parent_id, name, node_type, disk_path, size, created_at, updated_at, original_path, is_trashed, trashed_at
+FROM files_old;
+
+DROP TABLE files_old;
+
+CREATE INDEX IF NOT EXISTS idx_files_parent ON files (parent_id);
+CREATE INDEX IF NOT EXISTS idx_files_owner ON files (owner_id);
+CREATE INDEX IF NOT EXISTS idx_files_trashed ON files (owner_id, is_trashed);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_files_live_name ON files (owner_id, IFNULL(parent_id, 0), name) WHERE is_trashed = FALSE;
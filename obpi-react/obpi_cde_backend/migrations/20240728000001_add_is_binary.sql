@@ -0,0 +1,5 @@
+-- migrations/20240728000001_add_is_binary.sql
+
+-- Whether the file's content looked binary when it was last written, so clients can
+-- pick a text or hex view without downloading it first.
+ALTER TABLE files ADD COLUMN is_binary BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,13 @@
+-- migrations/20240729000001_add_command_history.sql
+
+-- Commands captured from users' shells, so history survives across sessions.
+CREATE TABLE IF NOT EXISTS command_history (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    session_id TEXT NOT NULL,
+    command TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_command_history_user ON command_history (user_id, id);
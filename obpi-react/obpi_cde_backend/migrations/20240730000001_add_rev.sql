@@ -0,0 +1,5 @@
+-- migrations/20240730000001_add_rev.sql
+
+-- Content revision, bumped on every write. Clients send it back as `if_none_match` to skip
+-- re-reading files they already hold.
+ALTER TABLE files ADD COLUMN rev INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- migrations/20240731000001_add_mode.sql
+
+-- Unix-style permission bits. Existing nodes get what the default umask (022) would have
+-- produced: 0644 for files and 0755 for directories.
+ALTER TABLE files ADD COLUMN mode INTEGER NOT NULL DEFAULT 420;
+UPDATE files SET mode = 493 WHERE node_type = 'dir';
@@ -0,0 +1,4 @@
+-- migrations/20240801000001_add_quota.sql
+
+-- Per-user storage quota in bytes, counting trashed files too. NULL means unlimited.
+ALTER TABLE users ADD COLUMN quota_bytes INTEGER;
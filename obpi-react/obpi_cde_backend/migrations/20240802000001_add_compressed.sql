@@ -0,0 +1,4 @@
+-- migrations/20240802000001_add_compressed.sql
+
+-- Whether the file's disk contents are gzipped. `size` is always the uncompressed size.
+ALTER TABLE files ADD COLUMN compressed BOOLEAN NOT NULL DEFAULT FALSE;
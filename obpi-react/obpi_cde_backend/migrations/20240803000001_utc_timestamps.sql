@@ -0,0 +1,9 @@
+-- migrations/20240803000001_utc_timestamps.sql
+
+-- Timestamps are now always written by the application as RFC 3339 UTC, the format sqlx uses
+-- for `DateTime<Utc>`. Rows that took SQLite's `CURRENT_TIMESTAMP` default ("YYYY-MM-DD HH:MM:SS",
+-- also UTC) are rewritten to match so text comparisons and ORDER BY stay chronological.
+UPDATE users SET created_at = strftime('%Y-%m-%dT%H:%M:%S+00:00', created_at) WHERE created_at NOT LIKE '%T%';
+UPDATE files SET created_at = strftime('%Y-%m-%dT%H:%M:%S+00:00', created_at) WHERE created_at NOT LIKE '%T%';
+UPDATE files SET updated_at = strftime('%Y-%m-%dT%H:%M:%S+00:00', updated_at) WHERE updated_at NOT LIKE '%T%';
+UPDATE command_history SET created_at = strftime('%Y-%m-%dT%H:%M:%S+00:00', created_at) WHERE created_at NOT LIKE '%T%';
@@ -0,0 +1,31 @@
+-- migrations/20240804000001_nest_home_dirs.sql
+
+-- Home directories used to be created as a single root node literally named "/home/<user>",
+-- which path resolution (one component at a time) could never find. Give each such owner a
+-- real "home" directory and move the node under it as "<user>", unless that name is taken.
+INSERT INTO files (owner_id, parent_id, name, node_type, mode, original_path, created_at, updated_at)
+SELECT DISTINCT f.owner_id, NULL, 'home', 'dir', 493, '/home',
+    strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now'), strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now')
+FROM files f
+WHERE f.parent_id IS NULL AND f.node_type = 'dir' AND f.name LIKE '/home/%' AND f.is_trashed = FALSE
+    AND NOT EXISTS (
+        SELECT 1 FROM files h
+        WHERE h.owner_id = f.owner_id AND h.parent_id IS NULL AND h.name = 'home' AND h.is_trashed = FALSE
+    );
+
+UPDATE files
+SET parent_id = (
+        SELECT h.id FROM files h
+        WHERE h.owner_id = files.owner_id AND h.parent_id IS NULL AND h.name = 'home' AND h.node_type = 'dir' AND h.is_trashed = FALSE
+    ),
+    name = substr(name, 7)
+WHERE parent_id IS NULL AND node_type = 'dir' AND name LIKE '/home/%' AND is_trashed = FALSE
+    AND EXISTS (
+        SELECT 1 FROM files h
+        WHERE h.owner_id = files.owner_id AND h.parent_id IS NULL AND h.name = 'home' AND h.node_type = 'dir' AND h.is_trashed = FALSE
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM files h JOIN files u ON u.parent_id = h.id
+        WHERE h.owner_id = files.owner_id AND h.parent_id IS NULL AND h.name = 'home' AND h.is_trashed = FALSE
+            AND u.name = substr(files.name, 7) AND u.is_trashed = FALSE
+    );
@@ -0,0 +1,5 @@
+-- migrations/20240805000001_add_startup_command.sql
+
+-- Command typed into the user's terminal once the shell is ready. NULL falls back to the
+-- server-wide STARTUP_COMMAND.
+ALTER TABLE users ADD COLUMN startup_command TEXT;
@@ -0,0 +1,11 @@
+-- migrations/20240806000001_add_file_attrs.sql
+
+-- Opaque per-file client state such as an editor's cursor position. Rows are keyed by file id,
+-- so they follow the file through moves and go with it when it's deleted.
+CREATE TABLE IF NOT EXISTS file_attrs (
+    file_id INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (file_id, key),
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
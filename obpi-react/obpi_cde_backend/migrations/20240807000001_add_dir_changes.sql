@@ -0,0 +1,38 @@
+-- migrations/20240807000001_add_dir_changes.sql
+
+-- Which children of each directory changed, for `VfsGetTreeDelta`. `seq` is the version: a
+-- directory's version is the highest `seq` logged against it (`dir_id` is NULL for the root).
+-- Only a node's latest change per directory is kept, and rows outlive the node so deletions
+-- can still be reported. The triggers keep this in step with every mutation's transaction.
+CREATE TABLE IF NOT EXISTS dir_changes (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id INTEGER NOT NULL,
+    dir_id INTEGER,
+    node_id INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_dir_changes_dir ON dir_changes (owner_id, dir_id, seq);
+CREATE INDEX IF NOT EXISTS idx_dir_changes_node ON dir_changes (node_id);
+
+INSERT INTO dir_changes (owner_id, dir_id, node_id) SELECT owner_id, parent_id, id FROM files;
+
+CREATE TRIGGER IF NOT EXISTS dir_changes_insert AFTER INSERT ON files
+BEGIN
+    DELETE FROM dir_changes WHERE node_id = NEW.id AND dir_id IS NEW.parent_id;
+    INSERT INTO dir_changes (owner_id, dir_id, node_id) VALUES (NEW.owner_id, NEW.parent_id, NEW.id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS dir_changes_update AFTER UPDATE ON files
+WHEN OLD.parent_id IS NOT NEW.parent_id OR OLD.name IS NOT NEW.name OR OLD.is_trashed IS NOT NEW.is_trashed
+    OR OLD.size IS NOT NEW.size OR OLD.updated_at IS NOT NEW.updated_at OR OLD.mode IS NOT NEW.mode
+BEGIN
+    DELETE FROM dir_changes WHERE node_id = NEW.id AND (dir_id IS NEW.parent_id OR dir_id IS OLD.parent_id);
+    INSERT INTO dir_changes (owner_id, dir_id, node_id)
+        SELECT OLD.owner_id, OLD.parent_id, OLD.id WHERE OLD.parent_id IS NOT NEW.parent_id;
+    INSERT INTO dir_changes (owner_id, dir_id, node_id) VALUES (NEW.owner_id, NEW.parent_id, NEW.id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS dir_changes_delete AFTER DELETE ON files
+BEGIN
+    DELETE FROM dir_changes WHERE node_id = OLD.id AND dir_id IS OLD.parent_id;
+    INSERT INTO dir_changes (owner_id, dir_id, node_id) VALUES (OLD.owner_id, OLD.parent_id, OLD.id);
+END;
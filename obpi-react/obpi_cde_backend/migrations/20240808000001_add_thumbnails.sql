@@ -0,0 +1,15 @@
+-- migrations/20240808000001_add_thumbnails.sql
+
+-- Scaled-down copies of image files for `VfsThumbnail`. Only the latest rev is kept for each
+-- size; a row whose rev is behind the file's is stale and gets replaced on the next request.
+CREATE TABLE IF NOT EXISTS thumbnails (
+    file_id INTEGER NOT NULL,
+    max_dim INTEGER NOT NULL,
+    rev INTEGER NOT NULL,
+    mime TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (file_id, max_dim),
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
@@ -0,0 +1,116 @@
+-- migrations_postgres/20240808000001_initial_schema.sql
+
+-- The schema `migrations` arrives at as of 20240808000001, written for Postgres. Later schema
+-- changes add a migration to both directories under the same version.
+
+CREATE TABLE IF NOT EXISTS users (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    role TEXT NOT NULL CHECK(role IN ('Admin', 'Standard', 'Limited')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- Storage quota in bytes, counting trashed files too. NULL means unlimited.
+    quota_bytes BIGINT,
+    -- Typed into the terminal once the shell is ready. NULL falls back to STARTUP_COMMAND.
+    startup_command TEXT
+);
+
+CREATE TABLE IF NOT EXISTS files (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    owner_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    parent_id BIGINT REFERENCES files (id) ON DELETE CASCADE,
+    -- Compared byte by byte, as SQLite does, so listings sort the same on both.
+    name TEXT COLLATE "C" NOT NULL,
+    node_type TEXT NOT NULL CHECK(node_type IN ('dir', 'file')),
+    disk_path TEXT UNIQUE,
+    -- Always the uncompressed size.
+    size BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    original_path TEXT COLLATE "C" NOT NULL,
+    is_trashed BOOLEAN NOT NULL DEFAULT FALSE,
+    trashed_at TIMESTAMPTZ,
+    -- Whether the content looked binary when it was last written.
+    is_binary BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Content revision, bumped on every write.
+    rev BIGINT NOT NULL DEFAULT 0,
+    -- Unix-style permission bits.
+    mode BIGINT NOT NULL DEFAULT 420,
+    -- Whether the disk contents are gzipped.
+    compressed BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS idx_files_parent ON files (parent_id);
+CREATE INDEX IF NOT EXISTS idx_files_owner ON files (owner_id);
+CREATE INDEX IF NOT EXISTS idx_files_trashed ON files (owner_id, is_trashed);
+-- Names are unique among live siblings; top-level nodes count as siblings under parent 0.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_files_live_name ON files (owner_id, COALESCE(parent_id, 0), name) WHERE is_trashed = FALSE;
+
+CREATE TABLE IF NOT EXISTS command_history (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    session_id TEXT NOT NULL,
+    command TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_command_history_user ON command_history (user_id, id);
+
+CREATE TABLE IF NOT EXISTS file_attrs (
+    file_id BIGINT NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (file_id, key)
+);
+
+-- See the SQLite migration of the same version for how `dir_changes` works.
+CREATE TABLE IF NOT EXISTS dir_changes (
+    seq BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    owner_id BIGINT NOT NULL,
+    dir_id BIGINT,
+    node_id BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_dir_changes_dir ON dir_changes (owner_id, dir_id, seq);
+CREATE INDEX IF NOT EXISTS idx_dir_changes_node ON dir_changes (node_id);
+
+CREATE OR REPLACE FUNCTION log_dir_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        DELETE FROM dir_changes WHERE node_id = NEW.id AND dir_id IS NOT DISTINCT FROM NEW.parent_id;
+        INSERT INTO dir_changes (owner_id, dir_id, node_id) VALUES (NEW.owner_id, NEW.parent_id, NEW.id);
+    ELSIF TG_OP = 'UPDATE' THEN
+        DELETE FROM dir_changes WHERE node_id = NEW.id
+            AND (dir_id IS NOT DISTINCT FROM NEW.parent_id OR dir_id IS NOT DISTINCT FROM OLD.parent_id);
+        IF OLD.parent_id IS DISTINCT FROM NEW.parent_id THEN
+            INSERT INTO dir_changes (owner_id, dir_id, node_id) VALUES (OLD.owner_id, OLD.parent_id, OLD.id);
+        END IF;
+        INSERT INTO dir_changes (owner_id, dir_id, node_id) VALUES (NEW.owner_id, NEW.parent_id, NEW.id);
+    ELSE
+        DELETE FROM dir_changes WHERE node_id = OLD.id AND dir_id IS NOT DISTINCT FROM OLD.parent_id;
+        INSERT INTO dir_changes (owner_id, dir_id, node_id) VALUES (OLD.owner_id, OLD.parent_id, OLD.id);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER dir_changes_insert AFTER INSERT ON files
+    FOR EACH ROW EXECUTE FUNCTION log_dir_change();
+CREATE TRIGGER dir_changes_update AFTER UPDATE ON files
+    FOR EACH ROW
+    WHEN (OLD.parent_id IS DISTINCT FROM NEW.parent_id OR OLD.name IS DISTINCT FROM NEW.name
+        OR OLD.is_trashed IS DISTINCT FROM NEW.is_trashed OR OLD.size IS DISTINCT FROM NEW.size
+        OR OLD.updated_at IS DISTINCT FROM NEW.updated_at OR OLD.mode IS DISTINCT FROM NEW.mode)
+    EXECUTE FUNCTION log_dir_change();
+CREATE TRIGGER dir_changes_delete AFTER DELETE ON files
+    FOR EACH ROW EXECUTE FUNCTION log_dir_change();
+
+CREATE TABLE IF NOT EXISTS thumbnails (
+    file_id BIGINT NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+    max_dim BIGINT NOT NULL,
+    rev BIGINT NOT NULL,
+    mime TEXT NOT NULL,
+    width BIGINT NOT NULL,
+    height BIGINT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (file_id, max_dim)
+);
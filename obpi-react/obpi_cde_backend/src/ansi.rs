@@ -0,0 +1,169 @@
+use std::path::PathBuf;
+
+const OSC7_START: &str = "\x1b]7;";
+/// An unterminated OSC 7 longer than this is treated as ordinary output rather than held back.
+const MAX_OSC_LEN: usize = 4096;
+const BRACKETED_PASTE_ON: &str = "\x1b[?2004h";
+const BRACKETED_PASTE_OFF: &str = "\x1b[?2004l";
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+/// Pulls OSC 7 working-directory reports (`ESC ] 7 ; file://host/path` ended by BEL or ST)
+/// out of PTY output. Sequences split across reads are held until their terminator arrives.
+pub struct Osc7Parser {
+    pending: String,
+    passthrough: bool,
+}
+
+impl Osc7Parser {
+    pub fn new(passthrough: bool) -> Self {
+        Self { pending: String::new(), passthrough }
+    }
+
+    /// Returns the output to forward to the client and the last directory reported in it.
+    pub fn feed(&mut self, chunk: &str) -> (String, Option<PathBuf>) {
+        let text = std::mem::take(&mut self.pending) + chunk;
+        let mut output = String::with_capacity(text.len());
+        let mut cwd = None;
+        let mut pos = 0;
+
+        while let Some(offset) = text[pos..].find(OSC7_START) {
+            let start = pos + offset;
+            let body = start + OSC7_START.len();
+            let Some((body_end, seq_end)) = find_terminator(&text[body..]).map(|(e, t)| (body + e, body + t)) else {
+                if text.len() - start <= MAX_OSC_LEN {
+                    output.push_str(&text[pos..start]);
+                    self.pending = text[start..].to_string();
+                    return (output, cwd);
+                }
+                break;
+            };
+            output.push_str(&text[pos..start]);
+            if self.passthrough {
+                output.push_str(&text[start..seq_end]);
+            }
+            if let Some(path) = parse_file_url(&text[body..body_end]) {
+                cwd = Some(path);
+            }
+            pos = seq_end;
+        }
+
+        let rest = &text[pos..];
+        let held = partial_start_len(rest);
+        output.push_str(&rest[..rest.len() - held]);
+        self.pending = rest[rest.len() - held..].to_string();
+        (output, cwd)
+    }
+
+    /// Hands back whatever is still held for want of a terminator, once no more output is coming.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// The bracketed-paste mode (DEC private mode 2004) left in effect by `chunk`, if it toggles it.
+/// Readline turns the mode on while it waits at a prompt and off while a command runs.
+pub fn bracketed_paste_mode(chunk: &str) -> Option<bool> {
+    match (chunk.rfind(BRACKETED_PASTE_ON), chunk.rfind(BRACKETED_PASTE_OFF)) {
+        (Some(on), Some(off)) => Some(on > off),
+        (on, off) => on.map(|_| true).or(off.map(|_| false)),
+    }
+}
+
+/// Wraps `data` in paste markers so the shell inserts it literally. Any end marker inside the
+/// data is dropped, otherwise the rest of the paste would run as typed input.
+pub fn bracket_paste(data: &str) -> String {
+    format!("{}{}{}", PASTE_START, data.replace(PASTE_END, ""), PASTE_END)
+}
+
+/// Removes escape sequences (CSI, OSC, DCS and the other string controls, and two-byte escapes)
+/// so captured output reads as plain text. Text after an unterminated sequence is dropped.
+pub fn strip_escapes(text: &str) -> String {
+    enum State {
+        Ground,
+        Escape,
+        /// After `ESC` and one or more intermediate bytes, waiting for the final byte.
+        Intermediate,
+        Csi,
+        /// OSC, DCS, SOS, PM or APC body, ended by BEL (OSC only in practice) or ST.
+        String,
+        StringEscape,
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut state = State::Ground;
+    for c in text.chars() {
+        state = match state {
+            State::Ground => match c {
+                '\x1b' => State::Escape,
+                '\u{9b}' => State::Csi,
+                _ => {
+                    output.push(c);
+                    State::Ground
+                }
+            },
+            State::Escape => match c {
+                '[' => State::Csi,
+                ']' | 'P' | 'X' | '^' | '_' => State::String,
+                '\x20'..='\x2f' => State::Intermediate,
+                _ => State::Ground,
+            },
+            State::Intermediate => match c {
+                '\x20'..='\x2f' => State::Intermediate,
+                _ => State::Ground,
+            },
+            State::Csi => match c {
+                '\x40'..='\x7e' => State::Ground,
+                _ => State::Csi,
+            },
+            State::String => match c {
+                '\x07' => State::Ground,
+                '\x1b' => State::StringEscape,
+                _ => State::String,
+            },
+            State::StringEscape => match c {
+                '\\' => State::Ground,
+                _ => State::String,
+            },
+        };
+    }
+    output
+}
+
+/// Returns (end of body, end of sequence) for a BEL or ST (`ESC \`) terminator.
+fn find_terminator(s: &str) -> Option<(usize, usize)> {
+    let bel = s.find('\x07').map(|i| (i, i + 1));
+    let st = s.find("\x1b\\").map(|i| (i, i + 2));
+    match (bel, st) {
+        (Some(b), Some(t)) => Some(if b.0 < t.0 { b } else { t }),
+        (b, t) => b.or(t),
+    }
+}
+
+/// Length of a trailing prefix of `OSC7_START`, which may be completed by the next read.
+fn partial_start_len(s: &str) -> usize {
+    (1..OSC7_START.len()).rev().find(|&n| s.ends_with(&OSC7_START[..n])).unwrap_or(0)
+}
+
+fn parse_file_url(url: &str) -> Option<PathBuf> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
+    percent_decode(path).map(PathBuf::from)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
@@ -0,0 +1,71 @@
+use crate::config::BackupConfig;
+use crate::db::DbPool;
+use anyhow::Result;
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::fs;
+
+const BACKUP_PREFIX: &str = "obpi-";
+const BACKUP_SUFFIX: &str = ".db";
+
+/// Checkpoints the WAL on every tick of `config.interval` and, when a backup directory is
+/// configured, snapshots the database into it. Does nothing if the interval is zero.
+pub fn spawn(pool: DbPool, config: BackupConfig) {
+    if config.interval.is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.interval);
+        // The first tick fires immediately; there's nothing worth saving yet.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = checkpoint(&pool).await {
+                tracing::warn!("WAL checkpoint failed: {}", e);
+            }
+            if let Some(dir) = &config.dir {
+                if let Err(e) = backup(&pool, dir, config.retention).await {
+                    tracing::warn!("Database backup to {:?} failed: {}", dir, e);
+                }
+            }
+        }
+    });
+}
+
+async fn checkpoint(pool: &DbPool) -> Result<()> {
+    sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(pool).await?;
+    Ok(())
+}
+
+/// `VACUUM INTO` writes a consistent snapshot from a read transaction, so writers carry on
+/// while it runs.
+async fn backup(pool: &DbPool, dir: &Path, retention: usize) -> Result<()> {
+    fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("{}{}{}", BACKUP_PREFIX, Utc::now().format("%Y%m%dT%H%M%SZ"), BACKUP_SUFFIX));
+    let started = Instant::now();
+    sqlx::query("VACUUM INTO $1").bind(path.to_string_lossy()).execute(pool).await?;
+    let size = fs::metadata(&path).await?.len();
+    tracing::info!("Backed up database to {:?} ({} bytes in {:?}).", path, size, started.elapsed());
+    prune(dir, retention).await
+}
+
+/// Keeps the newest `retention` backups. Names embed a sortable UTC timestamp.
+async fn prune(dir: &Path, retention: usize) -> Result<()> {
+    let mut backups: Vec<PathBuf> = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX) {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+    let excess = backups.len().saturating_sub(retention);
+    for old in &backups[..excess] {
+        fs::remove_file(old).await?;
+        tracing::debug!("Removed old backup {:?}.", old);
+    }
+    Ok(())
+}
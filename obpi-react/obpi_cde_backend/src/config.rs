@@ -0,0 +1,308 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_BIND_ADDR: &str = "127.0.0.1:8080";
+const DEFAULT_STORAGE_ROOT: &str = "/tmp/cde_storage";
+const DEFAULT_RESUME_GRACE_SECS: u64 = 30;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_IDLE_WARNING_SECS: u64 = 60;
+const DEFAULT_UNDO_WINDOW_SECS: u64 = 5 * 60;
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 8 * 1024 * 1024;
+const DEFAULT_MAX_PATH_DEPTH: usize = 64;
+const DEFAULT_OUTPUT_CAPACITY: usize = 64;
+const DEFAULT_SCROLLBACK_BYTES: usize = 64 * 1024;
+const DEFAULT_BACKUP_INTERVAL_SECS: u64 = 15 * 60;
+const DEFAULT_BACKUP_RETENTION: usize = 7;
+const DEFAULT_UMASK: u32 = 0o022;
+const DEFAULT_HEAVY_REQUESTS: &str = "execBatch,vfsListStream,vfsReadFileStream,vfsGetTree,vfsListDescendants,vfsThumbnail,vfsMoveNodes,vfsRestoreAll,vfsEmptyTrash,verifyStorage,exportUser,importUser";
+const DEFAULT_RATE_LIMIT_BURST: u32 = 10;
+const DEFAULT_RATE_LIMIT_PER_MINUTE: u32 = 30;
+const DEFAULT_ROLE: &str = "Standard";
+const MIN_ADMIN_PASSWORD_LEN: usize = 8;
+/// Matches the `users.role` CHECK constraint.
+const ROLES: [&str; 3] = ["Admin", "Standard", "Limited"];
+
+/// Everything the server reads from the environment, loaded and validated once at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    pub bind_addr: SocketAddr,
+    /// Serve `wss://` directly when `TLS_CERT` and `TLS_KEY` point at PEM files.
+    pub tls: Option<TlsConfig>,
+    /// `LOG_PROTOCOL` toggles raw frame logging; it defaults to on for debug builds only.
+    pub log_protocol: bool,
+    /// How long a dropped session waits for a `Resume`; zero disables resumption.
+    pub resume_grace: Duration,
+    /// Longest a read-only request run in the background may take before the client gets a
+    /// `TIMEOUT` error and the work is abandoned; zero disables the limit. Writes always run to
+    /// completion.
+    pub request_timeout: Duration,
+    /// Sessions that receive nothing from their client for this long are closed for good
+    /// (not parked for `Resume`); zero, the default, keeps them open indefinitely.
+    pub idle_timeout: Duration,
+    /// How long before `idle_timeout` the client is sent an `IdleWarning`; zero skips it.
+    pub idle_warning: Duration,
+    /// How long the `undo_token` of a trash or move stays usable with `VfsUndo`.
+    pub undo_window: Duration,
+    /// Caps the size of a single serialized response frame.
+    pub max_response_bytes: usize,
+    /// Create the `guest` and `root` demo accounts with random passwords. Off by default in
+    /// release builds.
+    pub seed_demo_users: bool,
+    /// Role given to seeded demo accounts.
+    pub default_role: String,
+    /// Admin account created on first run from `ADMIN_USERNAME` and `ADMIN_PASSWORD`.
+    pub bootstrap_admin: Option<Credentials>,
+    pub vfs: VfsConfig,
+    pub pty: PtyConfig,
+    pub backup: BackupConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    /// How often to checkpoint the WAL (and take a backup, if enabled); zero disables both.
+    /// SQLite only.
+    #[cfg_attr(feature = "postgres", allow(dead_code))]
+    pub interval: Duration,
+    /// Where timestamped snapshots go. Backups are off when this is unset.
+    pub dir: Option<PathBuf>,
+    /// Number of snapshots to keep.
+    pub retention: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials").field("username", &self.username).field("password", &"***").finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Request `type`s that draw from the per-user bucket, from comma-separated `HEAVY_REQUESTS`
+    /// (see `ClientRequestPayload::limit_kind`). Everything else, including typing and simple reads, is never limited.
+    pub heavy_requests: HashSet<String>,
+    /// Heavy requests a user can make back to back.
+    pub burst: u32,
+    /// Sustained rate the bucket refills at; zero disables rate limiting.
+    pub per_minute: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct VfsConfig {
+    /// Directory holding the on-disk contents of files.
+    pub storage_root: PathBuf,
+    /// A previous `storage_root`. `VerifyStorage` moves stored paths under it onto the current
+    /// one after the storage directory has been relocated.
+    pub storage_root_rewrite: Option<PathBuf>,
+    /// Paths deeper than this are rejected. Each component is one step of the recursive walk
+    /// `get_path_id` does in its single statement, as is each level for the ancestor checks on
+    /// moves, so this bounds how far those walks can go.
+    pub max_path_depth: usize,
+    /// Permission bits cleared from the default mode of new nodes, read as octal from `UMASK`.
+    pub umask: u32,
+    /// Quota given to newly created users. `QUOTA_BYTES=0`, the default, means unlimited.
+    pub default_quota_bytes: Option<u64>,
+    /// Most nodes, trashed ones included, a user may own. `MAX_NODES_PER_USER=0`, the default,
+    /// means unlimited.
+    pub max_nodes_per_user: Option<i64>,
+    /// File contents larger than this are gzipped on disk. `COMPRESS_ABOVE_BYTES=0`, the
+    /// default, turns compression off; files already compressed still read either way.
+    pub compress_above: Option<usize>,
+    /// Host directory whose subdirectories are the templates `VfsCreateNode` can populate new
+    /// directories from, by name. Unset (`TEMPLATE_DIR`) means no templates.
+    pub template_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PtyConfig {
+    /// Number of output chunks (up to 4 KiB each) buffered between the PTY reader and the socket.
+    ///
+    /// When the channel is full the reader stops reading until the session drains it, so a noisy
+    /// command on a slow client is throttled by the kernel's PTY buffer instead of growing server
+    /// memory. The alternative, dropping the oldest output, would keep the shell running at full
+    /// speed but silently lose data the user may need, so we favour backpressure.
+    pub output_capacity: usize,
+    /// Terminal output kept for replay to a resuming client.
+    pub scrollback_bytes: usize,
+    /// Cap on a session's terminal output held in memory, scrollback plus output read from the
+    /// PTY but not yet sent; the oldest scrollback goes first. `SESSION_OUTPUT_BYTES=0`, the
+    /// default, leaves just the per-buffer limits.
+    pub session_output_bytes: Option<usize>,
+    /// OSC 7 reports are stripped before output reaches the client unless this is set.
+    pub osc7_passthrough: bool,
+    /// Where shells append their history for capture into `command_history` when the
+    /// terminal closes. `None` when `PERSIST_SHELL_HISTORY` is off.
+    pub history_dir: Option<PathBuf>,
+    /// Where each session's generated shell rc file is written.
+    pub rc_dir: PathBuf,
+    /// Run in every new shell unless the user has their own `users.startup_command`.
+    pub startup_command: Option<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let config = Self {
+            database_url: env::var("DATABASE_URL").context("DATABASE_URL must be set")?,
+            bind_addr: parse_var("BIND_ADDR", DEFAULT_BIND_ADDR.parse()?)?,
+            tls: match (env::var("TLS_CERT"), env::var("TLS_KEY")) {
+                (Ok(cert), Ok(key)) => Some(TlsConfig { cert_path: cert.into(), key_path: key.into() }),
+                (Err(env::VarError::NotPresent), Err(env::VarError::NotPresent)) => None,
+                _ => return Err(anyhow!("TLS_CERT and TLS_KEY must be set together")),
+            },
+            log_protocol: flag_var("LOG_PROTOCOL", cfg!(debug_assertions))?,
+            resume_grace: Duration::from_secs(parse_var("RESUME_GRACE_SECS", DEFAULT_RESUME_GRACE_SECS)?),
+            request_timeout: Duration::from_secs(parse_var("REQUEST_TIMEOUT_SECS", DEFAULT_REQUEST_TIMEOUT_SECS)?),
+            idle_timeout: Duration::from_secs(parse_var("IDLE_TIMEOUT_SECS", 0)?),
+            idle_warning: Duration::from_secs(parse_var("IDLE_WARNING_SECS", DEFAULT_IDLE_WARNING_SECS)?),
+            undo_window: Duration::from_secs(parse_var("UNDO_WINDOW_SECS", DEFAULT_UNDO_WINDOW_SECS)?),
+            max_response_bytes: parse_var("MAX_RESPONSE_BYTES", DEFAULT_MAX_RESPONSE_BYTES)?,
+            seed_demo_users: flag_var("SEED_DEMO_USERS", cfg!(debug_assertions))?,
+            default_role: parse_var("DEFAULT_ROLE", DEFAULT_ROLE.to_string())?,
+            bootstrap_admin: match (env::var("ADMIN_USERNAME"), env::var("ADMIN_PASSWORD")) {
+                (Ok(username), Ok(password)) => Some(Credentials { username, password }),
+                (Err(env::VarError::NotPresent), Err(env::VarError::NotPresent)) => None,
+                _ => return Err(anyhow!("ADMIN_USERNAME and ADMIN_PASSWORD must be set together")),
+            },
+            vfs: VfsConfig {
+                storage_root: parse_var("STORAGE_ROOT", PathBuf::from(DEFAULT_STORAGE_ROOT))?,
+                storage_root_rewrite: env::var_os("STORAGE_ROOT_REWRITE").map(PathBuf::from),
+                max_path_depth: parse_var("MAX_PATH_DEPTH", DEFAULT_MAX_PATH_DEPTH)?,
+                umask: octal_var("UMASK", DEFAULT_UMASK)?,
+                default_quota_bytes: match parse_var("QUOTA_BYTES", 0u64)? {
+                    0 => None,
+                    quota => Some(quota),
+                },
+                max_nodes_per_user: match parse_var("MAX_NODES_PER_USER", 0i64)? {
+                    0 => None,
+                    limit => Some(limit),
+                },
+                compress_above: match parse_var("COMPRESS_ABOVE_BYTES", 0usize)? {
+                    0 => None,
+                    threshold => Some(threshold),
+                },
+                template_dir: env::var_os("TEMPLATE_DIR").map(PathBuf::from),
+            },
+            pty: PtyConfig {
+                output_capacity: parse_var("PTY_OUTPUT_CAPACITY", DEFAULT_OUTPUT_CAPACITY)?,
+                scrollback_bytes: parse_var("SCROLLBACK_BYTES", DEFAULT_SCROLLBACK_BYTES)?,
+                session_output_bytes: match parse_var("SESSION_OUTPUT_BYTES", 0usize)? {
+                    0 => None,
+                    cap => Some(cap),
+                },
+                osc7_passthrough: flag_var("OSC7_PASSTHROUGH", false)?,
+                history_dir: if flag_var("PERSIST_SHELL_HISTORY", true)? {
+                    Some(parse_var("SHELL_HISTORY_DIR", env::temp_dir().join("obpi-history"))?)
+                } else {
+                    None
+                },
+                rc_dir: parse_var("SHELL_RC_DIR", env::temp_dir().join("obpi-rc"))?,
+                startup_command: env::var("STARTUP_COMMAND").ok().filter(|command| !command.trim().is_empty()),
+            },
+            backup: BackupConfig {
+                interval: Duration::from_secs(parse_var("BACKUP_INTERVAL_SECS", DEFAULT_BACKUP_INTERVAL_SECS)?),
+                dir: env::var_os("BACKUP_DIR").map(PathBuf::from),
+                retention: parse_var("BACKUP_RETENTION", DEFAULT_BACKUP_RETENTION)?,
+            },
+            rate_limit: RateLimitConfig {
+                heavy_requests: parse_var("HEAVY_REQUESTS", DEFAULT_HEAVY_REQUESTS.to_string())?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|kind| !kind.is_empty())
+                    .map(String::from)
+                    .collect(),
+                burst: parse_var("RATE_LIMIT_BURST", DEFAULT_RATE_LIMIT_BURST)?,
+                per_minute: parse_var("RATE_LIMIT_PER_MINUTE", DEFAULT_RATE_LIMIT_PER_MINUTE)?,
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.pty.output_capacity == 0 {
+            return Err(anyhow!("PTY_OUTPUT_CAPACITY must be greater than zero"));
+        }
+        if self.vfs.max_path_depth == 0 {
+            return Err(anyhow!("MAX_PATH_DEPTH must be greater than zero"));
+        }
+        if !ROLES.contains(&self.default_role.as_str()) {
+            return Err(anyhow!("DEFAULT_ROLE must be one of {}", ROLES.join(", ")));
+        }
+        if let Some(admin) = &self.bootstrap_admin {
+            crate::db::validate_credentials(&admin.username, &admin.password)
+                .map_err(|e| anyhow!("Invalid ADMIN_USERNAME or ADMIN_PASSWORD: {}", e))?;
+            if admin.password.len() < MIN_ADMIN_PASSWORD_LEN {
+                return Err(anyhow!("ADMIN_PASSWORD must be at least {} characters", MIN_ADMIN_PASSWORD_LEN));
+            }
+        }
+        if self.vfs.umask > 0o777 {
+            return Err(anyhow!("UMASK must be between 000 and 777"));
+        }
+        if cfg!(feature = "postgres") && self.backup.dir.is_some() {
+            return Err(anyhow!("BACKUP_DIR only works with SQLite; back Postgres up with its own tools"));
+        }
+        if self.backup.dir.is_some() && self.backup.retention == 0 {
+            return Err(anyhow!("BACKUP_RETENTION must be greater than zero when BACKUP_DIR is set"));
+        }
+        if self.rate_limit.per_minute > 0 && self.rate_limit.burst == 0 {
+            return Err(anyhow!("RATE_LIMIT_BURST must be greater than zero when rate limiting is on"));
+        }
+        if !self.idle_timeout.is_zero() && self.idle_warning >= self.idle_timeout {
+            return Err(anyhow!("IDLE_WARNING_SECS must be less than IDLE_TIMEOUT_SECS"));
+        }
+        if self.max_response_bytes == 0 {
+            return Err(anyhow!("MAX_RESPONSE_BYTES must be greater than zero"));
+        }
+        Ok(())
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value.parse().map_err(|e| anyhow!("Invalid value for {}: {:?} ({})", name, value, e)),
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(anyhow!("Invalid value for {}: {}", name, e)),
+    }
+}
+
+fn octal_var(name: &str, default: u32) -> Result<u32> {
+    match env::var(name) {
+        Ok(value) => u32::from_str_radix(&value, 8).map_err(|e| anyhow!("Invalid value for {}: {:?} ({})", name, value, e)),
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(anyhow!("Invalid value for {}: {}", name, e)),
+    }
+}
+
+fn flag_var(name: &str, default: bool) -> Result<bool> {
+    match env::var(name) {
+        Ok(value) => match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(anyhow!("Invalid value for {}: {:?} (expected true or false)", name, value)),
+        },
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(anyhow!("Invalid value for {}: {}", name, e)),
+    }
+}
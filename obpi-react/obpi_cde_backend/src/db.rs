@@ -1,32 +1,100 @@
+use crate::config::Config;
 use crate::protocol::UserInfo;
-use rand::{Rng, thread_rng};
+use crate::vfs;
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, Rng, thread_rng};
 use sha2::{Digest, Sha256};
-use sqlx::{sqlite::{Sqlite, SqlitePoolOptions}, migrate::MigrateDatabase, Row, SqlitePool};
-use std::env;
+use anyhow::anyhow;
+use sqlx::{migrate::{MigrateDatabase, Migrator}, pool::PoolOptions, Pool, Row};
 
-pub type DbPool = SqlitePool;
+#[cfg(all(feature = "sqlite", feature = "postgres"))]
+compile_error!("The `sqlite` and `postgres` features are exclusive; build with `--no-default-features --features postgres` for Postgres");
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("Enable one of the `sqlite` and `postgres` features");
 
-pub async fn init_db() -> Result<DbPool, sqlx::Error> {
-    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    
-    if !Sqlite::database_exists(&db_url).await.unwrap_or(false) {
-        Sqlite::create_database(&db_url).await?;
+/// The one place the backend is chosen. Queries go through `Db` and `DbPool` and are written
+/// in SQL both backends accept, `$N` placeholders included; the few that can't be are cfg'd.
+#[cfg(feature = "sqlite")]
+pub type Db = sqlx::Sqlite;
+#[cfg(feature = "postgres")]
+pub type Db = sqlx::Postgres;
+pub type DbPool = Pool<Db>;
+
+const MAX_USERNAME_LEN: usize = 64;
+const MAX_PASSWORD_LEN: usize = 1024;
+const DEMO_USERS: [&str; 2] = ["guest", "root"];
+const DEMO_PASSWORD_LEN: usize = 16;
+
+#[cfg(feature = "sqlite")]
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+/// The same schema as `migrations`, written for Postgres. A schema change adds a migration to both.
+#[cfg(feature = "postgres")]
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations_postgres");
+
+#[cfg(feature = "sqlite")]
+const MIGRATIONS_TABLE_EXISTS: &str = "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')";
+#[cfg(feature = "postgres")]
+const MIGRATIONS_TABLE_EXISTS: &str = "SELECT to_regclass('_sqlx_migrations') IS NOT NULL";
+
+pub async fn init_db(config: &Config) -> Result<DbPool, anyhow::Error> {
+    let db_url = config.database_url.as_str();
+    if !Db::database_exists(db_url).await.unwrap_or(false) {
+        Db::create_database(db_url).await?;
     }
 
-    let pool = SqlitePoolOptions::new()
+    let pool = PoolOptions::<Db>::new()
         .max_connections(5)
-        .connect(&db_url)
+        .connect(db_url)
         .await?;
 
+    check_schema_version(&pool, true).await?;
     tracing::info!("Running database migrations...");
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    MIGRATOR.run(&pool).await?;
     tracing::info!("Database migrations complete.");
+    check_schema_version(&pool, false).await?;
 
-    setup_initial_users(&pool).await?;
+    setup_initial_users(&pool, config).await?;
 
     Ok(pool)
 }
 
+/// Refuses a database whose applied migrations don't end exactly at this build's latest, e.g.
+/// one written by a newer build or left half-migrated, rather than failing later on queries.
+/// Before migrating, `allow_behind` lets an older schema through to be brought up to date.
+async fn check_schema_version(pool: &DbPool, allow_behind: bool) -> Result<(), anyhow::Error> {
+    let expected = MIGRATOR.iter().map(|migration| migration.version).max().unwrap_or(0);
+    let (tracked,): (bool,) = sqlx::query_as(MIGRATIONS_TABLE_EXISTS)
+        .fetch_one(pool)
+        .await?;
+    if !tracked && allow_behind {
+        return Ok(());
+    }
+    let (applied, failed): (Option<i64>, i64) = sqlx::query_as("SELECT MAX(version) FILTER (WHERE success), COUNT(*) FILTER (WHERE NOT success) FROM _sqlx_migrations")
+        .fetch_one(pool)
+        .await?;
+    if failed > 0 {
+        return Err(anyhow!("Database has {} failed migration(s); repair it or restore a backup before starting", failed));
+    }
+    match applied.unwrap_or(0) {
+        version if version == expected || (allow_behind && version < expected) => Ok(()),
+        version if version > expected => Err(anyhow!("Database schema is at version {}, newer than this build's {}; run a newer build", version, expected)),
+        version => Err(anyhow!("Database schema is at version {} but this build expects {}", version, expected)),
+    }
+}
+
+/// Cheap shape checks run before credentials reach the database or the hasher, so oversized
+/// input can't make login do pointless work.
+pub fn validate_credentials(username: &str, password: &str) -> Result<(), String> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN || !username.chars().all(valid_char) {
+        return Err(format!("Username must be 1-{} letters, digits, '.', '_' or '-'", MAX_USERNAME_LEN));
+    }
+    if password.len() > MAX_PASSWORD_LEN {
+        return Err(format!("Password must be at most {} bytes", MAX_PASSWORD_LEN));
+    }
+    Ok(())
+}
+
 fn hash_password(password: &str, salt: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.update(password.as_bytes());
@@ -35,7 +103,7 @@ fn hash_password(password: &str, salt: &[u8]) -> Vec<u8> {
 }
 
 pub async fn verify_password(pool: &DbPool, username: &str, password: &str) -> Result<Option<UserInfo>, anyhow::Error> {
-    let row = sqlx::query("SELECT id, username, role, password_hash FROM users WHERE username = ?")
+    let row = sqlx::query("SELECT id, username, role, password_hash FROM users WHERE username = $1")
         .bind(username)
         .fetch_optional(pool)
         .await?;
@@ -65,8 +133,18 @@ pub async fn verify_password(pool: &DbPool, username: &str, password: &str) -> R
     }
 }
 
-async fn create_user_if_not_exists(pool: &DbPool, username: &str, password: &str, role: &str) -> Result<(), sqlx::Error> {
-    let user_exists: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE username = ?")
+/// The user's own startup command, falling back to the server-wide one.
+pub async fn startup_command(pool: &DbPool, config: &Config, user_id: i64) -> Result<Option<String>, sqlx::Error> {
+    let own: Option<String> = sqlx::query_scalar("SELECT startup_command FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(own.or_else(|| config.pty.startup_command.clone()))
+}
+
+/// Returns whether the user was created.
+async fn create_user_if_not_exists(pool: &DbPool, config: &Config, username: &str, password: &str, role: &str) -> Result<bool, sqlx::Error> {
+    let user_exists: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE username = $1")
         .bind(username)
         .fetch_one(pool)
         .await?;
@@ -78,28 +156,75 @@ async fn create_user_if_not_exists(pool: &DbPool, username: &str, password: &str
         let password_hash = hash_password(password, &salt);
         let password_hash_str = format!("{}:{}", hex::encode(salt), hex::encode(password_hash));
 
-        let user_id = sqlx::query("INSERT INTO users (username, password_hash, role) VALUES (?, ?, ?)")
+        let now = Utc::now();
+        let user_id = sqlx::query_scalar("INSERT INTO users (username, password_hash, role, quota_bytes, created_at) VALUES ($1, $2, $3, $4, $5) RETURNING id")
             .bind(username)
             .bind(&password_hash_str)
             .bind(role)
-            .execute(pool)
-            .await?
-            .last_insert_rowid();
-        
-        sqlx::query("INSERT INTO files (owner_id, parent_id, name, node_type, original_path) VALUES (?, NULL, ?, 'dir', ?)")
-            .bind(user_id)
-            .bind(format!("/home/{}", username))
-            .bind(format!("/home/{}", username))
-            .execute(pool)
+            .bind(config.vfs.default_quota_bytes.map(|quota| quota as i64))
+            .bind(now)
+            .fetch_one(pool)
             .await?;
         
+        ensure_home_dir(pool, config, user_id, username).await?;
+        
         tracing::info!("User '{}' created successfully.", username);
+        return Ok(true);
     }
-    Ok(())
+    Ok(false)
+}
+
+/// Creates whichever of `/home` and `/home/<username>` the user is missing, so a user left
+/// without a home by a partial failure can still log in to a working cwd. Returns whether
+/// anything had to be created.
+pub async fn ensure_home_dir(pool: &DbPool, config: &Config, user_id: i64, username: &str) -> Result<bool, sqlx::Error> {
+    let mut created = false;
+    let mut parent_id: Option<i64> = None;
+    for (name, path) in [("home", "/home".to_string()), (username, format!("/home/{}", username))] {
+        let existing: Option<(i64,)> = sqlx::query_as("SELECT id FROM files WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND name = $3 AND node_type = 'dir' AND is_trashed = FALSE")
+            .bind(user_id)
+            .bind(parent_id)
+            .bind(name)
+            .fetch_optional(pool)
+            .await?;
+        let id = match existing {
+            Some((id,)) => id,
+            None => {
+                created = true;
+                let now = Utc::now();
+                sqlx::query_scalar("INSERT INTO files (owner_id, parent_id, name, node_type, mode, original_path, created_at, updated_at) VALUES ($1, $2, $3, 'dir', $4, $5, $6, $7) RETURNING id")
+                    .bind(user_id)
+                    .bind(parent_id)
+                    .bind(name)
+                    .bind(i64::from(vfs::default_mode("dir", config.vfs.umask)))
+                    .bind(&path)
+                    .bind(now)
+                    .bind(now)
+                    .fetch_one(pool)
+                    .await?
+            }
+        };
+        parent_id = Some(id);
+    }
+    Ok(created)
 }
 
-async fn setup_initial_users(pool: &DbPool) -> Result<(), sqlx::Error> {
-    create_user_if_not_exists(pool, "guest", "password", "Admin").await?;
-    create_user_if_not_exists(pool, "root", "root", "Admin").await?;
+async fn setup_initial_users(pool: &DbPool, config: &Config) -> Result<(), sqlx::Error> {
+    if let Some(admin) = &config.bootstrap_admin {
+        create_user_if_not_exists(pool, config, &admin.username, &admin.password, "Admin").await?;
+    }
+    if config.seed_demo_users {
+        for username in DEMO_USERS {
+            let password = random_password();
+            if create_user_if_not_exists(pool, config, username, &password, &config.default_role).await? {
+                // Only shown once; the hash is all that's stored.
+                tracing::warn!("Demo user '{}' created with password '{}'.", username, password);
+            }
+        }
+    }
     Ok(())
 }
+
+fn random_password() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(DEMO_PASSWORD_LEN).map(char::from).collect()
+}
@@ -0,0 +1,97 @@
+use crate::ansi;
+use crate::protocol::{BatchCommand, StepResult};
+use std::process::Stdio;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+
+/// Bytes of stdout, and separately of stderr, kept from each step. The rest is read and thrown
+/// away so the command isn't blocked on a full pipe; `request_timeout` bounds one that never stops.
+const MAX_EXEC_OUTPUT: usize = 1024 * 1024;
+
+/// Runs each command outside the PTY, capturing its output and exit code.
+/// Steps run sequentially and a failing step doesn't stop the ones after it.
+///
+/// With `strip_ansi` the output has its escape sequences removed (see `ansi::strip_escapes`);
+/// the interactive terminal is unaffected.
+pub async fn run_batch(commands: Vec<BatchCommand>, cwd: Option<String>, strip_ansi: bool) -> Vec<StepResult> {
+    let mut steps = Vec::with_capacity(commands.len());
+    for command in commands {
+        let (mut process, command) = match command {
+            BatchCommand::Shell(command) => {
+                let mut process = Command::new("sh");
+                process.arg("-c").arg(&command);
+                (process, command)
+            }
+            BatchCommand::Argv { argv } => {
+                let Some((program, args)) = argv.split_first() else {
+                    steps.push(StepResult { command: String::new(), exit_code: None, stdout: String::new(), stderr: "Empty argv".to_string(), truncated: false });
+                    continue;
+                };
+                let mut process = Command::new(program);
+                process.args(args);
+                // Only for display; the arguments themselves were passed through untouched.
+                (process, argv.join(" "))
+            }
+        };
+        process.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+        if let Some(dir) = &cwd {
+            process.current_dir(dir);
+        }
+        let capture = |bytes: &[u8]| {
+            let text = String::from_utf8_lossy(bytes);
+            if strip_ansi { ansi::strip_escapes(&text) } else { text.into_owned() }
+        };
+        let mut child = match process.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                steps.push(StepResult { command, exit_code: None, stdout: String::new(), stderr: format!("Failed to start command: {}", e), truncated: false });
+                continue;
+            }
+        };
+        let (stdout, stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+        let step = match tokio::try_join!(read_capped(stdout), read_capped(stderr), child.wait()) {
+            Ok(((stdout, stdout_truncated), (stderr, stderr_truncated), status)) => StepResult {
+                command,
+                exit_code: status.code(),
+                stdout: capture(&stdout),
+                stderr: capture(&stderr),
+                truncated: stdout_truncated || stderr_truncated,
+            },
+            Err(e) => StepResult {
+                command,
+                exit_code: None,
+                stdout: String::new(),
+                stderr: format!("Failed to read command output: {}", e),
+                truncated: false,
+            },
+        };
+        steps.push(step);
+    }
+    steps
+}
+
+/// Reads `stream` to the end, keeping the first `MAX_EXEC_OUTPUT` bytes. Also returns whether
+/// anything past them was dropped.
+async fn read_capped(mut stream: impl AsyncRead + Unpin) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut kept = Vec::new();
+    (&mut stream).take(MAX_EXEC_OUTPUT as u64).read_to_end(&mut kept).await?;
+    let dropped = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+    Ok((kept, dropped > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn output_is_capped() {
+        let commands = vec![
+            BatchCommand::Shell(format!("head -c {} /dev/zero | tr '\\0' x; echo oops >&2", MAX_EXEC_OUTPUT + 4096)),
+            BatchCommand::Shell("echo fine".to_string()),
+        ];
+        let steps = run_batch(commands, None, false).await;
+        assert_eq!(steps[0].stdout.len(), MAX_EXEC_OUTPUT);
+        assert_eq!((steps[0].stderr.as_str(), steps[0].exit_code, steps[0].truncated), ("oops\n", Some(0), true));
+        assert_eq!((steps[1].stdout.as_str(), steps[1].truncated), ("fine\n", false));
+    }
+}
@@ -0,0 +1,41 @@
+use crate::db::DbPool;
+use anyhow::Result;
+use chrono::Utc;
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio::fs;
+
+/// Moves the commands bash appended to `history_file` into `command_history` and removes
+/// the file. Timestamp comment lines written under `HISTTIMEFORMAT` are skipped.
+pub async fn persist_shell_history(pool: &DbPool, user_id: i64, session_id: &str, history_file: &Path) -> Result<usize> {
+    let contents = match fs::read_to_string(history_file).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let commands: Vec<&str> = contents
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !is_timestamp_line(line))
+        .collect();
+
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+    for command in &commands {
+        sqlx::query("INSERT INTO command_history (user_id, session_id, command, created_at) VALUES ($1, $2, $3, $4)")
+            .bind(user_id)
+            .bind(session_id)
+            .bind(command)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    fs::remove_file(history_file).await?;
+    Ok(commands.len())
+}
+
+fn is_timestamp_line(line: &str) -> bool {
+    line.strip_prefix('#').is_some_and(|rest| !rest.is_empty() && rest.bytes().all(|b| b.is_ascii_digit()))
+}
@@ -1,16 +1,29 @@
 use axum::{extract::{ws::{WebSocket, WebSocketUpgrade}, State}, response::Response, routing::get, Router};
-use std::net::SocketAddr;
+use axum_server::tls_rustls::RustlsConfig;
 use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod ansi;
+#[cfg(feature = "sqlite")]
+mod backup;
+mod config;
 mod db;
+mod exec;
+mod history;
 mod pty_handler;
 mod protocol;
+mod ratelimit;
+mod registry;
 mod session;
+mod shell_rc;
+mod state;
+#[cfg(test)]
+mod test_support;
 mod vfs;
 
-use crate::db::DbPool;
+use crate::config::Config;
 use crate::session::UserSession;
+use crate::state::AppState;
 
 #[tokio::main]
 async fn main() {
@@ -23,29 +36,43 @@ async fn main() {
 
     dotenvy::dotenv().expect("Failed to read .env file");
 
-    let db_pool = db::init_db().await.expect("Failed to initialize database");
+    let config = Config::from_env().expect("Invalid configuration");
+    let db_pool = db::init_db(&config).await.expect("Failed to initialize database");
+    let addr = config.bind_addr;
+    let tls = config.tls.clone();
+    #[cfg(feature = "sqlite")]
+    backup::spawn(db_pool.clone(), config.backup.clone());
     
-    let app_state = Arc::new(db_pool);
+    let app_state = Arc::new(AppState::new(db_pool, config));
 
     let app = Router::new()
         .route("/ws", get(ws_handler))
         .with_state(app_state);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-    tracing::debug!("listening on {}", addr);
-
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    match tls {
+        Some(tls) => {
+            let rustls_config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to load TLS certificate {:?} and key {:?}: {}", tls.cert_path, tls.key_path, e));
+            tracing::debug!("listening on {} (TLS)", addr);
+            axum_server::bind_rustls(addr, rustls_config).serve(app.into_make_service()).await.unwrap();
+        }
+        None => {
+            tracing::debug!("listening on {}", addr);
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            axum::serve(listener, app).await.unwrap();
+        }
+    }
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    State(state): State<Arc<DbPool>>,
+    State(state): State<Arc<AppState>>,
 ) -> Response {
     ws.on_upgrade(|socket| handle_socket(socket, state))
 }
 
-async fn handle_socket(socket: WebSocket, db_pool: Arc<DbPool>) {
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     tracing::debug!("New WebSocket connection received.");
-    UserSession::new(socket, db_pool).run().await;
+    UserSession::new(&state).run(socket).await;
 }
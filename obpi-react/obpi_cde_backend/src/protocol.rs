@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
 
 pub type RequestId = String;
 
@@ -15,22 +17,261 @@ pub struct ClientRequest {
 #[serde(rename_all = "camelCase")]
 pub enum ClientRequestPayload {
     Login { username: String, password: String },
+    Resume { token: String },
+    /// Convenience for running a whole command line: appends a newline and tracks `cd`.
     RunCommand { command: String },
-    VfsList { path: String },
-    VfsReadFile { path: String },
-    VfsWriteFile { path: String, content: String },
-    VfsCreateNode { path: String, node_type: String },
+    /// Raw keystrokes for a terminal, forwarded untouched. Sends no response on success.
+    PtyInput { terminal_id: String, data: String },
+    /// Pastes `data` into a terminal, bracketed when the shell has bracketed paste enabled.
+    PtyPaste { terminal_id: String, data: String },
+    /// Starts a shell in the session's cwd if the terminal has none, either because the
+    /// previous one exited or because it couldn't be started at login.
+    PtySpawn {
+        terminal_id: String,
+        #[serde(default)]
+        output_encoding: TerminalEncoding,
+    },
+    /// The original name for `PtySpawn`.
+    PtyRespawn {
+        terminal_id: String,
+        #[serde(default)]
+        output_encoding: TerminalEncoding,
+    },
+    ExecBatch {
+        commands: Vec<BatchCommand>,
+        cwd: Option<String>,
+        /// Remove ANSI escape sequences from the captured output, e.g. before storing it as a log.
+        #[serde(default)]
+        strip_ansi: bool,
+    },
+    VfsList {
+        path: String,
+        #[serde(default = "default_true")]
+        include_hidden: bool,
+        /// List every descendant with its path relative to `path` instead of direct children.
+        #[serde(default)]
+        recursive: bool,
+        #[serde(default)]
+        offset: u32,
+        limit: Option<u32>,
+    },
+    /// Sends the whole listing as `VfsListChunk` pushes instead of a response, ending with one
+    /// that has `eof` set. An error stops the stream and is reported as a response.
+    VfsListStream {
+        path: String,
+        #[serde(default = "default_true")]
+        include_hidden: bool,
+    },
+    VfsGetTree { path: String, max_depth: u32 },
+    /// The children of `path` added, changed or removed since `since_version`; 0 gets them all.
+    VfsGetTreeDelta { path: String, since_version: i64 },
+    /// Completes the last component of `partial` against the VFS, as typed (relative to the
+    /// cwd, `~` for home).
+    PathComplete { partial: String },
+    /// The first of `base_name`, `base_name (1)`, ... that's free under `parent_path`.
+    VfsSuggestName { parent_path: String, base_name: String },
+    VfsReadFile {
+        path: String,
+        #[serde(default)]
+        encoding: ContentEncoding,
+        /// The `rev` the client already holds; an unchanged file answers `NotModified`.
+        if_none_match: Option<i64>,
+        /// Legacy encoding of the stored bytes, e.g. `windows-1252`; they're converted to UTF-8.
+        source_encoding: Option<String>,
+        /// Also report the line count and line endings of text files.
+        #[serde(default)]
+        line_info: bool,
+        /// Return text files with CRLF line endings converted to LF. The stored file is untouched.
+        #[serde(default)]
+        normalize_eol: bool,
+    },
+    /// Like `VfsReadFile` but addressed by node id, immune to concurrent renames.
+    VfsReadFileById {
+        id: i64,
+        #[serde(default)]
+        encoding: ContentEncoding,
+        if_none_match: Option<i64>,
+        source_encoding: Option<String>,
+        #[serde(default)]
+        line_info: bool,
+        #[serde(default)]
+        normalize_eol: bool,
+    },
+    /// Sends the file's raw bytes as `VfsReadChunk` pushes from `start_offset`, ending with one
+    /// that has `eof` set. To resume an interrupted download, pass the chunks' `rev` as
+    /// `expected_rev`; if the file has been written since, the read fails with `FILE_CHANGED`.
+    VfsReadFileStream {
+        path: String,
+        #[serde(default)]
+        start_offset: u64,
+        expected_rev: Option<i64>,
+    },
+    VfsStat { path: String },
+    VfsGetAttrs { path: String },
+    /// Stores an opaque string against a node; a missing `value` removes the attribute.
+    VfsSetAttr { path: String, key: String, value: Option<String> },
+    /// Tells other sessions this one is done with a file opened by `VfsReadFile`.
+    VfsCloseFile { id: i64 },
+    VfsStatMany { paths: Vec<String> },
+    /// A small copy of an image file that fits in `max_dim` pixels (up to 512) each way.
+    VfsThumbnail { path: String, max_dim: u32 },
+    /// With `source_encoding`, `content` is UTF-8 text to be stored in that encoding.
+    VfsWriteFile {
+        path: String,
+        content: String,
+        source_encoding: Option<String>,
+        /// The `rev` the new content was based on. If the file has changed since, the server
+        /// sends a `ConfirmOverwrite` request and only writes if the client confirms.
+        expected_rev: Option<i64>,
+        /// Convert CRLF line endings to LF before saving text.
+        #[serde(default)]
+        normalize_eol: bool,
+    },
+    VfsWriteFileById {
+        id: i64,
+        content: String,
+        source_encoding: Option<String>,
+        #[serde(default)]
+        normalize_eol: bool,
+    },
+    /// `mode` overrides the server's umask-derived default permission bits.
+    VfsCreateNode {
+        path: String,
+        node_type: String,
+        content: Option<String>,
+        mode: Option<u32>,
+        /// Answer with the parent's updated listing instead of `Success`.
+        #[serde(default)]
+        return_listing: bool,
+        /// Populate a new directory from this server-side template (see `TEMPLATE_DIR`).
+        template: Option<String>,
+    },
     VfsMoveNode { old_path: String, new_path: String },
-    VfsTrashNode { path: String },
-    VfsListTrash,
+    /// Moves every source into `dest_dir` under its current name, e.g. a drag-and-drop of a
+    /// multi-selection.
+    VfsMoveNodes { src_paths: Vec<String>, dest_dir: String },
+    VfsTrashNode {
+        path: String,
+        /// When false, a directory that still has children is refused (`rmdir` semantics).
+        #[serde(default = "default_true")]
+        recursive: bool,
+    },
+    VfsListTrash {
+        #[serde(default)]
+        sort: TrashSort,
+        #[serde(default)]
+        offset: u32,
+        limit: Option<u32>,
+    },
     VfsRestoreNode { id: i64 },
-    VfsDeleteNode { id: i64 },
+    /// Reverses the trash or move that returned `token`, putting the node back under the same
+    /// parent with the same name. Tokens work once, in the session that got them, until
+    /// `UNDO_WINDOW_SECS` runs out.
+    VfsUndo { token: String },
+    VfsRestoreAll,
+    VfsDeleteNode {
+        id: i64,
+        /// As for `VfsTrashNode`.
+        #[serde(default = "default_true")]
+        recursive: bool,
+    },
     VfsEmptyTrash,
+    VfsGetQuota,
+    VfsWatch { path: String },
+    VfsUnwatch { path: String },
+    GrantObserver { username: String },
+    AttachObserver { session_id: String },
+    DetachObserver,
+    /// Who is online: users with a connected session that is answering heartbeats.
+    ListPresence,
+    /// The logged-in user, this session's id and the server's idea of the cwd.
+    WhoAmI,
+    /// Admin only: pushes an `Announcement` to every connected session.
+    Broadcast { message: String },
+    /// Admin only: checks every stored file's blob, rebasing paths if `STORAGE_ROOT_REWRITE`
+    /// is set.
+    VerifyStorage,
+    /// Admin only: sends the user's whole live tree as `ExportChunk` pushes of a gzipped bundle,
+    /// ending with one that has `eof` set.
+    ExportUser { user_id: i64 },
+    /// Admin only: recreates an `ExportUser` bundle (base64) for `user_id` under `dest_path`,
+    /// which must be absolute and defaults to the root.
+    ImportUser { user_id: i64, bundle: String, dest_path: Option<String> },
+    /// Answers a `ServerRequest` by its `server_request_id`.
+    AnswerServerRequest { server_request_id: RequestId, answer: ServerRequestAnswer },
+}
+
+impl ClientRequestPayload {
+    /// The request's `type` tag, for configuration and logging.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Login { .. } => "login",
+            Self::Resume { .. } => "resume",
+            Self::RunCommand { .. } => "runCommand",
+            Self::PtyInput { .. } => "ptyInput",
+            Self::PtyPaste { .. } => "ptyPaste",
+            Self::PtySpawn { .. } => "ptySpawn",
+            Self::PtyRespawn { .. } => "ptyRespawn",
+            Self::ExecBatch { .. } => "execBatch",
+            Self::VfsList { .. } => "vfsList",
+            Self::VfsListStream { .. } => "vfsListStream",
+            Self::VfsGetTree { .. } => "vfsGetTree",
+            Self::VfsGetTreeDelta { .. } => "vfsGetTreeDelta",
+            Self::PathComplete { .. } => "pathComplete",
+            Self::VfsSuggestName { .. } => "vfsSuggestName",
+            Self::VfsReadFile { .. } => "vfsReadFile",
+            Self::VfsReadFileById { .. } => "vfsReadFileById",
+            Self::VfsReadFileStream { .. } => "vfsReadFileStream",
+            Self::VfsStat { .. } => "vfsStat",
+            Self::VfsGetAttrs { .. } => "vfsGetAttrs",
+            Self::VfsSetAttr { .. } => "vfsSetAttr",
+            Self::VfsCloseFile { .. } => "vfsCloseFile",
+            Self::VfsStatMany { .. } => "vfsStatMany",
+            Self::VfsThumbnail { .. } => "vfsThumbnail",
+            Self::VfsWriteFile { .. } => "vfsWriteFile",
+            Self::VfsWriteFileById { .. } => "vfsWriteFileById",
+            Self::VfsCreateNode { .. } => "vfsCreateNode",
+            Self::VfsMoveNode { .. } => "vfsMoveNode",
+            Self::VfsMoveNodes { .. } => "vfsMoveNodes",
+            Self::VfsTrashNode { .. } => "vfsTrashNode",
+            Self::VfsListTrash { .. } => "vfsListTrash",
+            Self::VfsRestoreNode { .. } => "vfsRestoreNode",
+            Self::VfsUndo { .. } => "vfsUndo",
+            Self::VfsRestoreAll => "vfsRestoreAll",
+            Self::VfsDeleteNode { .. } => "vfsDeleteNode",
+            Self::VfsEmptyTrash => "vfsEmptyTrash",
+            Self::VfsGetQuota => "vfsGetQuota",
+            Self::VfsWatch { .. } => "vfsWatch",
+            Self::VfsUnwatch { .. } => "vfsUnwatch",
+            Self::GrantObserver { .. } => "grantObserver",
+            Self::AttachObserver { .. } => "attachObserver",
+            Self::DetachObserver => "detachObserver",
+            Self::ListPresence => "listPresence",
+            Self::WhoAmI => "whoAmI",
+            Self::Broadcast { .. } => "broadcast",
+            Self::VerifyStorage => "verifyStorage",
+            Self::ExportUser { .. } => "exportUser",
+            Self::ImportUser { .. } => "importUser",
+            Self::AnswerServerRequest { .. } => "answerServerRequest",
+        }
+    }
+
+    /// The name `HEAVY_REQUESTS` lists the request under: its `kind`, except that a recursive
+    /// `VfsList` is `vfsListDescendants`, since it can cost as much as a whole tree.
+    pub fn limit_kind(&self) -> &'static str {
+        match self {
+            Self::VfsList { recursive: true, .. } => "vfsListDescendants",
+            _ => self.kind(),
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
 pub struct ServerResponse {
     pub request_id: RequestId,
+    /// Set on `Error` responses, for support to find the session in the logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
     #[serde(flatten)]
     pub payload: ServerResponsePayload,
 }
@@ -39,12 +280,69 @@ pub struct ServerResponse {
 #[serde(tag = "type", content = "payload")]
 #[serde(rename_all = "camelCase")]
 pub enum ServerResponsePayload {
-    LoginSuccess { user: UserInfo },
-    Error { message: String },
+    /// `terminal_available` is false when the shell couldn't be started; everything else still
+    /// works, and terminal requests fail with `TERMINAL_UNAVAILABLE` until a `PtySpawn` succeeds.
+    LoginSuccess { user: UserInfo, session_id: String, resume_token: String, terminal_available: bool },
+    Error {
+        message: String,
+        /// Stable identifier for errors a client is expected to branch on, e.g. `IS_A_DIRECTORY`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<&'static str>,
+    },
+    /// A rate-limited request was refused; it can be retried after `retry_after_ms`.
+    RateLimited { retry_after_ms: u64 },
+    /// The serialized result exceeded the server's frame limit; the client should narrow the query.
+    ResultTooLarge { size: usize, limit: usize },
     VfsListResponse { items: Vec<FileNode> },
-    VfsReadFileResponse { content: String },
+    /// The result of a mutation that asked for it: the full listing of the directory it changed.
+    VfsParentListingResponse { parent: String, items: Vec<FileNode> },
+    VfsListRecursiveResponse { items: Vec<DescendantNode> },
+    VfsGetTreeResponse { items: Vec<TreeNode> },
+    /// `removed` holds the ids of children that were deleted, trashed or moved elsewhere.
+    VfsGetTreeDeltaResponse { version: i64, changed: Vec<FileNode>, removed: Vec<i64> },
+    /// `prefix` is the part of the input being completed; each match replaces it.
+    PathCompleteResponse { prefix: String, matches: Vec<FileNode> },
+    VfsSuggestNameResponse { name: String },
+    VfsReadFileResponse {
+        id: i64,
+        content: String,
+        encoding: ContentEncoding,
+        is_binary: bool,
+        rev: i64,
+        /// Hex SHA-256 of the bytes `content` decodes to, for the client to check it against.
+        sha256: String,
+        /// Only present when `line_info` was requested and the file is text.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        line_count: Option<usize>,
+        /// Absent as above, or when the file has no line breaks.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        line_ending: Option<LineEnding>,
+    },
+    NotModified { rev: i64 },
+    VfsStatResponse { entry: StatEntry },
+    VfsGetAttrsResponse { attrs: BTreeMap<String, String> },
+    /// One entry per requested path, in request order; `None` where the path doesn't exist.
+    VfsStatManyResponse { entries: Vec<Option<StatEntry>> },
+    /// `data` is the base64 image, of type `mime`.
+    VfsThumbnailResponse { rev: i64, mime: String, width: u32, height: u32, data: String },
     Success,
-    VfsListTrashResponse { items: Vec<TrashedFileNode> },
+    /// `id` can be passed straight to `VfsRestoreNode` to undo the trash.
+    VfsTrashNodeResponse { id: i64, original_path: String, undo_token: String },
+    VfsMoveNodeResponse { undo_token: String },
+    /// `total` counts every trashed item, not just this page.
+    VfsListTrashResponse { items: Vec<TrashedFileNode>, total: i64 },
+    VfsRestoreAllResponse { paths: Vec<String> },
+    /// One result per source, in request order.
+    VfsMoveNodesResponse { results: Vec<MoveResult> },
+    /// `quota_bytes` is `None` for an unlimited user. Trashed files are reported separately
+    /// from `used_bytes` but both count against the quota.
+    VfsGetQuotaResponse { quota_bytes: Option<i64>, used_bytes: i64, trash_bytes: i64 },
+    ExecBatchResponse { steps: Vec<StepResult> },
+    /// `missing` counts files whose blob is still nowhere to be found after any rebasing.
+    VerifyStorageResponse { checked: u64, rebased: u64, missing: u64 },
+    ImportUserResponse { imported: u64 },
+    ListPresenceResponse { users: Vec<PresenceEntry> },
+    WhoAmIResponse { user: UserInfo, session_id: String, cwd: String },
 }
 
 #[derive(Serialize, Debug)]
@@ -52,6 +350,32 @@ pub enum ServerResponsePayload {
 pub enum ServerMessage {
     Response(ServerResponse),
     Push(ServerPush),
+    Request(ServerRequest),
+}
+
+/// A question for the client, answered with `AnswerServerRequest`. The request that prompted
+/// it gets its response once the answer arrives.
+#[derive(Serialize, Debug)]
+pub struct ServerRequest {
+    pub server_request_id: RequestId,
+    #[serde(flatten)]
+    pub payload: ServerRequestPayload,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "payload")]
+#[serde(rename_all = "camelCase")]
+pub enum ServerRequestPayload {
+    /// `VfsWriteFile` request `request_id` expected `expected_rev` but found `current_rev`.
+    /// Answered with `Confirm`: true overwrites, false fails the write with `REV_MISMATCH`.
+    ConfirmOverwrite { request_id: RequestId, path: String, expected_rev: i64, current_rev: i64 },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload")]
+#[serde(rename_all = "camelCase")]
+pub enum ServerRequestAnswer {
+    Confirm { confirmed: bool },
 }
 
 #[derive(Serialize, Debug)]
@@ -65,7 +389,111 @@ pub struct ServerPush {
 #[serde(rename_all = "camelCase")]
 pub enum ServerPushPayload {
     TerminalOutput { output: String },
+    TerminalExit { terminal_id: String, exit_code: Option<i32> },
+    /// The session's cwd, which relative paths in requests resolve against, has changed.
+    CwdChanged { terminal_id: String, path: String },
     VfsUpdate { path: String },
+    /// A file this session has open was moved, directly or along with a directory.
+    VfsFileMoved { id: i64, old_path: String, new_path: String },
+    ObserverJoined { username: String },
+    ObserverLeft { username: String },
+    ObservedOutput { session_id: String, output: String },
+    ObservationEnded { session_id: String },
+    /// A batch of a `VfsListStream` listing.
+    VfsListChunk { request_id: RequestId, items: Vec<FileNode>, eof: bool },
+    /// A base64 batch of a `VfsReadFileStream` download, starting at byte `offset`. The `eof`
+    /// chunk carries the hex SHA-256 of the whole file, whatever `start_offset` was.
+    VfsReadChunk {
+        request_id: RequestId,
+        offset: u64,
+        data: String,
+        rev: i64,
+        eof: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+    },
+    /// A base64 batch of an `ExportUser` bundle.
+    ExportChunk { request_id: RequestId, data: String, eof: bool },
+    Announcement { message: String },
+    /// The session will be closed for inactivity unless the client sends something first.
+    IdleWarning { seconds_remaining: u64 },
+}
+
+/// How a terminal's output reaches the client.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum TerminalEncoding {
+    /// `TerminalOutput` pushes, with bytes that aren't UTF-8 shown as U+FFFD.
+    #[default]
+    Lossy,
+    /// The shell's bytes exactly as read, in binary WebSocket frames, OSC 7 reports included.
+    /// Scrollback and observers still get the lossy text.
+    Raw,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    #[default]
+    Base64,
+    Utf8,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Mixed,
+}
+
+/// Names and paths sort ascending; the trash date sorts newest first.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum TrashSort {
+    Name,
+    OriginalPath,
+    #[default]
+    TrashedAt,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MoveResult {
+    pub src_path: String,
+    /// `None` if the source couldn't be resolved.
+    pub new_path: Option<String>,
+    /// Set when this item wasn't moved.
+    pub error: Option<String>,
+    pub code: Option<&'static str>,
+}
+
+/// An `ExecBatch` step: a plain string runs through `sh -c`, while `{ "argv": [...] }` runs the
+/// program directly with those exact arguments, so nothing in them is interpreted by a shell.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BatchCommand {
+    Shell(String),
+    Argv { argv: Vec<String> },
+}
+
+#[derive(Serialize, Debug)]
+pub struct PresenceEntry {
+    pub username: String,
+    /// When the user's longest-connected session connected.
+    pub connected_at: DateTime<Utc>,
+    /// Running terminals across all of the user's sessions.
+    pub terminals: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StepResult {
+    pub command: String,
+    /// `None` if the command couldn't be started or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Output beyond the first MiB of either stream was left out.
+    pub truncated: bool,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -77,12 +505,97 @@ pub struct UserInfo {
 
 #[derive(Serialize, Debug, sqlx::FromRow)]
 pub struct FileNode {
+    pub id: i64,
     pub name: String,
+    /// Superseded by `kind`; kept until clients have moved over.
+    pub node_type: String,
+    #[sqlx(rename = "node_type", try_from = "String")]
+    pub kind: NodeKind,
+    /// Where a symlink points. Nothing creates symlinks yet, so this is always absent.
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub size: i64,
+    pub updated_at: DateTime<Utc>,
+    /// Whether a directory has any live children, hidden ones included. Always false for files.
+    pub has_children: bool,
+    /// Other sessions that currently have the file open.
+    #[sqlx(skip)]
+    pub open_by: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl TryFrom<String> for NodeKind {
+    type Error = String;
+
+    fn try_from(node_type: String) -> Result<Self, Self::Error> {
+        match node_type.as_str() {
+            "file" => Ok(NodeKind::File),
+            "dir" => Ok(NodeKind::Directory),
+            "symlink" => Ok(NodeKind::Symlink),
+            _ => Err(format!("Unknown node type '{}'", node_type)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct DescendantNode {
+    /// Relative to the listed directory, e.g. `src/main.rs`.
+    pub path: String,
     pub node_type: String,
     pub size: i64,
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct StatEntry {
+    pub id: i64,
+    pub name: String,
+    pub node_type: String,
+    pub size: i64,
+    pub is_binary: bool,
+    pub rev: i64,
+    #[sqlx(try_from = "i64")]
+    pub mode: u32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Other sessions that currently have the file open.
+    #[sqlx(skip)]
+    pub open_by: Vec<String>,
+    /// How the client should open the node; see `vfs::openable_as`.
+    #[sqlx(skip)]
+    pub openable_as: Option<OpenableAs>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OpenableAs {
+    Text,
+    Image,
+    Binary,
+    Directory,
+    /// Reading it would exceed the response size limit.
+    TooLarge,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TreeNode {
+    pub name: String,
+    pub node_type: String,
+    pub size: i64,
+    pub updated_at: DateTime<Utc>,
+    /// Number of live children, including any beyond the requested depth.
+    pub child_count: i64,
+    pub children: Vec<TreeNode>,
+}
+
 #[derive(Serialize, Debug, sqlx::FromRow)]
 pub struct TrashedFileNode {
     pub id: i64,
@@ -90,3 +603,35 @@ pub struct TrashedFileNode {
     pub original_path: String,
     pub trashed_at: DateTime<Utc>,
 }
+
+fn default_true() -> bool {
+    true
+}
+
+/// Renders a raw protocol frame for debug logging with every `password` field masked.
+/// Frames that aren't valid JSON can't be redacted reliably, so only their size is reported.
+pub fn redact_for_log(raw: &str) -> String {
+    match serde_json::from_str::<Value>(raw) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<unparseable frame, {} bytes>", raw.len()),
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if key == "password" {
+                    *field = Value::String("***".to_string());
+                } else {
+                    redact_value(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
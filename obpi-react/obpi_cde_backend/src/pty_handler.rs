@@ -1,23 +1,140 @@
+use crate::ansi::{self, Osc7Parser};
+use crate::config::PtyConfig;
+use crate::protocol::TerminalEncoding;
 use pty_process_tokio::PtyProcess;
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::io::{AsyncWriteExt, AsyncReadExt};
 use tokio::sync::mpsc;
 
+/// Shell integration for bash: report the working directory as an OSC 7 escape before every
+/// prompt so the session can track `cd`, `pushd`, scripts, etc. without parsing commands.
+/// It is installed through `PROMPT_COMMAND` in the spawned shell's environment.
+pub const BASH_OSC7_HOOK: &str = r#"printf '\033]7;file://%s%s\033\\' "${HOSTNAME}" "${PWD}""#;
+
 pub enum PtyMessage {
     Output(String),
+    /// Output of a terminal spawned with `TerminalEncoding::Raw`.
+    RawOutput(Vec<u8>),
+    Cwd(PathBuf),
+    /// The shell exited; carries its exit code, if it had one.
+    Exit(Option<i32>),
+}
+
+/// The most recent terminal output, replayed to a client that resumes a session.
+pub struct Scrollback {
+    chunks: VecDeque<String>,
+    bytes: usize,
+    limit: usize,
 }
 
+impl Scrollback {
+    pub fn new(limit: usize) -> Self {
+        Self { chunks: VecDeque::new(), bytes: 0, limit }
+    }
+
+    pub fn push(&mut self, output: &str) {
+        self.bytes += output.len();
+        self.chunks.push_back(output.to_string());
+        while self.bytes > self.limit && self.chunks.len() > 1 {
+            if let Some(oldest) = self.chunks.pop_front() {
+                self.bytes -= oldest.len();
+            }
+        }
+    }
+
+    pub fn contents(&self) -> String {
+        self.chunks.iter().map(String::as_str).collect()
+    }
+
+    /// Drops the oldest output until at most `max` bytes remain, though never the latest chunk.
+    pub fn trim_to(&mut self, max: usize) {
+        while self.bytes > max && self.chunks.len() > 1 {
+            if let Some(oldest) = self.chunks.pop_front() {
+                self.bytes -= oldest.len();
+            }
+        }
+    }
+}
+
+impl PtyMessage {
+    /// Output bytes the message holds, as counted by `PtyHandler::in_flight_bytes`.
+    pub fn output_len(&self) -> usize {
+        match self {
+            PtyMessage::Output(output) => output.len(),
+            PtyMessage::RawOutput(bytes) => bytes.len(),
+            PtyMessage::Cwd(_) | PtyMessage::Exit(_) => 0,
+        }
+    }
+}
+
+/// Input is written to the PTY in pieces of at most this size, so one huge write doesn't sit
+/// in the kernel's buffer all at once.
+const PTY_WRITE_CHUNK: usize = 4096;
+
+/// The only terminal a session has for now; requests name it so more can be added later.
+pub const DEFAULT_TERMINAL_ID: &str = "main";
+
 pub struct PtyHandler {
+    config: PtyConfig,
     pty_writer: Option<mpsc::UnboundedSender<String>>,
+    /// Whether the shell last asked for bracketed paste, tracked from its output.
+    bracketed_paste: Arc<AtomicBool>,
+    /// Output bytes sent to the session's channel that it hasn't received yet.
+    in_flight: Arc<AtomicUsize>,
 }
 
 impl PtyHandler {
-    pub fn new() -> Self { Self { pty_writer: None } }
+    pub fn new(config: PtyConfig) -> Self {
+        Self { config, pty_writer: None, bracketed_paste: Arc::new(AtomicBool::new(false)), in_flight: Arc::new(AtomicUsize::new(0)) }
+    }
 
-    pub fn spawn(&mut self, _cwd: PathBuf, output_tx: mpsc::UnboundedSender<PtyMessage>) -> Result<(), String> {
-        let process = PtyProcess::spawn(Command::new("bash")).map_err(|e| e.to_string())?;
+    /// Starts the shell in `cwd` when that directory exists on the host.
+    ///
+    /// With a `history_file`, bash appends each command to it as soon as it finishes
+    /// (`history -a` before every prompt). Nothing is lost if the terminal is killed.
+    ///
+    /// The shell is interactive and reads `rc_file` (see `shell_rc::write_rc_file`) in place of
+    /// the usual startup files; without one it runs as an ordinary login shell.
+    ///
+    /// `startup_command` is typed into the shell once, when the OSC 7 hook first reports the
+    /// cwd: `PROMPT_COMMAND` runs just before the first prompt, so initialization is finished
+    /// and the line isn't swallowed or echoed out of order.
+    ///
+    /// Does nothing if a shell is already running, so a retried spawn can't orphan one.
+    pub fn spawn(&mut self, cwd: PathBuf, history_file: Option<&Path>, rc_file: Option<&Path>, startup_command: Option<&str>, encoding: TerminalEncoding, output_tx: mpsc::Sender<PtyMessage>) -> Result<(), String> {
+        if self.is_running() {
+            return Ok(());
+        }
+        let mut command = Command::new("bash");
+        match rc_file {
+            Some(rc_file) => command.arg("--noprofile").arg("--rcfile").arg(rc_file).arg("-i"),
+            None => command.arg("-l").arg("-i"),
+        };
+        if cwd.is_dir() {
+            command.current_dir(&cwd);
+        }
+        let prompt_command = match history_file {
+            Some(history_file) => {
+                if let Some(dir) = history_file.parent() {
+                    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                }
+                command.env("HISTFILE", history_file);
+                format!("history -a; {}", BASH_OSC7_HOOK)
+            }
+            None => BASH_OSC7_HOOK.to_string(),
+        };
+        // Kept separately so the rc file can restore the hooks if a user's startup files
+        // overwrite PROMPT_COMMAND.
+        command.env("OBPI_PROMPT_COMMAND", &prompt_command);
+        command.env("PROMPT_COMMAND", prompt_command);
+        let mut process = PtyProcess::spawn(command).map_err(|e| e.to_string())?;
         let (pty_tx, mut pty_rx) = mpsc::unbounded_channel::<String>();
+        let startup_tx = pty_tx.clone();
+        let mut startup_command = startup_command.map(|command| format!("{}\n", command));
         self.pty_writer = Some(pty_tx);
 
         let mut master = process.master.clone();
@@ -25,27 +142,88 @@ impl PtyHandler {
 
         tokio::spawn(async move {
             while let Some(cmd) = pty_rx.recv().await {
-                if child_writer.write_all(cmd.as_bytes()).await.is_err() { break; }
+                for chunk in cmd.as_bytes().chunks(PTY_WRITE_CHUNK) {
+                    if child_writer.write_all(chunk).await.is_err() { return; }
+                }
             }
         });
 
+        let mut osc7 = Osc7Parser::new(self.config.osc7_passthrough);
+        let bracketed_paste = self.bracketed_paste.clone();
+        let in_flight = self.in_flight.clone();
         tokio::spawn(async move {
             let mut buf = [0u8; 4096];
+            let mut undecoded = Vec::new();
             loop {
                 match master.read(&mut buf).await {
                     Ok(0) | Err(_) => { break; }
                     Ok(n) => {
-                        if let Ok(s) = String::from_utf8(buf[..n].to_vec()) {
-                            if output_tx.send(PtyMessage::Output(s)).is_err() { break; }
+                        undecoded.extend_from_slice(&buf[..n]);
+                        let s = take_utf8(&mut undecoded);
+                        if let Some(enabled) = ansi::bracketed_paste_mode(&s) {
+                            bracketed_paste.store(enabled, Ordering::Relaxed);
+                        }
+                        let (output, cwd) = osc7.feed(&s);
+                        if let Some(cwd) = cwd {
+                            if let Some(command) = startup_command.take() {
+                                let _ = startup_tx.send(command);
+                            }
+                            if output_tx.send(PtyMessage::Cwd(cwd)).await.is_err() { return; }
                         }
+                        let message = match encoding {
+                            TerminalEncoding::Lossy if output.is_empty() => continue,
+                            TerminalEncoding::Lossy => PtyMessage::Output(output),
+                            TerminalEncoding::Raw => PtyMessage::RawOutput(buf[..n].to_vec()),
+                        };
+                        in_flight.fetch_add(message.output_len(), Ordering::Relaxed);
+                        if output_tx.send(message).await.is_err() { return; }
                     }
                 }
             }
+            // Bytes held back for a character or escape sequence that never completed still go
+            // out, ahead of the exit. Raw output was forwarded as it was read.
+            let (mut output, _) = osc7.feed(&String::from_utf8_lossy(&undecoded));
+            output.push_str(&osc7.finish());
+            if encoding == TerminalEncoding::Lossy && !output.is_empty() {
+                in_flight.fetch_add(output.len(), Ordering::Relaxed);
+                if output_tx.send(PtyMessage::Output(output)).await.is_err() { return; }
+            }
+            let exit_code = process.child.wait().await.ok().and_then(|status| status.code());
+            let _ = output_tx.send(PtyMessage::Exit(exit_code)).await;
         });
 
         Ok(())
     }
 
+    /// Output the reader has queued for the session and the session hasn't taken yet.
+    pub fn in_flight_bytes(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Called by the session for each message it takes off the output channel.
+    pub fn received(&self, message: &PtyMessage) {
+        self.in_flight.fetch_sub(message.output_len(), Ordering::Relaxed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.pty_writer.is_some()
+    }
+
+    /// Drops the writer to a shell that has exited so it can be respawned.
+    pub fn mark_exited(&mut self) {
+        self.pty_writer = None;
+    }
+
+    /// Sends pasted text, bracketed when the shell supports it so multi-line pastes aren't
+    /// executed line by line.
+    pub fn paste(&self, data: &str) {
+        if self.bracketed_paste.load(Ordering::Relaxed) {
+            self.send_command(ansi::bracket_paste(data));
+        } else {
+            self.send_command(data.to_string());
+        }
+    }
+
     pub fn send_command(&self, cmd: String) {
         if let Some(writer) = &self.pty_writer {
             if writer.send(cmd).is_err() {
@@ -54,3 +232,77 @@ impl PtyHandler {
         }
     }
 }
+
+/// Decodes `bytes` as far as it can, leaving a multi-byte character cut off by the end of a
+/// read for the next one. Invalid sequences become U+FFFD rather than dropping the chunk.
+fn take_utf8(bytes: &mut Vec<u8>) -> String {
+    let lead = bytes.iter().rev().take(4).position(|&b| b & 0xC0 != 0x80).map(|i| bytes.len() - 1 - i);
+    let held = match lead.map(|i| (i, bytes[i])) {
+        Some((i, b)) if b >= 0xF0 && bytes.len() - i < 4 => bytes.len() - i,
+        Some((i, b)) if (0xE0..0xF0).contains(&b) && bytes.len() - i < 3 => bytes.len() - i,
+        Some((i, b)) if (0xC0..0xE0).contains(&b) && bytes.len() - i < 2 => bytes.len() - i,
+        _ => 0,
+    };
+    let rest = bytes.split_off(bytes.len() - held);
+    let decoded = String::from_utf8_lossy(bytes).into_owned();
+    *bytes = rest;
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn handler() -> PtyHandler {
+        PtyHandler::new(PtyConfig {
+            output_capacity: 64,
+            scrollback_bytes: 1024,
+            session_output_bytes: None,
+            osc7_passthrough: false,
+            history_dir: None,
+            rc_dir: std::env::temp_dir(),
+            startup_command: None,
+        })
+    }
+
+    fn spawn(handler: &mut PtyHandler) -> mpsc::Receiver<PtyMessage> {
+        let (tx, rx) = mpsc::channel(64);
+        handler.spawn(std::env::temp_dir(), None, None, None, TerminalEncoding::default(), tx).unwrap();
+        rx
+    }
+
+    /// Everything the shell prints until it exits.
+    async fn output_until_exit(rx: &mut mpsc::Receiver<PtyMessage>) -> String {
+        let mut output = String::new();
+        loop {
+            match tokio::time::timeout(Duration::from_secs(10), rx.recv()).await.expect("shell didn't exit") {
+                Some(PtyMessage::Output(chunk)) => output.push_str(&chunk),
+                Some(PtyMessage::Exit(_)) | None => return output,
+                Some(_) => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn second_spawn_keeps_the_running_shell() {
+        let mut handler = handler();
+        let mut first = spawn(&mut handler);
+        let mut second = spawn(&mut handler);
+        // The second spawn returned without starting a shell, so its channel is already closed.
+        assert!(second.recv().await.is_none());
+        handler.send_command("echo still''here; exit\n".to_string());
+        assert!(output_until_exit(&mut first).await.contains("stillhere"));
+    }
+
+    #[tokio::test]
+    async fn output_before_exit_is_flushed() {
+        let mut handler = handler();
+        let mut rx = spawn(&mut handler);
+        // The unterminated escape is held back by the OSC 7 parser until the shell exits.
+        handler.send_command("echo by''e; printf 'tail\\033]7;'; exit\n".to_string());
+        let output = output_until_exit(&mut rx).await;
+        assert!(output.contains("bye"), "{:?}", output);
+        assert!(output.contains("tail\u{1b}]7;"), "{:?}", output);
+    }
+}
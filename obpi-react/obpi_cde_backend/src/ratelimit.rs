@@ -0,0 +1,44 @@
+use crate::config::RateLimitConfig;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets for expensive requests, one per user and shared by all of that user's
+/// sessions so opening more connections doesn't buy more capacity.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Arc<Mutex<HashMap<i64, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self { config, buckets: Arc::default() }
+    }
+
+    /// Takes a token if `kind` is rate limited. Returns how long to wait when the bucket is
+    /// empty; requests that aren't limited always pass.
+    pub fn check(&self, user_id: i64, kind: &str) -> Result<(), Duration> {
+        if self.config.per_minute == 0 || !self.config.heavy_requests.contains(kind) {
+            return Ok(());
+        }
+        let capacity = self.config.burst as f64;
+        let per_sec = self.config.per_minute as f64 / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(user_id).or_insert(Bucket { tokens: capacity, refilled_at: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * per_sec).min(capacity);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
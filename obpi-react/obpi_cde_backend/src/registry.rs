@@ -0,0 +1,186 @@
+use crate::protocol::{PresenceEntry, ServerPushPayload, UserInfo};
+use crate::session::UserSession;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+
+/// The parts of a logged-in session that other sessions are allowed to reach.
+pub struct SessionHandle {
+    pub terminal_output: broadcast::Sender<String>,
+    /// Bounded, so pushes for a session that isn't draining them (e.g. a parked one) are
+    /// dropped once it fills instead of piling up.
+    pub events: mpsc::Sender<ServerPushPayload>,
+    pub granted_observers: HashSet<String>,
+}
+
+/// A connected, logged-in session as `ListPresence` sees it.
+struct Presence {
+    username: String,
+    connected_at: DateTime<Utc>,
+    /// When the client last sent anything, heartbeat pongs included.
+    last_seen: Instant,
+    terminals: u32,
+}
+
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<String, SessionHandle>>>,
+    /// Disconnected sessions kept alive for their grace window, keyed by resume token.
+    parked: Arc<Mutex<HashMap<String, UserSession>>>,
+    /// Sessions that have each file open, keyed by file id so renames don't lose track.
+    open_files: Arc<Mutex<HashMap<i64, HashSet<String>>>>,
+    /// Sessions with a live socket. Parked sessions leave it until they're resumed.
+    presence: Arc<Mutex<HashMap<String, Presence>>>,
+}
+
+impl SessionRegistry {
+    pub fn register(&self, session_id: String, handle: SessionHandle) {
+        self.sessions.lock().unwrap().insert(session_id, handle);
+    }
+
+    pub fn unregister(&self, session_id: &str) {
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+
+    pub fn grant_observer(&self, session_id: &str, username: String) {
+        if let Some(handle) = self.sessions.lock().unwrap().get_mut(session_id) {
+            handle.granted_observers.insert(username);
+        }
+    }
+
+    /// Subscribes `observer` to the target session's terminal output. Admins may observe any
+    /// session; everyone else needs a grant from the owner. The owner is told who joined.
+    pub fn attach_observer(&self, session_id: &str, observer: &UserInfo) -> Result<broadcast::Receiver<String>> {
+        let sessions = self.sessions.lock().unwrap();
+        let target = sessions.get(session_id).ok_or_else(|| anyhow!("Session not found"))?;
+        if observer.role != "Admin" && !target.granted_observers.contains(&observer.username) {
+            return Err(anyhow!("Not permitted to observe this session"));
+        }
+        let _ = target.events.try_send(ServerPushPayload::ObserverJoined { username: observer.username.clone() });
+        Ok(target.terminal_output.subscribe())
+    }
+
+    pub fn notify(&self, session_id: &str, payload: ServerPushPayload) {
+        if let Some(handle) = self.sessions.lock().unwrap().get(session_id) {
+            let _ = handle.events.try_send(payload);
+        }
+    }
+
+    pub fn open_file(&self, file_id: i64, session_id: &str) {
+        self.open_files.lock().unwrap().entry(file_id).or_default().insert(session_id.to_string());
+    }
+
+    pub fn close_file(&self, file_id: i64, session_id: &str) {
+        let mut open_files = self.open_files.lock().unwrap();
+        if let Some(sessions) = open_files.get_mut(&file_id) {
+            sessions.remove(session_id);
+            if sessions.is_empty() {
+                open_files.remove(&file_id);
+            }
+        }
+    }
+
+    pub fn close_all_files(&self, session_id: &str) {
+        let mut open_files = self.open_files.lock().unwrap();
+        open_files.retain(|_, sessions| {
+            sessions.remove(session_id);
+            !sessions.is_empty()
+        });
+    }
+
+    pub fn open_file_ids(&self) -> Vec<i64> {
+        self.open_files.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Sends a push to every session that has `file_id` open, including the caller's.
+    pub fn notify_open_file(&self, file_id: i64, payload: impl Fn() -> ServerPushPayload) {
+        let open_files = self.open_files.lock().unwrap();
+        let sessions = self.sessions.lock().unwrap();
+        for session_id in open_files.get(&file_id).into_iter().flatten() {
+            if let Some(handle) = sessions.get(session_id) {
+                let _ = handle.events.try_send(payload());
+            }
+        }
+    }
+
+    /// The other sessions that have `file_id` open, sorted.
+    pub fn open_by(&self, file_id: i64, session_id: &str) -> Vec<String> {
+        let open_files = self.open_files.lock().unwrap();
+        let mut sessions: Vec<String> = open_files
+            .get(&file_id)
+            .map(|sessions| sessions.iter().filter(|s| *s != session_id).cloned().collect())
+            .unwrap_or_default();
+        sessions.sort();
+        sessions
+    }
+
+    pub fn join_presence(&self, session_id: &str, username: String, terminals: u32) {
+        let presence = Presence { username, connected_at: Utc::now(), last_seen: Instant::now(), terminals };
+        self.presence.lock().unwrap().insert(session_id.to_string(), presence);
+    }
+
+    pub fn leave_presence(&self, session_id: &str) {
+        self.presence.lock().unwrap().remove(session_id);
+    }
+
+    pub fn touch(&self, session_id: &str) {
+        if let Some(presence) = self.presence.lock().unwrap().get_mut(session_id) {
+            presence.last_seen = Instant::now();
+        }
+    }
+
+    pub fn set_terminals(&self, session_id: &str, terminals: u32) {
+        if let Some(presence) = self.presence.lock().unwrap().get_mut(session_id) {
+            presence.terminals = terminals;
+        }
+    }
+
+    /// Users with at least one session heard from within `timeout`, by username. A user's
+    /// sessions are combined: the earliest connection time and the total terminal count.
+    pub fn list_presence(&self, timeout: Duration) -> Vec<PresenceEntry> {
+        let mut users: BTreeMap<&str, PresenceEntry> = BTreeMap::new();
+        let presence = self.presence.lock().unwrap();
+        for session in presence.values().filter(|session| session.last_seen.elapsed() <= timeout) {
+            let entry = users.entry(&session.username).or_insert_with(|| PresenceEntry {
+                username: session.username.clone(),
+                connected_at: session.connected_at,
+                terminals: 0,
+            });
+            entry.connected_at = entry.connected_at.min(session.connected_at);
+            entry.terminals += session.terminals;
+        }
+        users.into_values().collect()
+    }
+
+    pub fn park(&self, resume_token: String, session: UserSession) {
+        self.parked.lock().unwrap().insert(resume_token, session);
+    }
+
+    pub fn take_parked(&self, resume_token: &str) -> Option<UserSession> {
+        self.parked.lock().unwrap().remove(resume_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undrained_events_are_dropped() {
+        let registry = SessionRegistry::default();
+        let (events, mut events_rx) = mpsc::channel(2);
+        let (terminal_output, _) = broadcast::channel(1);
+        registry.register("s".to_string(), SessionHandle { terminal_output, events, granted_observers: HashSet::new() });
+        for n in 0..5 {
+            registry.notify("s", ServerPushPayload::Announcement { message: n.to_string() });
+        }
+        let mut received = Vec::new();
+        while let Ok(ServerPushPayload::Announcement { message }) = events_rx.try_recv() {
+            received.push(message);
+        }
+        assert_eq!(received, ["0", "1"]);
+    }
+}
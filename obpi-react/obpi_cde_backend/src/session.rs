@@ -1,94 +1,407 @@
 use axum::extract::ws::{Message, WebSocket};
-use futures_util::{stream::{SplitSink}, StreamExt};
-use std::path::PathBuf;
+use futures_util::{stream::{SplitSink}, SinkExt, StreamExt};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+use tracing::Instrument;
+use uuid::Uuid;
+use crate::config::Config;
 use crate::db::{self, DbPool};
-use crate::pty_handler::{PtyHandler, PtyMessage};
-use crate::protocol::{ClientRequest, ClientRequestPayload, ServerMessage, ServerPush, ServerPushPayload, ServerResponse, ServerResponsePayload, UserInfo};
+use crate::exec;
+use crate::history;
+use crate::pty_handler::{PtyHandler, PtyMessage, Scrollback, DEFAULT_TERMINAL_ID};
+use crate::protocol::{self, ClientRequest, ClientRequestPayload, RequestId, ServerMessage, ServerPush, ServerPushPayload, ServerRequest, ServerRequestAnswer, ServerRequestPayload, ServerResponse, ServerResponsePayload, TerminalEncoding, UserInfo};
+use crate::ratelimit::RateLimiter;
+use crate::registry::{SessionHandle, SessionRegistry};
+use crate::shell_rc;
+use crate::state::AppState;
 use crate::vfs;
 
+const OBSERVER_BUFFER: usize = 256;
+/// Pushes from other sessions waiting to be sent. More are dropped, which only happens while
+/// the session isn't draining them, mostly when it's parked.
+const EVENT_BUFFER: usize = 256;
+const LIST_CHUNK_SIZE: usize = 256;
+/// Bytes per `VfsReadChunk`, before base64.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_PENDING_SERVER_REQUESTS: usize = 16;
+/// Longest `RunCommand` line accepted; anything longer is almost certainly a mistake.
+const MAX_COMMAND_BYTES: usize = 64 * 1024;
+/// Older undo tokens are forgotten once a session holds this many.
+const MAX_UNDO_TOKENS: usize = 64;
+/// How often logged-in clients are pinged. Browsers answer pings on their own.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// A session silent for longer than this (two missed heartbeats) drops out of `ListPresence`.
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(75);
+
+pub enum SessionError {
+    /// The request was bad but the connection is fine: report it and keep serving.
+    Recoverable { request_id: RequestId, message: String },
+    /// The connection can't continue and the session must be torn down.
+    Fatal(String),
+}
+
+/// What a request needs to finish once the client answers the `ServerRequest` it sent.
+enum PendingServerRequest {
+    Overwrite { request_id: RequestId, write: PendingWrite, current_rev: i64 },
+}
+
+/// A `VfsWriteFile` kept until it can go ahead.
+struct PendingWrite {
+    path: String,
+    content: String,
+    source_encoding: Option<String>,
+    normalize_eol: bool,
+}
+
 pub struct UserSession {
-    ws: WebSocket,
-    db_pool: Arc<DbPool>,
+    session_id: String,
+    /// Tags every log line from this session with its id, and its username once logged in.
+    span: tracing::Span,
+    /// Secret that lets a reconnecting client adopt this session during its grace window.
+    resume_token: String,
+    db_pool: DbPool,
+    sessions: SessionRegistry,
+    rate_limiter: RateLimiter,
+    announcements: broadcast::Sender<String>,
+    config: Arc<Config>,
     pty_handler: PtyHandler,
+    pty_tx: mpsc::Sender<PtyMessage>,
+    pty_rx: mpsc::Receiver<PtyMessage>,
+    scrollback: Scrollback,
     user: Option<UserInfo>,
     cwd: PathBuf,
+    /// Resolved directory prefixes this session wants `VfsUpdate` pushes for.
+    /// An empty set means "everything", which keeps unscoped clients working.
+    watched_paths: HashSet<String>,
+    /// Fan-out of this session's terminal output to attached observers.
+    terminal_output: broadcast::Sender<String>,
+    events_tx: mpsc::Sender<ServerPushPayload>,
+    events_rx: mpsc::Receiver<ServerPushPayload>,
+    /// Responses from requests running as their own tasks (see `spawn_request`).
+    responses_tx: mpsc::UnboundedSender<(RequestId, ServerResponsePayload)>,
+    responses_rx: mpsc::UnboundedReceiver<(RequestId, ServerResponsePayload)>,
+    /// Server requests awaiting the client's answer, by `server_request_id`.
+    pending_server_requests: HashMap<RequestId, PendingServerRequest>,
+    /// The session we are attached to as a read-only observer, if any.
+    observing: Option<(String, broadcast::Receiver<String>)>,
+    /// What each live `undo_token` puts back, and when it expires.
+    undo_tokens: HashMap<String, (Instant, vfs::NodePlacement)>,
 }
 
 impl UserSession {
-    pub fn new(socket: WebSocket, db_pool: Arc<DbPool>) -> Self {
+    pub fn new(state: &AppState) -> Self {
+        let (terminal_output, _) = broadcast::channel(OBSERVER_BUFFER);
+        let (events_tx, events_rx) = mpsc::channel(EVENT_BUFFER);
+        let (responses_tx, responses_rx) = mpsc::unbounded_channel();
+        let (pty_tx, pty_rx) = mpsc::channel(state.config.pty.output_capacity);
+        let session_id = Uuid::new_v4().to_string();
         Self {
-            ws: socket,
-            db_pool,
-            pty_handler: PtyHandler::new(),
+            span: tracing::info_span!("session", id = %session_id, user = tracing::field::Empty),
+            session_id,
+            resume_token: Uuid::new_v4().to_string(),
+            db_pool: state.db_pool.clone(),
+            sessions: state.sessions.clone(),
+            rate_limiter: state.rate_limiter.clone(),
+            announcements: state.announcements.clone(),
+            config: state.config.clone(),
+            pty_handler: PtyHandler::new(state.config.pty.clone()),
+            pty_tx,
+            pty_rx,
+            scrollback: Scrollback::new(state.config.pty.scrollback_bytes),
             user: None,
             cwd: PathBuf::from("/"),
+            watched_paths: HashSet::new(),
+            terminal_output,
+            events_tx,
+            events_rx,
+            responses_tx,
+            responses_rx,
+            pending_server_requests: HashMap::new(),
+            observing: None,
+            undo_tokens: HashMap::new(),
         }
     }
 
-    pub async fn run(mut self) {
-        let (mut ws_sender, mut ws_receiver) = self.ws.split();
-        let (pty_tx, mut pty_rx) = mpsc::unbounded_channel();
-        
+    pub async fn run(mut self, socket: WebSocket) {
+        let (mut ws_sender, mut ws_receiver) = socket.split();
+        let mut announcements = self.announcements.subscribe();
+        let mut last_activity = Instant::now();
+        let mut idle_warned = false;
+        let mut idled_out = false;
+        let idle_timeout = self.config.idle_timeout;
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
         loop {
-            tokio::select! {
-                ws_msg = ws_receiver.next() => {
-                    if let Some(Ok(msg)) = ws_msg {
-                        if self.handle_client_message(msg, &pty_tx, &mut ws_sender).await.is_err() { break; }
-                    } else { break; }
-                },
-                pty_msg = pty_rx.recv() => {
-                    if let Some(PtyMessage::Output(output)) = pty_msg {
-                        let _ = self.send_push(ServerPushPayload::TerminalOutput { output }, &mut ws_sender).await;
-                    } else { break; }
+            let idle_deadline = self.idle_deadline(last_activity, idle_warned);
+            // Entered per iteration, since a `Resume` swaps in the parked session and its span.
+            let span = self.span.clone();
+            let result = async {
+                let result = tokio::select! {
+                    ws_msg = ws_receiver.next() => {
+                        self.sessions.touch(&self.session_id);
+                        // A pong shows the client is still there, not that the user is.
+                        if !matches!(ws_msg, Some(Ok(Message::Ping(_) | Message::Pong(_)))) {
+                            last_activity = Instant::now();
+                            idle_warned = false;
+                        }
+                        match ws_msg {
+                            Some(Ok(msg)) => self.handle_client_message(msg, &mut ws_sender).await,
+                            Some(Err(e)) => Err(SessionError::Fatal(format!("WebSocket error: {}", e))),
+                            None => Err(SessionError::Fatal("WebSocket stream ended".to_string())),
+                        }
+                    },
+                    pty_msg = self.pty_rx.recv() => {
+                        if let Some(msg) = &pty_msg {
+                            self.pty_handler.received(msg);
+                        }
+                        match pty_msg {
+                            Some(PtyMessage::Output(output)) => {
+                                self.keep_scrollback(&output);
+                                let _ = self.terminal_output.send(output.clone());
+                                self.send_push(ServerPushPayload::TerminalOutput { output }, &mut ws_sender).await
+                            }
+                            Some(PtyMessage::RawOutput(bytes)) => {
+                                let output = String::from_utf8_lossy(&bytes).into_owned();
+                                self.keep_scrollback(&output);
+                                let _ = self.terminal_output.send(output);
+                                ws_sender.send(Message::Binary(bytes)).await.map_err(|e| SessionError::Fatal(format!("Failed to send to client: {}", e)))
+                            }
+                            Some(PtyMessage::Cwd(cwd)) => self.set_cwd(cwd, &mut ws_sender).await,
+                            Some(PtyMessage::Exit(exit_code)) => {
+                                self.pty_handler.mark_exited();
+                                self.sessions.set_terminals(&self.session_id, 0);
+                                let terminal_id = DEFAULT_TERMINAL_ID.to_string();
+                                self.send_push(ServerPushPayload::TerminalExit { terminal_id, exit_code }, &mut ws_sender).await
+                            }
+                            None => Err(SessionError::Fatal("PTY channel closed".to_string())),
+                        }
+                    },
+                    Some(event) = self.events_rx.recv() => {
+                        self.send_push(event, &mut ws_sender).await
+                    },
+                    Some((request_id, payload)) = self.responses_rx.recv() => {
+                        self.send_response(request_id, payload, &mut ws_sender).await
+                    },
+                    announcement = announcements.recv() => {
+                        match announcement {
+                            // Not yet logged in: nobody to show it to.
+                            Ok(_) if self.user.is_none() => Ok(()),
+                            Ok(message) => self.send_push(ServerPushPayload::Announcement { message }, &mut ws_sender).await,
+                            Err(broadcast::error::RecvError::Lagged(_)) => Ok(()),
+                            // The sender lives in AppState, so this only happens at shutdown.
+                            Err(broadcast::error::RecvError::Closed) => Err(SessionError::Fatal("Server shutting down".to_string())),
+                        }
+                    },
+                    _ = tokio::time::sleep_until(idle_deadline), if !idle_timeout.is_zero() => {
+                        if idle_warned || self.config.idle_warning.is_zero() {
+                            idled_out = true;
+                            Err(SessionError::Fatal("Idle timeout".to_string()))
+                        } else {
+                            idle_warned = true;
+                            let seconds_remaining = self.config.idle_warning.as_secs();
+                            self.send_push(ServerPushPayload::IdleWarning { seconds_remaining }, &mut ws_sender).await
+                        }
+                    },
+                    _ = heartbeat.tick(), if self.user.is_some() => {
+                        ws_sender.send(Message::Ping(Vec::new())).await.map_err(|e| SessionError::Fatal(format!("Failed to send to client: {}", e)))
+                    },
+                    observed = recv_observed(&mut self.observing) => {
+                        match observed {
+                            Ok(output) => {
+                                let session_id = self.observing.as_ref().map(|(id, _)| id.clone()).unwrap_or_default();
+                                self.send_push(ServerPushPayload::ObservedOutput { session_id, output }, &mut ws_sender).await
+                            }
+                            Err(broadcast::error::RecvError::Lagged(_)) => Ok(()),
+                            Err(broadcast::error::RecvError::Closed) => match self.observing.take() {
+                                Some((session_id, _)) => self.send_push(ServerPushPayload::ObservationEnded { session_id }, &mut ws_sender).await,
+                                None => Ok(()),
+                            },
+                        }
+                    }
+                };
+                match result {
+                    Err(SessionError::Recoverable { request_id, message }) => {
+                        self.send_error_response(request_id, message, &mut ws_sender).await
+                    }
+                    other => other,
                 }
             }
+            .instrument(span)
+            .await;
+            if let Err(SessionError::Fatal(reason)) = result {
+                self.span.in_scope(|| tracing::debug!("Closing session: {}", reason));
+                break;
+            }
         }
+
+        let grace = self.config.resume_grace;
+        if self.user.is_some() && !grace.is_zero() && !idled_out {
+            self.park(grace);
+        } else {
+            self.close();
+        }
+    }
+
+    /// When the idle timer next fires: first to warn, then, once warned, to disconnect.
+    fn idle_deadline(&self, last_activity: Instant, warned: bool) -> Instant {
+        let timeout = self.config.idle_timeout;
+        if warned {
+            last_activity + timeout
+        } else {
+            last_activity + timeout.saturating_sub(self.config.idle_warning)
+        }
+    }
+
+    /// Keeps the PTY and session state alive after the socket drops so a `Resume` within
+    /// `grace` can pick up where the client left off. Output produced meanwhile waits in the
+    /// bounded PTY channel. Once the window elapses the session is torn down as usual.
+    fn park(self, grace: Duration) {
+        self.span.in_scope(|| tracing::debug!("Parking session for '{:?}' for {:?}.", self.user.as_ref().map(|u| &u.username), grace));
+        let sessions = self.sessions.clone();
+        let resume_token = self.resume_token.clone();
+        let span = self.span.clone();
+        sessions.leave_presence(&self.session_id);
+        sessions.park(resume_token.clone(), self);
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            if let Some(session) = sessions.take_parked(&resume_token) {
+                session.close();
+            }
+        }.instrument(span));
+    }
+
+    fn close(mut self) {
+        let _entered = self.span.clone().entered();
+        self.detach_observer();
+        self.sessions.unregister(&self.session_id);
+        self.sessions.leave_presence(&self.session_id);
+        self.sessions.close_all_files(&self.session_id);
+        if let (Some(user), Some(history_file)) = (&self.user, self.history_file()) {
+            let pool = self.db_pool.clone();
+            let (user_id, session_id) = (user.id, self.session_id.clone());
+            tokio::spawn(async move {
+                match history::persist_shell_history(&pool, user_id, &session_id, &history_file).await {
+                    Ok(count) => tracing::debug!("Saved {} history entries for session {}.", count, session_id),
+                    Err(e) => tracing::warn!("Failed to save shell history for session {}: {}", session_id, e),
+                }
+            }.in_current_span());
+        }
+        let rc_file = self.rc_file();
+        tokio::spawn(async move {
+            let _ = tokio::fs::remove_file(rc_file).await;
+        });
         tracing::debug!("User session for '{:?}' ended.", self.user.as_ref().map(|u| &u.username));
     }
 
-    async fn handle_client_message(&mut self, msg: Message, pty_tx: &mpsc::UnboundedSender<PtyMessage>, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), ()> {
-        if let Message::Text(text) = msg {
-            match serde_json::from_str::<ClientRequest>(&text) {
-                Ok(req) => {
-                    let req_id = req.request_id.clone();
-                    if self.user.is_none() {
-                        if let ClientRequestPayload::Login { username, password } = req.payload {
-                            self.handle_login(req_id, username, password, pty_tx, ws_sender).await;
-                        } else {
-                            self.send_error_response(req_id, "Authentication required".to_string(), ws_sender).await;
+    async fn handle_client_message(&mut self, msg: Message, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        match msg {
+            Message::Text(text) => {
+                self.log_frame("<-", &text);
+                let req = serde_json::from_str::<ClientRequest>(&text).map_err(|e| SessionError::Recoverable {
+                    request_id: "unknown".to_string(),
+                    message: format!("Invalid request format: {}", e),
+                })?;
+                let req_id = req.request_id.clone();
+                match self.user.as_ref().map(|u| u.id) {
+                    None => match req.payload {
+                        ClientRequestPayload::Login { username, password } => {
+                            self.handle_login(req_id, username, password, ws_sender).await?;
+                        }
+                        ClientRequestPayload::Resume { token } => self.handle_resume(req_id, token, ws_sender).await?,
+                        _ => return Err(SessionError::Recoverable { request_id: req_id, message: "Authentication required".to_string() }),
+                    },
+                    Some(user_id) => {
+                        if let Err(retry_after) = self.rate_limiter.check(user_id, req.payload.limit_kind()) {
+                            let retry_after_ms = retry_after.as_millis() as u64;
+                            return self.send_response(req_id, ServerResponsePayload::RateLimited { retry_after_ms }, ws_sender).await;
                         }
-                    } else {
-                        self.handle_authenticated_request(req, ws_sender).await;
+                        // Handled inline, and so never timed out: dropping a write or a stream
+                        // midway would leave it half done. `spawn_request` bounds the reads.
+                        self.handle_authenticated_request(req, ws_sender).await?;
                     }
                 }
-                Err(e) => self.send_error_response("unknown".to_string(), format!("Invalid request format: {}", e), ws_sender).await,
+                Ok(())
             }
-        } else if let Message::Close(_) = msg {
-            return Err(());
+            // The protocol is JSON text only for now; say so instead of dropping the frame.
+            Message::Binary(data) => Err(SessionError::Recoverable {
+                request_id: "unknown".to_string(),
+                message: format!("Binary frames are not supported ({} bytes ignored); send requests as JSON text", data.len()),
+            }),
+            Message::Close(_) => Err(SessionError::Fatal("Client closed the connection".to_string())),
+            // Pings are answered by axum itself.
+            Message::Ping(_) | Message::Pong(_) => Ok(()),
         }
-        Ok(())
     }
     
-    async fn handle_login(&mut self, req_id: String, username: String, password: String, pty_tx: &mpsc::UnboundedSender<PtyMessage>, ws_sender: &mut SplitSink<WebSocket, Message>) {
+    async fn handle_login(&mut self, req_id: String, username: String, password: String, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        if let Err(message) = db::validate_credentials(&username, &password) {
+            return self.send_error_response(req_id, message, ws_sender).await;
+        }
         match db::verify_password(&self.db_pool, &username, &password).await {
             Ok(Some(user)) => {
+                match db::ensure_home_dir(&self.db_pool, &self.config, user.id, &user.username).await {
+                    Ok(true) => tracing::warn!("Recreated missing home directory for '{}'.", user.username),
+                    Ok(false) => {}
+                    Err(e) => tracing::error!("Failed to check home directory for '{}': {}", user.username, e),
+                }
                 let home_dir = PathBuf::from(format!("/home/{}", &user.username));
-                if self.pty_handler.spawn(home_dir.clone(), pty_tx.clone()).is_ok() {
-                    self.cwd = home_dir;
-                    self.user = Some(user.clone());
-                    self.send_response(req_id, ServerResponsePayload::LoginSuccess { user }, ws_sender).await;
-                } else {
-                    self.send_error_response(req_id, "Failed to start terminal session".to_string(), ws_sender).await;
+                let history_file = self.history_file();
+                let rc_file = self.prepare_rc_file(&user).await;
+                let startup_command = self.startup_command(&user).await;
+                // Without a terminal (e.g. no /dev/pts in the container) the VFS still works, so
+                // the login goes ahead and the client can retry with `PtySpawn`.
+                if let Err(e) = self.pty_handler.spawn(home_dir.clone(), history_file.as_deref(), rc_file.as_deref(), startup_command.as_deref(), TerminalEncoding::default(), self.pty_tx.clone()) {
+                    tracing::warn!("Failed to start terminal for '{}', continuing without one: {}", user.username, e);
                 }
+                self.cwd = home_dir;
+                self.user = Some(user.clone());
+                self.span.record("user", user.username.as_str());
+                self.sessions.join_presence(&self.session_id, user.username.clone(), self.pty_handler.is_running() as u32);
+                self.sessions.register(self.session_id.clone(), SessionHandle {
+                    terminal_output: self.terminal_output.clone(),
+                    events: self.events_tx.clone(),
+                    granted_observers: HashSet::new(),
+                });
+                let session_id = self.session_id.clone();
+                let resume_token = self.resume_token.clone();
+                let terminal_available = self.pty_handler.is_running();
+                self.send_response(req_id, ServerResponsePayload::LoginSuccess { user, session_id, resume_token, terminal_available }, ws_sender).await?;
             }
-            Ok(None) => self.send_error_response(req_id, "Invalid credentials".to_string(), ws_sender).await,
-            Err(e) => self.send_error_response(req_id, format!("Login error: {}", e), ws_sender).await,
+            Ok(None) => self.send_error_response(req_id, "Invalid credentials".to_string(), ws_sender).await?,
+            Err(e) => self.send_response(req_id, vfs_error(e), ws_sender).await?,
         }
+        Ok(())
     }
 
-    async fn handle_authenticated_request(&mut self, req: ClientRequest, ws_sender: &mut SplitSink<WebSocket, Message>) {
+    async fn handle_resume(&mut self, req_id: String, token: String, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        let parked = self.sessions.take_parked(&token).ok_or_else(|| SessionError::Recoverable {
+            request_id: req_id.clone(),
+            message: "Session expired or unknown resume token".to_string(),
+        })?;
+        tracing::debug!("Resuming session {}.", parked.session_id);
+        *self = parked;
+        // Rotate the token so a leaked one can't be replayed after this reconnect.
+        self.resume_token = Uuid::new_v4().to_string();
+
+        let user = self.user.clone().unwrap();
+        let session_id = self.session_id.clone();
+        let resume_token = self.resume_token.clone();
+        let terminal_available = self.pty_handler.is_running();
+        self.sessions.join_presence(&session_id, user.username.clone(), terminal_available as u32);
+        self.send_response(req_id, ServerResponsePayload::LoginSuccess { user, session_id, resume_token, terminal_available }, ws_sender).await?;
+        let output = self.scrollback.contents();
+        if !output.is_empty() {
+            self.send_push(ServerPushPayload::TerminalOutput { output }, ws_sender).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_authenticated_request(&mut self, req: ClientRequest, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
         let user_id = self.user.as_ref().unwrap().id;
         let req_id = req.request_id;
         let user_home_dir = format!("/home/{}", self.user.as_ref().unwrap().username);
@@ -96,111 +409,841 @@ impl UserSession {
         let resolve = |p: &str| vfs::resolve_path(&self.cwd, p, &user_home_dir).to_string_lossy().to_string();
 
         match req.payload {
+            ClientRequestPayload::RunCommand { .. } | ClientRequestPayload::PtyInput { .. } | ClientRequestPayload::PtyPaste { .. } if !self.pty_handler.is_running() => {
+                let message = "No terminal is running; start one with PtySpawn".to_string();
+                self.send_response(req_id, ServerResponsePayload::Error { message, code: Some("TERMINAL_UNAVAILABLE") }, ws_sender).await?;
+            }
+            ClientRequestPayload::RunCommand { command } if command.len() > MAX_COMMAND_BYTES => {
+                let message = format!("Command is longer than {} bytes", MAX_COMMAND_BYTES);
+                self.send_response(req_id, ServerResponsePayload::Error { message, code: Some("COMMAND_TOO_LONG") }, ws_sender).await?;
+            }
             ClientRequestPayload::RunCommand { command } => {
                 if command.trim().starts_with("cd ") {
                     let target = command.trim().split_whitespace().nth(1).unwrap_or("~");
-                    self.cwd = vfs::resolve_path(&self.cwd, target, &user_home_dir);
+                    self.set_cwd(vfs::resolve_path(&self.cwd, target, &user_home_dir), ws_sender).await?;
                 }
                 self.pty_handler.send_command(command + "\n");
             }
-            ClientRequestPayload::VfsList { path } => {
-                match vfs::list_directory(&self.db_pool, user_id, &resolve(&path)).await {
-                    Ok(items) => self.send_response(req_id, ServerResponsePayload::VfsListResponse { items }, ws_sender).await,
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+            ClientRequestPayload::PtyInput { terminal_id, data } => {
+                match check_terminal_id(&terminal_id) {
+                    Ok(()) => self.pty_handler.send_command(data),
+                    Err(message) => self.send_error_response(req_id, message, ws_sender).await?,
                 }
             }
-            ClientRequestPayload::VfsReadFile { path } => {
-                match vfs::read_file_content(&self.db_pool, user_id, &resolve(&path)).await {
-                    Ok(content) => self.send_response(req_id, ServerResponsePayload::VfsReadFileResponse { content }, ws_sender).await,
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+            ClientRequestPayload::PtyPaste { terminal_id, data } => {
+                match check_terminal_id(&terminal_id) {
+                    Ok(()) => {
+                        self.pty_handler.paste(&data);
+                        self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+                    }
+                    Err(message) => self.send_error_response(req_id, message, ws_sender).await?,
                 }
             }
-            ClientRequestPayload::VfsWriteFile { path, content } => {
-                let resolved_path = resolve(&path);
-                match vfs::write_file_content(&self.db_pool, user_id, &resolved_path, &content).await {
-                    Ok(_) => { self.send_response_and_push_vfs(req_id, resolved_path, ws_sender).await; },
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+            ClientRequestPayload::PtySpawn { terminal_id, output_encoding } | ClientRequestPayload::PtyRespawn { terminal_id, output_encoding } => {
+                if let Err(message) = check_terminal_id(&terminal_id) {
+                    self.send_error_response(req_id, message, ws_sender).await?;
+                } else if self.pty_handler.is_running() {
+                    // Most likely a retry whose first attempt succeeded; the client gets the
+                    // terminal it already has.
+                    self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+                } else {
+                    let history_file = self.history_file();
+                    let (rc_file, startup_command) = match self.user.clone() {
+                        Some(user) => (self.prepare_rc_file(&user).await, self.startup_command(&user).await),
+                        None => (None, None),
+                    };
+                    match self.pty_handler.spawn(self.cwd.clone(), history_file.as_deref(), rc_file.as_deref(), startup_command.as_deref(), output_encoding, self.pty_tx.clone()) {
+                        Ok(()) => {
+                            self.sessions.set_terminals(&self.session_id, 1);
+                            self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?
+                        }
+                        Err(e) => self.send_error_response(req_id, format!("Failed to start terminal session: {}", e), ws_sender).await?,
+                    }
+                }
+            }
+            ClientRequestPayload::ExecBatch { commands, cwd, strip_ansi } => {
+                self.spawn_request(req_id, async move {
+                    let steps = exec::run_batch(commands, cwd, strip_ansi).await;
+                    ServerResponsePayload::ExecBatchResponse { steps }
+                });
+            }
+            ClientRequestPayload::VfsList { path, include_hidden, recursive: false, offset, limit } => {
+                let page = vfs::Page { offset, limit };
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                let (sessions, session_id) = (self.sessions.clone(), self.session_id.clone());
+                self.spawn_request(req_id, async move {
+                    match vfs::list_directory(&pool, &config.vfs, user_id, &path, include_hidden, page).await {
+                        Ok(mut items) => {
+                            for item in &mut items {
+                                item.open_by = sessions.open_by(item.id, &session_id);
+                            }
+                            ServerResponsePayload::VfsListResponse { items }
+                        }
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsList { path, include_hidden, recursive: true, offset, limit } => {
+                let page = vfs::Page { offset, limit };
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                self.spawn_request(req_id, async move {
+                    match vfs::list_descendants(&pool, &config.vfs, user_id, &path, include_hidden, page).await {
+                        Ok(items) => ServerResponsePayload::VfsListRecursiveResponse { items },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsListStream { path, include_hidden } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                self.spawn_stream(req_id, |request_id, events| async move {
+                    let rows = vfs::stream_directory(&pool, &config.vfs, user_id, &path, include_hidden).await.map_err(vfs_error)?;
+                    let mut chunks = rows.chunks(LIST_CHUNK_SIZE);
+                    while let Some(chunk) = chunks.next().await {
+                        let items = chunk.into_iter().collect::<Result<Vec<_>, _>>().map_err(|e| vfs_error(e.into()))?;
+                        let request_id = request_id.clone();
+                        push(&events, ServerPushPayload::VfsListChunk { request_id, items, eof: false }).await?;
+                    }
+                    push(&events, ServerPushPayload::VfsListChunk { request_id, items: Vec::new(), eof: true }).await
+                });
+            }
+            ClientRequestPayload::VfsReadFileStream { path, start_offset, expected_rev } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                self.spawn_stream(req_id, |request_id, events| async move {
+                    let mut file = vfs::open_file_from(&pool, &config.vfs, user_id, &path, start_offset, expected_rev).await.map_err(vfs_error)?;
+                    let rev = file.rev;
+                    loop {
+                        let offset = file.offset();
+                        let chunk = file.next_chunk(READ_CHUNK_SIZE).await.map_err(vfs_error)?;
+                        if chunk.is_empty() {
+                            break;
+                        }
+                        let (request_id, data) = (request_id.clone(), base64::encode(chunk));
+                        push(&events, ServerPushPayload::VfsReadChunk { request_id, offset, data, rev, eof: false, sha256: None }).await?;
+                    }
+                    let (offset, sha256) = (file.offset(), file.sha256());
+                    push(&events, ServerPushPayload::VfsReadChunk { request_id, offset, data: String::new(), rev, eof: true, sha256 }).await
+                });
+            }
+            ClientRequestPayload::VfsGetTree { path, max_depth } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                self.spawn_request(req_id, async move {
+                    match vfs::get_tree(&pool, &config.vfs, user_id, &path, max_depth).await {
+                        Ok(items) => ServerResponsePayload::VfsGetTreeResponse { items },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsGetTreeDelta { path, since_version } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                let (sessions, session_id) = (self.sessions.clone(), self.session_id.clone());
+                self.spawn_request(req_id, async move {
+                    match vfs::dir_delta(&pool, &config.vfs, user_id, &path, since_version).await {
+                        Ok(vfs::DirDelta { version, mut changed, removed }) => {
+                            for item in &mut changed {
+                                item.open_by = sessions.open_by(item.id, &session_id);
+                            }
+                            ServerResponsePayload::VfsGetTreeDeltaResponse { version, changed, removed }
+                        }
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsSuggestName { parent_path, base_name } => {
+                let (pool, config, parent_path) = (self.db_pool.clone(), self.config.clone(), resolve(&parent_path));
+                self.spawn_request(req_id, async move {
+                    match vfs::suggest_name(&pool, &config.vfs, user_id, &parent_path, &base_name).await {
+                        Ok(name) => ServerResponsePayload::VfsSuggestNameResponse { name },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::PathComplete { partial } => {
+                let (dir, prefix) = match partial.rfind('/') {
+                    Some(idx) => (&partial[..=idx], &partial[idx + 1..]),
+                    None => (".", partial.as_str()),
+                };
+                let (pool, config, dir, prefix) = (self.db_pool.clone(), self.config.clone(), resolve(dir), prefix.to_string());
+                self.spawn_request(req_id, async move {
+                    match vfs::complete_path(&pool, &config.vfs, user_id, &dir, &prefix).await {
+                        Ok(matches) => ServerResponsePayload::PathCompleteResponse { prefix, matches },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsReadFile { path, encoding, if_none_match, source_encoding, line_info, normalize_eol } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                let (sessions, session_id) = (self.sessions.clone(), self.session_id.clone());
+                self.spawn_request(req_id, async move {
+                    let options = vfs::ReadOptions { encoding, if_none_match, source_encoding: source_encoding.as_deref(), line_info, normalize_eol };
+                    read_response(&sessions, &session_id, vfs::read_file_content(&pool, &config.vfs, user_id, &path, options).await)
+                });
+            }
+            ClientRequestPayload::VfsReadFileById { id, encoding, if_none_match, source_encoding, line_info, normalize_eol } => {
+                let pool = self.db_pool.clone();
+                let (sessions, session_id) = (self.sessions.clone(), self.session_id.clone());
+                self.spawn_request(req_id, async move {
+                    let options = vfs::ReadOptions { encoding, if_none_match, source_encoding: source_encoding.as_deref(), line_info, normalize_eol };
+                    read_response(&sessions, &session_id, vfs::read_file_by_id(&pool, user_id, id, options).await)
+                });
+            }
+            ClientRequestPayload::VfsStat { path } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                let (sessions, session_id) = (self.sessions.clone(), self.session_id.clone());
+                self.spawn_request(req_id, async move {
+                    let stat = async {
+                        let mut entry = vfs::stat_node(&pool, &config.vfs, user_id, &path).await?;
+                        entry.open_by = sessions.open_by(entry.id, &session_id);
+                        entry.openable_as = Some(vfs::openable_as(&pool, user_id, &entry, config.max_response_bytes).await?);
+                        anyhow::Ok(entry)
+                    };
+                    match stat.await {
+                        Ok(entry) => ServerResponsePayload::VfsStatResponse { entry },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsGetAttrs { path } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                self.spawn_request(req_id, async move {
+                    match vfs::get_attrs(&pool, &config.vfs, user_id, &path).await {
+                        Ok(attrs) => ServerResponsePayload::VfsGetAttrsResponse { attrs },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsSetAttr { path, key, value } => {
+                match vfs::set_attr(&self.db_pool, &self.config.vfs, user_id, &resolve(&path), &key, value.as_deref()).await {
+                    Ok(()) => self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?,
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
                 }
             }
-            ClientRequestPayload::VfsCreateNode { path, node_type } => {
+            ClientRequestPayload::VfsCloseFile { id } => {
+                self.sessions.close_file(id, &self.session_id);
+                self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+            }
+            ClientRequestPayload::VfsStatMany { paths } => {
+                let resolved: Vec<String> = paths.iter().map(|p| resolve(p)).collect();
+                let (pool, config) = (self.db_pool.clone(), self.config.clone());
+                let (sessions, session_id) = (self.sessions.clone(), self.session_id.clone());
+                self.spawn_request(req_id, async move {
+                    let stat = async {
+                        let mut entries = vfs::stat_many(&pool, &config.vfs, user_id, &resolved).await?;
+                        for entry in entries.iter_mut().flatten() {
+                            entry.open_by = sessions.open_by(entry.id, &session_id);
+                            entry.openable_as = Some(vfs::openable_as(&pool, user_id, entry, config.max_response_bytes).await?);
+                        }
+                        anyhow::Ok(entries)
+                    };
+                    match stat.await {
+                        Ok(entries) => ServerResponsePayload::VfsStatManyResponse { entries },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsThumbnail { path, max_dim } => {
+                let (pool, config, path) = (self.db_pool.clone(), self.config.clone(), resolve(&path));
+                self.spawn_request(req_id, async move {
+                    match vfs::thumbnail(&pool, &config.vfs, user_id, &path, max_dim).await {
+                        Ok(vfs::Thumbnail { rev, mime, width, height, data }) => ServerResponsePayload::VfsThumbnailResponse { rev, mime, width, height, data: base64::encode(data) },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::VfsWriteFile { path, content, source_encoding, expected_rev, normalize_eol } => {
+                let write = PendingWrite { path: resolve(&path), content, source_encoding, normalize_eol };
+                self.write_or_confirm(req_id, write, expected_rev, ws_sender).await?;
+            }
+            ClientRequestPayload::AnswerServerRequest { server_request_id, answer } => {
+                match (self.pending_server_requests.remove(&server_request_id), answer) {
+                    (None, _) => self.send_error_response(req_id, "Unknown or already answered server request".to_string(), ws_sender).await?,
+                    (Some(PendingServerRequest::Overwrite { request_id, write, current_rev }), ServerRequestAnswer::Confirm { confirmed }) => {
+                        self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+                        if confirmed {
+                            // Still conditional: if the file changed again while the user was
+                            // deciding, they're asked again about the newer revision.
+                            self.write_or_confirm(request_id, write, Some(current_rev), ws_sender).await?;
+                        } else {
+                            let message = "Write cancelled because the file has changed since it was read".to_string();
+                            self.send_response(request_id, ServerResponsePayload::Error { message, code: Some("REV_MISMATCH") }, ws_sender).await?;
+                        }
+                    }
+                }
+            }
+            ClientRequestPayload::VfsWriteFileById { id, content, source_encoding, normalize_eol } => {
+                let options = vfs::WriteOptions { source_encoding: source_encoding.as_deref(), expected_rev: None, normalize_eol };
+                match vfs::write_file_by_id(&self.db_pool, &self.config.vfs, user_id, id, &content, options).await {
+                    Ok(path) => self.send_response_and_push_vfs(req_id, path, ws_sender).await?,
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
+                }
+            }
+            ClientRequestPayload::VfsCreateNode { path, node_type, content, mode, return_listing, template } => {
                 let resolved_path = resolve(&path);
-                match vfs::create_node(&self.db_pool, user_id, &resolved_path, &node_type).await {
-                    Ok(_) => { self.send_response_and_push_vfs(req_id, resolved_path, ws_sender).await; },
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+                let created = match template {
+                    Some(_) if node_type != "dir" || content.is_some() => Err(anyhow::anyhow!("Only directories can be created from a template")),
+                    Some(template) => vfs::create_from_template(&self.db_pool, &self.config.vfs, user_id, &resolved_path, &template, mode).await,
+                    None => vfs::create_node(&self.db_pool, &self.config.vfs, user_id, &resolved_path, &node_type, content.as_deref(), mode).await,
+                };
+                match created {
+                    Ok(_) if return_listing => {
+                        self.send_parent_listing(req_id, &resolved_path, ws_sender).await?;
+                        self.push_vfs_update(resolved_path, ws_sender).await?;
+                    }
+                    Ok(_) => { self.send_response_and_push_vfs(req_id, resolved_path, ws_sender).await?; },
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
                 }
             }
             ClientRequestPayload::VfsMoveNode { old_path, new_path } => {
                 let resolved_old = resolve(&old_path);
                 let resolved_new = resolve(&new_path);
-                match vfs::move_node(&self.db_pool, user_id, &resolved_old, &resolved_new).await {
-                    Ok(_) => {
-                        self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await;
-                        let _ = self.send_push(ServerPushPayload::VfsUpdate{ path: resolved_old }, ws_sender).await;
-                        let _ = self.send_push(ServerPushPayload::VfsUpdate{ path: resolved_new }, ws_sender).await;
+                match vfs::move_node(&self.db_pool, &self.config.vfs, user_id, &resolved_old, &resolved_new).await {
+                    Ok(placement) => {
+                        let undo_token = self.issue_undo_token(placement);
+                        self.send_response(req_id, ServerResponsePayload::VfsMoveNodeResponse { undo_token }, ws_sender).await?;
+                        self.notify_moved(user_id, &[(resolved_old.as_str(), resolved_new.as_str())]).await;
+                        self.push_vfs_update(resolved_old, ws_sender).await?;
+                        self.push_vfs_update(resolved_new, ws_sender).await?;
                     },
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
                 }
             }
-            ClientRequestPayload::VfsTrashNode { path } => {
-                let resolved_path = resolve(&path);
-                match vfs::trash_node(&self.db_pool, user_id, &resolved_path).await {
-                    Ok(_) => { self.send_response_and_push_vfs(req_id, resolved_path, ws_sender).await; },
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+            ClientRequestPayload::VfsMoveNodes { src_paths, dest_dir } => {
+                let src_paths: Vec<String> = src_paths.iter().map(|p| resolve(p)).collect();
+                let dest_dir = resolve(&dest_dir);
+                match vfs::move_nodes(&self.db_pool, &self.config.vfs, user_id, &src_paths, &dest_dir).await {
+                    Ok(results) => {
+                        let moves: Vec<(&str, &str)> = results.iter().filter(|r| r.error.is_none()).filter_map(|r| Some((r.src_path.as_str(), r.new_path.as_deref()?))).collect();
+                        self.notify_moved(user_id, &moves).await;
+                        // One update per directory that lost or gained children, each pushed once.
+                        let mut changed: BTreeSet<String> = moves.iter().map(|(src, _)| Path::new(src).parent().unwrap_or(Path::new("/")).to_string_lossy().to_string()).collect();
+                        if !changed.is_empty() {
+                            changed.insert(dest_dir);
+                        }
+                        self.send_response(req_id, ServerResponsePayload::VfsMoveNodesResponse { results }, ws_sender).await?;
+                        for path in changed {
+                            self.push_vfs_update(path, ws_sender).await?;
+                        }
+                    }
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
                 }
             }
-            ClientRequestPayload::VfsListTrash => {
-                match vfs::list_trash(&self.db_pool, user_id).await {
-                    Ok(items) => self.send_response(req_id, ServerResponsePayload::VfsListTrashResponse { items }, ws_sender).await,
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+            ClientRequestPayload::VfsTrashNode { path, recursive } => {
+                let resolved_path = resolve(&path);
+                match vfs::trash_node(&self.db_pool, &self.config.vfs, user_id, &resolved_path, recursive).await {
+                    Ok(placement) => {
+                        let (id, original_path) = (placement.id, resolved_path.clone());
+                        let undo_token = self.issue_undo_token(placement);
+                        self.send_response(req_id, ServerResponsePayload::VfsTrashNodeResponse { id, original_path, undo_token }, ws_sender).await?;
+                        self.push_vfs_update(resolved_path, ws_sender).await?;
+                    }
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
                 }
             }
+            ClientRequestPayload::VfsListTrash { sort, offset, limit } => {
+                let page = vfs::Page { offset, limit };
+                let pool = self.db_pool.clone();
+                self.spawn_request(req_id, async move {
+                    match vfs::list_trash(&pool, user_id, sort, page).await {
+                        Ok((items, total)) => ServerResponsePayload::VfsListTrashResponse { items, total },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
             ClientRequestPayload::VfsRestoreNode { id } => {
                 match vfs::restore_node(&self.db_pool, user_id, id).await {
-                    Ok(path) => { self.send_response_and_push_vfs(req_id, path, ws_sender).await; },
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+                    Ok(path) => { self.send_response_and_push_vfs(req_id, path, ws_sender).await?; },
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
+                }
+            }
+            ClientRequestPayload::VfsUndo { token } => {
+                let Some((_, placement)) = self.undo_tokens.remove(&token).filter(|(expires, _)| *expires > Instant::now()) else {
+                    let message = "Undo token has expired or is unknown".to_string();
+                    self.send_response(req_id, ServerResponsePayload::Error { message, code: Some("UNDO_EXPIRED") }, ws_sender).await?;
+                    return Ok(());
+                };
+                match vfs::undo_placement(&self.db_pool, user_id, &placement).await {
+                    Ok((old_path, new_path)) => {
+                        self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+                        if let Some(old_path) = old_path {
+                            self.notify_moved(user_id, &[(old_path.as_str(), new_path.as_str())]).await;
+                            self.push_vfs_update(old_path, ws_sender).await?;
+                        }
+                        self.push_vfs_update(new_path, ws_sender).await?;
+                    }
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
                 }
             }
-            ClientRequestPayload::VfsDeleteNode { id } => {
-                match vfs::permanently_delete_node(&self.db_pool, user_id, id).await {
-                    Ok(_) => self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await,
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+            ClientRequestPayload::VfsRestoreAll => {
+                match vfs::restore_all(&self.db_pool, user_id).await {
+                    Ok(paths) => {
+                        self.send_response(req_id, ServerResponsePayload::VfsRestoreAllResponse { paths: paths.clone() }, ws_sender).await?;
+                        for path in paths {
+                            self.push_vfs_update(path, ws_sender).await?;
+                        }
+                    }
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
                 }
             }
+            ClientRequestPayload::VfsDeleteNode { id, recursive } => {
+                match vfs::permanently_delete_node(&self.db_pool, user_id, id, recursive).await {
+                    Ok(_) => self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?,
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
+                }
+            }
+            ClientRequestPayload::VfsGetQuota => {
+                let pool = self.db_pool.clone();
+                self.spawn_request(req_id, async move {
+                    match vfs::current_usage(&pool, user_id).await {
+                        Ok(vfs::Usage { quota_bytes, used_bytes, trash_bytes }) => ServerResponsePayload::VfsGetQuotaResponse { quota_bytes, used_bytes, trash_bytes },
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
             ClientRequestPayload::VfsEmptyTrash => {
                 match vfs::empty_trash(&self.db_pool, user_id).await {
-                    Ok(_) => self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await,
-                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await,
+                    Ok(_) => self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?,
+                    Err(e) => self.send_vfs_error(req_id, e, ws_sender).await?,
+                }
+            }
+            ClientRequestPayload::VfsWatch { path } => {
+                self.watched_paths.insert(resolve(&path));
+                self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+            }
+            ClientRequestPayload::VfsUnwatch { path } => {
+                self.watched_paths.remove(&resolve(&path));
+                self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+            }
+            ClientRequestPayload::GrantObserver { username } => {
+                self.sessions.grant_observer(&self.session_id, username);
+                self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+            }
+            ClientRequestPayload::AttachObserver { session_id } => {
+                if session_id == self.session_id {
+                    return self.send_error_response(req_id, "Cannot observe your own session".to_string(), ws_sender).await;
+                }
+                match self.sessions.attach_observer(&session_id, self.user.as_ref().unwrap()) {
+                    Ok(output_rx) => {
+                        self.detach_observer();
+                        self.observing = Some((session_id, output_rx));
+                        self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+                    }
+                    Err(e) => self.send_error_response(req_id, e.to_string(), ws_sender).await?,
+                }
+            }
+            ClientRequestPayload::DetachObserver => {
+                self.detach_observer();
+                self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+            }
+            ClientRequestPayload::ListPresence => {
+                let users = self.sessions.list_presence(PRESENCE_TIMEOUT);
+                self.send_response(req_id, ServerResponsePayload::ListPresenceResponse { users }, ws_sender).await?;
+            }
+            ClientRequestPayload::WhoAmI => {
+                let user = self.user.clone().unwrap();
+                let (session_id, cwd) = (self.session_id.clone(), self.cwd.to_string_lossy().to_string());
+                self.send_response(req_id, ServerResponsePayload::WhoAmIResponse { user, session_id, cwd }, ws_sender).await?;
+            }
+            ClientRequestPayload::Broadcast { message } => {
+                let user = self.user.as_ref().unwrap();
+                if user.role != "Admin" {
+                    let message = "Only admins can broadcast announcements".to_string();
+                    return self.send_response(req_id, ServerResponsePayload::Error { message, code: Some("PERMISSION_DENIED") }, ws_sender).await;
+                }
+                tracing::info!("Announcement from '{}': {}", user.username, message);
+                // This session is subscribed too, so there's always a receiver.
+                let _ = self.announcements.send(message);
+                self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+            }
+            ClientRequestPayload::VerifyStorage => {
+                if self.user.as_ref().unwrap().role != "Admin" {
+                    let message = "Only admins can verify storage".to_string();
+                    return self.send_response(req_id, ServerResponsePayload::Error { message, code: Some("PERMISSION_DENIED") }, ws_sender).await;
                 }
+                let (pool, config) = (self.db_pool.clone(), self.config.clone());
+                self.spawn_request(req_id, async move {
+                    match vfs::verify_storage(&pool, &config.vfs).await {
+                        Ok(vfs::StorageReport { checked, rebased, missing }) => {
+                            tracing::info!("Verified storage: {} files checked, {} rebased, {} missing.", checked, rebased, missing);
+                            ServerResponsePayload::VerifyStorageResponse { checked, rebased, missing }
+                        }
+                        Err(e) => vfs_error(e),
+                    }
+                });
+            }
+            ClientRequestPayload::ExportUser { user_id } => {
+                if self.user.as_ref().unwrap().role != "Admin" {
+                    let message = "Only admins can export users".to_string();
+                    return self.send_response(req_id, ServerResponsePayload::Error { message, code: Some("PERMISSION_DENIED") }, ws_sender).await;
+                }
+                tracing::info!("Exporting user {}.", user_id);
+                let pool = self.db_pool.clone();
+                self.spawn_stream(req_id, |request_id, events| async move {
+                    let (chunks_tx, mut chunks_rx) = mpsc::channel(1);
+                    let forward = async {
+                        while let Some(chunk) = chunks_rx.recv().await {
+                            let (request_id, data) = (request_id.clone(), base64::encode(chunk));
+                            push(&events, ServerPushPayload::ExportChunk { request_id, data, eof: false }).await?;
+                        }
+                        Ok(())
+                    };
+                    // If the client goes, dropping `chunks_rx` stops the export at its next chunk.
+                    let (exported, forwarded) = tokio::join!(vfs::export_user(&pool, user_id, READ_CHUNK_SIZE, chunks_tx), forward);
+                    forwarded?;
+                    exported.map_err(vfs_error)?;
+                    push(&events, ServerPushPayload::ExportChunk { request_id, data: String::new(), eof: true }).await
+                });
+            }
+            ClientRequestPayload::ImportUser { user_id, bundle, dest_path } => {
+                if self.user.as_ref().unwrap().role != "Admin" {
+                    let message = "Only admins can import users".to_string();
+                    return self.send_response(req_id, ServerResponsePayload::Error { message, code: Some("PERMISSION_DENIED") }, ws_sender).await;
+                }
+                let (pool, config, responses) = (self.db_pool.clone(), self.config.clone(), self.responses_tx.clone());
+                // A write, so it isn't under the request timeout: abandoned part way, it would
+                // leave the blobs it had already stored with no rows pointing at them.
+                tokio::spawn(async move {
+                    let imported = match base64::decode(&bundle) {
+                        Ok(bundle) => vfs::import_user(&pool, &config.vfs, user_id, dest_path.as_deref().unwrap_or("/"), &bundle).await,
+                        Err(e) => Err(e.into()),
+                    };
+                    let payload = match imported {
+                        Ok(imported) => {
+                            tracing::info!("Imported {} nodes for user {}.", imported, user_id);
+                            ServerResponsePayload::ImportUserResponse { imported }
+                        }
+                        Err(e) => vfs_error(e),
+                    };
+                    let _ = responses.send((req_id, payload));
+                }.instrument(self.span.clone()));
             }
-            _ => self.send_error_response(req_id, "Unsupported action".to_string(), ws_sender).await,
+            _ => self.send_error_response(req_id, "Unsupported action".to_string(), ws_sender).await?,
         }
+        Ok(())
     }
-    
-    async fn send_response_and_push_vfs(&self, req_id: String, path: String, ws_sender: &mut SplitSink<WebSocket, Message>) {
-        self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await;
-        let _ = self.send_push(ServerPushPayload::VfsUpdate{ path }, ws_sender).await;
+
+    /// Adds output to the scrollback, trimming it to fit the session-wide cap alongside the
+    /// output still queued behind it.
+    fn keep_scrollback(&mut self, output: &str) {
+        self.scrollback.push(output);
+        if let Some(cap) = self.config.pty.session_output_bytes {
+            self.scrollback.trim_to(cap.saturating_sub(self.pty_handler.in_flight_bytes()));
+        }
+    }
+
+    fn history_file(&self) -> Option<PathBuf> {
+        self.config.pty.history_dir.as_ref().map(|dir| dir.join(&self.session_id))
+    }
+
+    fn rc_file(&self) -> PathBuf {
+        self.config.pty.rc_dir.join(&self.session_id)
+    }
+
+    /// Regenerated on every spawn so edits to the VFS rc files apply to the next terminal.
+    async fn prepare_rc_file(&self, user: &UserInfo) -> Option<PathBuf> {
+        let rc_file = self.rc_file();
+        let home = format!("/home/{}", user.username);
+        match shell_rc::write_rc_file(&self.db_pool, &self.config.vfs, user.id, &home, &rc_file).await {
+            Ok(()) => Some(rc_file),
+            Err(e) => {
+                tracing::warn!("Failed to write shell rc file {:?}, starting a plain login shell: {}", rc_file, e);
+                None
+            }
+        }
+    }
+
+    async fn startup_command(&self, user: &UserInfo) -> Option<String> {
+        match db::startup_command(&self.db_pool, &self.config, user.id).await {
+            Ok(command) => command,
+            Err(e) => {
+                tracing::warn!("Failed to look up the startup command for '{}': {}", user.username, e);
+                None
+            }
+        }
+    }
+
+    fn detach_observer(&mut self) {
+        if let (Some((session_id, _)), Some(user)) = (self.observing.take(), self.user.as_ref()) {
+            self.sessions.notify(&session_id, ServerPushPayload::ObserverLeft { username: user.username.clone() });
+        }
     }
     
-    async fn send_response(&self, request_id: String, payload: ServerResponsePayload, sender: &mut SplitSink<WebSocket, Message>) {
-        let response = ServerMessage::Response(ServerResponse { request_id, payload });
-        if let Ok(json) = serde_json::to_string(&response) {
-            if sender.send(Message::Text(json)).await.is_err() {
-                tracing::warn!("Failed to send response to client.");
+    /// Writes `path`, or, if it has moved past `expected_rev`, asks the client whether to
+    /// overwrite anyway and leaves the request pending until the answer arrives.
+    async fn write_or_confirm(&mut self, req_id: RequestId, write: PendingWrite, expected_rev: Option<i64>, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        let user_id = self.user.as_ref().unwrap().id;
+        let options = vfs::WriteOptions { source_encoding: write.source_encoding.as_deref(), expected_rev, normalize_eol: write.normalize_eol };
+        let error = match vfs::write_file_content(&self.db_pool, &self.config.vfs, user_id, &write.path, &write.content, options).await {
+            Ok(()) => return self.send_response_and_push_vfs(req_id, write.path, ws_sender).await,
+            Err(e) => e,
+        };
+        let (expected_rev, current_rev) = match (error.downcast_ref::<vfs::VfsError>(), expected_rev) {
+            (Some(&vfs::VfsError::RevMismatch { current }), Some(expected)) if self.pending_server_requests.len() < MAX_PENDING_SERVER_REQUESTS => (expected, current),
+            _ => return self.send_vfs_error(req_id, error, ws_sender).await,
+        };
+        let server_request_id = Uuid::new_v4().to_string();
+        let payload = ServerRequestPayload::ConfirmOverwrite { request_id: req_id.clone(), path: write.path.clone(), expected_rev, current_rev };
+        self.pending_server_requests.insert(server_request_id.clone(), PendingServerRequest::Overwrite { request_id: req_id, write, current_rev });
+        self.send_server_request(server_request_id, payload, ws_sender).await
+    }
+
+    async fn send_response_and_push_vfs(&self, req_id: String, path: String, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        self.send_response(req_id, ServerResponsePayload::Success, ws_sender).await?;
+        self.push_vfs_update(path, ws_sender).await
+    }
+
+    /// Answers a mutation of `path` with the full listing of its parent, so the client can
+    /// refresh without another round-trip. The mutation has already happened, so a failed
+    /// listing is reported as an error without undoing it.
+    async fn send_parent_listing(&self, req_id: String, path: &str, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        let user_id = self.user.as_ref().unwrap().id;
+        let parent = Path::new(path).parent().unwrap_or(Path::new("/")).to_string_lossy().to_string();
+        match vfs::list_directory(&self.db_pool, &self.config.vfs, user_id, &parent, true, vfs::Page::default()).await {
+            Ok(mut items) => {
+                for item in &mut items {
+                    item.open_by = self.sessions.open_by(item.id, &self.session_id);
+                }
+                self.send_response(req_id, ServerResponsePayload::VfsParentListingResponse { parent, items }, ws_sender).await
+            }
+            Err(e) => self.send_vfs_error(req_id, e, ws_sender).await,
+        }
+    }
+
+    /// Remembers how to reverse a trash or move for `VfsUndo`, dropping expired tokens and,
+    /// past `MAX_UNDO_TOKENS`, the oldest.
+    fn issue_undo_token(&mut self, placement: vfs::NodePlacement) -> String {
+        let now = Instant::now();
+        self.undo_tokens.retain(|_, (expires, _)| *expires > now);
+        if self.undo_tokens.len() >= MAX_UNDO_TOKENS {
+            if let Some(oldest) = self.undo_tokens.iter().min_by_key(|(_, (expires, _))| *expires).map(|(token, _)| token.clone()) {
+                self.undo_tokens.remove(&oldest);
             }
         }
+        let token = Uuid::new_v4().to_string();
+        self.undo_tokens.insert(token.clone(), (now + self.config.undo_window, placement));
+        token
+    }
+
+    /// Records the cwd the shell or a `cd` moved to and tells the client, if it differs.
+    async fn set_cwd(&mut self, cwd: PathBuf, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        if cwd == self.cwd {
+            return Ok(());
+        }
+        self.cwd = cwd;
+        let (terminal_id, path) = (DEFAULT_TERMINAL_ID.to_string(), self.cwd.to_string_lossy().to_string());
+        self.send_push(ServerPushPayload::CwdChanged { terminal_id, path }, ws_sender).await
     }
 
-    async fn send_error_response(&self, request_id: String, message: String, sender: &mut SplitSink<WebSocket, Message>) {
+    /// Tells each session holding an open file under one of the moved `(old, new)` paths where
+    /// it went. Open files are tracked by id, so only their paths need correcting.
+    async fn notify_moved(&self, user_id: i64, moves: &[(&str, &str)]) {
+        let open_ids = self.sessions.open_file_ids();
+        if open_ids.is_empty() || moves.is_empty() {
+            return;
+        }
+        let paths = match vfs::live_paths(&self.db_pool, user_id, &open_ids).await {
+            Ok(paths) => paths,
+            Err(e) => {
+                tracing::warn!("Couldn't look up open files after a move: {}", e);
+                return;
+            }
+        };
+        for (id, new_path) in paths {
+            // Nothing lived at a move's destination beforehand, so anything under it was moved.
+            let Some((old, rest)) = moves.iter().find_map(|(old, new)| Some((old, Path::new(&new_path).strip_prefix(new).ok()?))) else {
+                continue;
+            };
+            let old_path = if rest.as_os_str().is_empty() { old.to_string() } else { Path::new(old).join(rest).to_string_lossy().to_string() };
+            self.sessions.notify_open_file(id, || ServerPushPayload::VfsFileMoved { id, old_path: old_path.clone(), new_path: new_path.clone() });
+        }
+    }
+
+    fn is_watched(&self, path: &str) -> bool {
+        self.watched_paths.is_empty() || self.watched_paths.iter().any(|watched| Path::new(path).starts_with(watched))
+    }
+
+    async fn push_vfs_update(&self, path: String, ws_sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        if self.is_watched(&path) {
+            self.send_push(ServerPushPayload::VfsUpdate { path }, ws_sender).await?;
+        }
+        Ok(())
+    }
+    
+    async fn send_response(&self, request_id: String, payload: ServerResponsePayload, sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        match self.serialize_response(request_id, payload) {
+            Some(json) => self.send_frame(json, sender).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Responses larger than `max_response_bytes` are replaced with `ResultTooLarge` so a huge
+    /// listing can't stall a slow client or pin server memory. Pushes are already small
+    /// (terminal chunks, paths) and aren't checked.
+    fn serialize_response(&self, request_id: String, payload: ServerResponsePayload) -> Option<String> {
+        // Errors carry the session id so a user's report can be matched to the server's logs.
+        let session_id = matches!(payload, ServerResponsePayload::Error { .. }).then(|| self.session_id.clone());
+        let response = ServerMessage::Response(ServerResponse { request_id: request_id.clone(), session_id, payload });
+        let json = serde_json::to_string(&response).ok()?;
+        if json.len() <= self.config.max_response_bytes {
+            return Some(json);
+        }
+        tracing::warn!("Response of {} bytes exceeds the {} byte limit.", json.len(), self.config.max_response_bytes);
+        let payload = ServerResponsePayload::ResultTooLarge { size: json.len(), limit: self.config.max_response_bytes };
+        serde_json::to_string(&ServerMessage::Response(ServerResponse { request_id, session_id: None, payload })).ok()
+    }
+
+    async fn send_error_response(&self, request_id: String, message: String, sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
         tracing::error!("Sending error to client: {}", message);
-        self.send_response(request_id, ServerResponsePayload::Error { message }, sender).await;
+        self.send_response(request_id, ServerResponsePayload::Error { message, code: None }, sender).await
+    }
+
+    async fn send_vfs_error(&self, request_id: String, error: anyhow::Error, sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        self.send_response(request_id, vfs_error(error), sender).await
+    }
+
+    /// Runs a read-only request as its own task so a slow query or batch doesn't hold up the
+    /// requests behind it, such as terminal input. Its response goes out from the run loop
+    /// whenever it's ready, so it may overtake or trail responses to later requests; clients
+    /// match them up by `request_id`. Everything else is still handled in arrival order, so a
+    /// write is never reordered with another write.
+    fn spawn_request(&self, request_id: RequestId, response: impl Future<Output = ServerResponsePayload> + Send + 'static) {
+        let responses = self.responses_tx.clone();
+        let timeout = self.config.request_timeout;
+        tokio::spawn(async move {
+            let payload = time_limited(&request_id, timeout, response).await.unwrap_or_else(|| timed_out(timeout));
+            let _ = responses.send((request_id, payload));
+        }.instrument(self.span.clone()));
+    }
+
+    /// Like `spawn_request`, for a read answered by a series of pushes rather than a response.
+    /// `pushes` gets the request's id and a channel that forwards to the event channel, so the
+    /// pushes queue behind the ones already waiting and never hold up the run loop. The time
+    /// limit applies to producing each push rather than the whole series, so a long download
+    /// isn't cut off while the client keeps up. Only a failure, including running out of time,
+    /// comes back as a response.
+    fn spawn_stream<F>(&self, request_id: RequestId, pushes: impl FnOnce(RequestId, mpsc::Sender<ServerPushPayload>) -> F)
+    where
+        F: Future<Output = Result<(), ServerResponsePayload>> + Send + 'static,
+    {
+        let (pushes_tx, mut pushes_rx) = mpsc::channel(1);
+        let producer = tokio::spawn(pushes(request_id.clone(), pushes_tx).instrument(self.span.clone()));
+        let (events, responses) = (self.events_tx.clone(), self.responses_tx.clone());
+        let timeout = self.config.request_timeout;
+        tokio::spawn(async move {
+            loop {
+                match time_limited(&request_id, timeout, pushes_rx.recv()).await {
+                    Some(Some(push)) => {
+                        if events.send(push).await.is_err() {
+                            producer.abort();
+                            return;
+                        }
+                    }
+                    Some(None) => break,
+                    None => {
+                        producer.abort();
+                        let _ = responses.send((request_id, timed_out(timeout)));
+                        return;
+                    }
+                }
+            }
+            if let Ok(Err(payload)) = producer.await {
+                let _ = responses.send((request_id, payload));
+            }
+        }.instrument(self.span.clone()));
     }
     
-    async fn send_push(&self, payload: ServerPushPayload, sender: &mut SplitSink<WebSocket, Message>) {
+    async fn send_server_request(&self, server_request_id: RequestId, payload: ServerRequestPayload, sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        let request = ServerMessage::Request(ServerRequest { server_request_id, payload });
+        match serde_json::to_string(&request) {
+            Ok(json) => self.send_frame(json, sender).await,
+            Err(_) => Ok(()),
+        }
+    }
+
+    async fn send_push(&self, payload: ServerPushPayload, sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
         let push = ServerMessage::Push(ServerPush { payload });
-        if let Ok(json) = serde_json::to_string(&push) {
-            if sender.send(Message::Text(json)).await.is_err() {
-                tracing::warn!("Failed to send push notification to client.");
-            }
+        match serde_json::to_string(&push) {
+            Ok(json) => self.send_frame(json, sender).await,
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// A failed send is always fatal: axum's sink only errors once the connection is closed
+    /// or broken, so there's no transient case worth retrying, and carrying on would just do
+    /// work nobody can receive.
+    async fn send_frame(&self, json: String, sender: &mut SplitSink<WebSocket, Message>) -> Result<(), SessionError> {
+        self.log_frame("->", &json);
+        sender.send(Message::Text(json)).await.map_err(|e| SessionError::Fatal(format!("Failed to send to client: {}", e)))
+    }
+
+    fn log_frame(&self, direction: &str, raw: &str) {
+        if self.config.log_protocol {
+            tracing::trace!("{} {}", direction, protocol::redact_for_log(raw));
         }
     }
 }
+
+/// The response to a file read. A read that succeeds, even as `NotModified`, counts as opening
+/// the file in `session_id`, which then hears when it moves.
+fn read_response(sessions: &SessionRegistry, session_id: &str, outcome: anyhow::Result<vfs::ReadOutcome>) -> ServerResponsePayload {
+    match outcome {
+        Ok(vfs::ReadOutcome::Content(vfs::FileContent { id, content, encoding, is_binary, rev, sha256, line_info })) => {
+            sessions.open_file(id, session_id);
+            let (line_count, line_ending) = match line_info {
+                Some(vfs::LineInfo { line_count, line_ending }) => (Some(line_count), line_ending),
+                None => (None, None),
+            };
+            ServerResponsePayload::VfsReadFileResponse { id, content, encoding, is_binary, rev, sha256, line_count, line_ending }
+        }
+        Ok(vfs::ReadOutcome::NotModified { id, rev }) => {
+            sessions.open_file(id, session_id);
+            ServerResponsePayload::NotModified { rev }
+        }
+        Err(e) => vfs_error(e),
+    }
+}
+
+fn vfs_error(error: anyhow::Error) -> ServerResponsePayload {
+    let (message, code) = vfs::client_error(&error);
+    tracing::error!("Sending error to client: {:#}", error);
+    ServerResponsePayload::Error { message, code }
+}
+
+/// Runs `work` for at most `timeout`, where zero means no limit. `None` if it ran out of time.
+async fn time_limited<T>(request_id: &str, timeout: Duration, work: impl Future<Output = T>) -> Option<T> {
+    if timeout.is_zero() {
+        return Some(work.await);
+    }
+    let finished = tokio::time::timeout(timeout, work).await.ok();
+    if finished.is_none() {
+        tracing::warn!("Request {} timed out after {:?}.", request_id, timeout);
+    }
+    finished
+}
+
+/// Queues a push from a `spawn_stream` task, waiting for room. Fails once nobody is forwarding
+/// them any more, which stops the task early.
+async fn push(events: &mpsc::Sender<ServerPushPayload>, payload: ServerPushPayload) -> Result<(), ServerResponsePayload> {
+    events.send(payload).await.map_err(|_| ServerResponsePayload::Error { message: "Session closed".to_string(), code: None })
+}
+
+fn timed_out(timeout: Duration) -> ServerResponsePayload {
+    let message = format!("Request timed out after {} seconds", timeout.as_secs());
+    ServerResponsePayload::Error { message, code: Some("TIMEOUT") }
+}
+
+fn check_terminal_id(terminal_id: &str) -> Result<(), String> {
+    if terminal_id == DEFAULT_TERMINAL_ID {
+        Ok(())
+    } else {
+        Err(format!("Unknown terminal '{}'", terminal_id))
+    }
+}
+
+async fn recv_observed(observing: &mut Option<(String, broadcast::Receiver<String>)>) -> Result<String, broadcast::error::RecvError> {
+    match observing {
+        Some((_, output_rx)) => output_rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests;
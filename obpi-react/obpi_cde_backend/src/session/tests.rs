@@ -0,0 +1,246 @@
+use crate::config::RateLimitConfig;
+use crate::test_support::{read_file, TestEnv, PASSWORD, USERNAME};
+use crate::vfs;
+use serde_json::json;
+
+#[tokio::test]
+async fn login_required() {
+    let env = TestEnv::new().await;
+    let server = env.serve().await;
+    let mut client = server.connect().await;
+    let response = client.request("vfsList", json!({ "path": "/" })).await;
+    assert_eq!(response["payload"]["message"], "Authentication required");
+    let response = client.request("login", json!({ "username": USERNAME, "password": "wrong" })).await;
+    assert_eq!(response["payload"]["message"], "Invalid credentials");
+    let response = client.request("login", json!({ "username": USERNAME, "password": PASSWORD })).await;
+    assert_eq!(response["payload"]["user"]["username"], USERNAME);
+}
+
+#[tokio::test]
+async fn file_round_trip() {
+    let env = TestEnv::new().await;
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    let response = client.request("vfsCreateNode", json!({ "path": "/home/tester/notes.txt", "node_type": "file", "content": base64::encode("hi") })).await;
+    assert_eq!(response["type"], "success", "{}", response);
+    assert_eq!(client.push("vfsUpdate").await["payload"]["path"], "/home/tester/notes.txt");
+
+    let response = client.request("vfsWriteFile", json!({ "path": "/home/tester/notes.txt", "content": base64::encode("hello") })).await;
+    assert_eq!(response["type"], "success", "{}", response);
+    let response = client.request("vfsReadFile", json!({ "path": "/home/tester/notes.txt", "encoding": "utf8" })).await;
+    assert_eq!(response["payload"]["content"], "hello");
+
+    let response = client.request("vfsList", json!({ "path": "/home/tester" })).await;
+    let items = response["payload"]["items"].as_array().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!((&items[0]["name"], &items[0]["size"]), (&json!("notes.txt"), &json!(5)));
+}
+
+#[tokio::test]
+async fn move_trash_and_restore() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    let response = client.request("vfsMoveNode", json!({ "old_path": "/home/tester/readme.md", "new_path": "/home/tester/docs/readme.md" })).await;
+    assert!(response["payload"]["undo_token"].is_string(), "{}", response);
+    assert_eq!(env.read("/home/tester/docs/readme.md").await, b"readme");
+
+    let response = client.request("vfsTrashNode", json!({ "path": "/home/tester/docs", "recursive": true })).await;
+    let id = response["payload"]["id"].as_i64().unwrap();
+    let response = client.request("vfsListTrash", json!({})).await;
+    assert_eq!(response["payload"]["total"], 1);
+
+    let response = client.request("vfsRestoreNode", json!({ "id": id })).await;
+    assert_eq!(response["type"], "success", "{}", response);
+    assert_eq!(env.read("/home/tester/docs/old/draft.txt").await, b"draft");
+
+    client.request("vfsTrashNode", json!({ "path": "/home/tester/src", "recursive": true })).await;
+    let response = client.request("vfsEmptyTrash", json!(null)).await;
+    assert_eq!(response["type"], "success", "{}", response);
+    assert_eq!(env.count("files WHERE is_trashed").await, 0);
+}
+
+#[tokio::test]
+async fn writes_outlast_request_timeout() {
+    let mut env = TestEnv::new().await;
+    env.config.request_timeout = std::time::Duration::from_nanos(1);
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    let response = client.request("vfsCreateNode", json!({ "path": "/home/tester/a.txt", "node_type": "file", "content": base64::encode("one") })).await;
+    assert_eq!(response["type"], "success", "{}", response);
+    let response = client.request("vfsWriteFile", json!({ "path": "/home/tester/a.txt", "content": base64::encode("two") })).await;
+    assert_eq!(response["type"], "success", "{}", response);
+    assert_eq!(env.read("/home/tester/a.txt").await, b"two");
+
+    let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::channel(16);
+    vfs::export_user(&env.pool, env.user_id, 1024, chunks_tx).await.unwrap();
+    let mut bundle = Vec::new();
+    while let Some(chunk) = chunks_rx.recv().await {
+        bundle.extend(chunk);
+    }
+    let other = env.add_user("other").await;
+    let response = client.request("importUser", json!({ "user_id": other, "bundle": base64::encode(bundle) })).await;
+    assert_eq!(response["type"], "importUserResponse", "{}", response);
+    assert_eq!(read_file(&env.pool, env.vfs(), other, "/home/tester/a.txt").await.unwrap(), b"two");
+}
+
+#[tokio::test]
+async fn export_arrives_in_chunks() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    let request_id = client.send("exportUser", json!({ "user_id": env.user_id })).await;
+    let mut bundle = Vec::new();
+    loop {
+        let chunk = client.push("exportChunk").await;
+        assert_eq!(chunk["payload"]["request_id"], request_id.as_str());
+        bundle.extend(base64::decode(chunk["payload"]["data"].as_str().unwrap()).unwrap());
+        if chunk["payload"]["eof"] == true {
+            break;
+        }
+    }
+    let other = env.add_user("other").await;
+    vfs::import_user(&env.pool, env.vfs(), other, "/", &bundle).await.unwrap();
+    assert_eq!(read_file(&env.pool, env.vfs(), other, "/home/tester/src/main.rs").await.unwrap(), b"fn main() {}");
+}
+
+#[tokio::test]
+async fn reads_time_out() {
+    let mut env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    env.config.request_timeout = std::time::Duration::from_millis(200);
+    let server = env.serve().await;
+    let mut client = server.login().await;
+    // With every connection held, a read can only finish by timing out.
+    let mut held = Vec::new();
+    for _ in 0..env.pool.options().get_max_connections() {
+        held.push(env.pool.acquire().await.unwrap());
+    }
+
+    for (kind, payload) in [
+        ("vfsList", json!({ "path": "/home/tester" })),
+        ("vfsReadFile", json!({ "path": "/home/tester/readme.md" })),
+        ("vfsStat", json!({ "path": "/home/tester/readme.md" })),
+        ("vfsStatMany", json!({ "paths": ["/home/tester/readme.md"] })),
+        ("vfsGetTreeDelta", json!({ "path": "/home/tester", "since_version": 0 })),
+        ("vfsListStream", json!({ "path": "/home/tester" })),
+        ("vfsReadFileStream", json!({ "path": "/home/tester/readme.md" })),
+    ] {
+        let response = client.request(kind, payload).await;
+        assert_eq!(response["payload"]["code"], "TIMEOUT", "{}: {}", kind, response);
+    }
+}
+
+#[tokio::test]
+async fn list_stream_ends_with_eof() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    let request_id = client.send("vfsListStream", json!({ "path": "/home/tester" })).await;
+    let mut names = Vec::new();
+    loop {
+        let chunk = client.push("vfsListChunk").await;
+        assert_eq!(chunk["payload"]["request_id"], request_id.as_str());
+        names.extend(chunk["payload"]["items"].as_array().unwrap().iter().map(|item| item["name"].as_str().unwrap().to_string()));
+        if chunk["payload"]["eof"] == true {
+            break;
+        }
+    }
+    assert_eq!(names, ["docs", "src", "readme.md"]);
+}
+
+#[tokio::test]
+async fn read_stream_resumes_from_offset() {
+    let env = TestEnv::new().await;
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    env.write("/home/tester/big.bin", &content).await;
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    client.send("vfsReadFileStream", json!({ "path": "/home/tester/big.bin", "start_offset": 10, "expected_rev": 0 })).await;
+    let (mut received, mut expected_offset) = (Vec::new(), 10);
+    let sha256 = loop {
+        let chunk = client.push("vfsReadChunk").await;
+        assert_eq!(chunk["payload"]["offset"], expected_offset);
+        let data = base64::decode(chunk["payload"]["data"].as_str().unwrap()).unwrap();
+        expected_offset += data.len() as u64;
+        received.extend(data);
+        if chunk["payload"]["eof"] == true {
+            break chunk["payload"]["sha256"].clone();
+        }
+    };
+    assert_eq!(received, content[10..]);
+    assert_eq!(sha256, hex::encode(<sha2::Sha256 as sha2::Digest>::digest(&content)));
+}
+
+#[tokio::test]
+async fn batch_move_updates_each_watched_parent() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let server = env.serve().await;
+    let mut client = server.login().await;
+    for path in ["/home/tester/docs", "/home/tester/src"] {
+        client.request("vfsWatch", json!({ "path": path })).await;
+    }
+
+    let response = client.request("vfsMoveNodes", json!({ "src_paths": ["/home/tester/docs/notes.txt", "/home/tester/readme.md"], "dest_dir": "/home/tester/src" })).await;
+    assert_eq!(response["type"], "vfsMoveNodesResponse", "{}", response);
+    let mut updated = [client.push("vfsUpdate").await, client.push("vfsUpdate").await];
+    updated.sort_by_key(|push| push["payload"]["path"].to_string());
+    assert_eq!(updated[0]["payload"]["path"], "/home/tester/docs");
+    assert_eq!(updated[1]["payload"]["path"], "/home/tester/src");
+}
+
+#[tokio::test]
+async fn recursive_list_is_rate_limited_separately() {
+    let mut env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    env.config.rate_limit = RateLimitConfig { heavy_requests: ["vfsListDescendants".to_string()].into(), burst: 1, per_minute: 1 };
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    for _ in 0..3 {
+        let response = client.request("vfsList", json!({ "path": "/home/tester" })).await;
+        assert_eq!(response["type"], "vfsListResponse", "{}", response);
+    }
+    let response = client.request("vfsList", json!({ "path": "/home/tester", "recursive": true })).await;
+    assert_eq!(response["type"], "vfsListRecursiveResponse", "{}", response);
+    let response = client.request("vfsList", json!({ "path": "/home/tester", "recursive": true })).await;
+    assert_eq!(response["type"], "rateLimited", "{}", response);
+}
+
+#[tokio::test]
+async fn login_recreates_missing_home() {
+    let env = TestEnv::new().await;
+    let old_home = env.node_id("/home/tester").await;
+    crate::vfs::trash_node(&env.pool, env.vfs(), env.user_id, "/home", true).await.unwrap();
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    let response = client.request("vfsCreateNode", json!({ "path": "/home/tester/a.txt", "node_type": "file" })).await;
+    assert_eq!(response["type"], "success", "{}", response);
+    assert_ne!(env.node_id("/home/tester").await, old_home);
+    assert!(!crate::db::ensure_home_dir(&env.pool, &env.config, env.user_id, USERNAME).await.unwrap());
+}
+
+#[tokio::test]
+async fn oversized_command_rejected() {
+    let env = TestEnv::new().await;
+    let server = env.serve().await;
+    let mut client = server.login().await;
+
+    let response = client.request("runCommand", json!({ "command": format!(": {}", "x".repeat(64 * 1024)) })).await;
+    assert_eq!(response["payload"]["code"], "COMMAND_TOO_LONG", "{}", response);
+    // A long line that fits still goes through, and the shell keeps up afterwards.
+    client.send("runCommand", json!({ "command": format!(": {}", "x".repeat(60 * 1024)) })).await;
+    client.send("runCommand", json!({ "command": "echo al''ive" })).await;
+    client.output_until("alive").await;
+}
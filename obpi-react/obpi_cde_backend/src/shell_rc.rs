@@ -0,0 +1,70 @@
+use crate::config::VfsConfig;
+use crate::db::DbPool;
+use crate::protocol::ContentEncoding;
+use crate::vfs::{self, FileContent, ReadOptions, ReadOutcome};
+use anyhow::Result;
+use std::path::Path;
+use tokio::fs;
+
+const PROFILE_FILES: [&str; 3] = [".bash_profile", ".bash_login", ".profile"];
+const RC_FILE: &str = ".bashrc";
+
+/// `OBPI_PROMPT_COMMAND` is set by `PtyHandler::spawn` to the hooks the session relies on.
+const PROMPT_HOOK_FOOTER: &str = r#"case "$PROMPT_COMMAND" in
+  *"$OBPI_PROMPT_COMMAND"*) ;;
+  *) PROMPT_COMMAND="$OBPI_PROMPT_COMMAND${PROMPT_COMMAND:+; $PROMPT_COMMAND}" ;;
+esac
+"#;
+
+/// Writes the rc file for a shell started by `user_id`, whose VFS home is `home`.
+///
+/// Users keep their `~/.bash_profile` and `~/.bashrc` in the VFS, which bash can't see: the
+/// home directory only exists in the database. Rather than materializing a fake home on the
+/// host, we concatenate those files into one generated rc file per session and start bash as
+/// `bash --noprofile --rcfile <file> -i`. `--rcfile` is only honoured by non-login shells, so
+/// the generated file does what a login shell would have done first and sources
+/// `/etc/profile` itself. The resulting order is:
+///
+/// 1. `/etc/profile`, when readable;
+/// 2. the first of `~/.bash_profile`, `~/.bash_login` and `~/.profile` found in the VFS;
+/// 3. `~/.bashrc` from the VFS;
+/// 4. our prompt hook, re-added in case the user's files replaced `PROMPT_COMMAND`.
+///
+/// The VFS files are inlined rather than sourced, so a profile that runs `. ~/.bashrc` reads
+/// the host's file, not the VFS one; step 3 covers the common case. Binary or missing files
+/// are skipped. If the rc file can't be written the shell falls back to `bash -l -i`.
+pub async fn write_rc_file(pool: &DbPool, config: &VfsConfig, user_id: i64, home: &str, rc_file: &Path) -> Result<()> {
+    let mut script = String::from("[ -r /etc/profile ] && . /etc/profile\n");
+    for name in PROFILE_FILES {
+        if let Some(contents) = read_text(pool, config, user_id, home, name).await {
+            append_section(&mut script, name, &contents);
+            break;
+        }
+    }
+    if let Some(contents) = read_text(pool, config, user_id, home, RC_FILE).await {
+        append_section(&mut script, RC_FILE, &contents);
+    }
+    script.push_str(PROMPT_HOOK_FOOTER);
+
+    if let Some(dir) = rc_file.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    fs::write(rc_file, script).await?;
+    Ok(())
+}
+
+async fn read_text(pool: &DbPool, config: &VfsConfig, user_id: i64, home: &str, name: &str) -> Option<String> {
+    let path = format!("{}/{}", home, name);
+    match vfs::read_file_content(pool, config, user_id, &path, ReadOptions { encoding: ContentEncoding::Utf8, ..Default::default() }).await {
+        Ok(ReadOutcome::Content(FileContent { content, is_binary: false, .. })) => Some(content),
+        _ => None,
+    }
+}
+
+fn append_section(script: &mut String, name: &str, contents: &str) {
+    script.push_str(&format!("# ~/{}\n", name));
+    script.push_str(contents);
+    if !contents.ends_with('\n') {
+        script.push('\n');
+    }
+}
@@ -0,0 +1,25 @@
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::ratelimit::RateLimiter;
+use crate::registry::SessionRegistry;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+const ANNOUNCEMENT_BUFFER: usize = 16;
+
+pub struct AppState {
+    pub db_pool: DbPool,
+    pub sessions: SessionRegistry,
+    pub rate_limiter: RateLimiter,
+    /// Admin announcements, forwarded by every connected session.
+    pub announcements: broadcast::Sender<String>,
+    pub config: Arc<Config>,
+}
+
+impl AppState {
+    pub fn new(db_pool: DbPool, config: Config) -> Self {
+        let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+        let (announcements, _) = broadcast::channel(ANNOUNCEMENT_BUFFER);
+        Self { db_pool, sessions: SessionRegistry::default(), rate_limiter, announcements, config: Arc::new(config) }
+    }
+}
@@ -0,0 +1,287 @@
+//! Fixtures for the unit tests: a database migrated by `db::init_db` with one user, a scratch
+//! directory for storage and shell files, and a client for driving sessions over a real socket.
+
+use crate::config::{BackupConfig, Config, Credentials, PtyConfig, RateLimitConfig, VfsConfig};
+use crate::db::{self, DbPool};
+use crate::state::AppState;
+use crate::vfs;
+use axum::{routing::get, Router};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+pub const USERNAME: &str = "tester";
+pub const PASSWORD: &str = "correct-horse";
+/// Longest a test waits for a frame it expects before failing.
+const RECV_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub struct TestEnv {
+    pub pool: DbPool,
+    pub config: Config,
+    /// The bootstrap user, an admin whose home is `/home/tester`.
+    pub user_id: i64,
+    /// Holds the storage root and the shells' rc files; removed on drop.
+    _dir: TempDir,
+}
+
+impl TestEnv {
+    /// A fresh in-memory database. Every connection in the pool shares it.
+    #[cfg(feature = "sqlite")]
+    pub async fn new() -> Self {
+        Self::with_database(|_| "sqlite::memory:".to_string()).await
+    }
+
+    /// A fresh database on the Postgres server `TEST_DATABASE_URL` points at, e.g.
+    /// `postgres://postgres@localhost:5432`. It's left on the server after the test.
+    #[cfg(feature = "postgres")]
+    pub async fn new() -> Self {
+        let server = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must point at a Postgres server");
+        let name = format!("obpi_test_{}", uuid::Uuid::new_v4().simple());
+        Self::with_database(|_| format!("{}/{}", server.trim_end_matches('/'), name)).await
+    }
+
+    /// A database file in the scratch directory, for tests whose connections have to
+    /// interleave the way they would in production.
+    #[cfg(feature = "sqlite")]
+    pub async fn on_disk() -> Self {
+        Self::with_database(|dir| format!("sqlite://{}", dir.join("test.db").display())).await
+    }
+
+    /// Postgres connections always interleave as they would in production.
+    #[cfg(feature = "postgres")]
+    pub async fn on_disk() -> Self {
+        Self::new().await
+    }
+
+    async fn with_database(url: impl FnOnce(&Path) -> String) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), url(dir.path()));
+        let pool = db::init_db(&config).await.unwrap();
+        let user_id = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(USERNAME)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        Self { pool, config, user_id, _dir: dir }
+    }
+
+    pub fn vfs(&self) -> &VfsConfig {
+        &self.config.vfs
+    }
+
+    /// Adds a standard user that can't log in, for checks across owners.
+    pub async fn add_user(&self, username: &str) -> i64 {
+        sqlx::query_scalar("INSERT INTO users (username, password_hash, role) VALUES ($1, 'x:y', 'Standard') RETURNING id")
+            .bind(username)
+            .fetch_one(&self.pool)
+            .await
+            .unwrap()
+    }
+
+    pub async fn mkdir(&self, path: &str) {
+        vfs::create_node(&self.pool, self.vfs(), self.user_id, path, "dir", None, None).await.unwrap();
+    }
+
+    pub async fn write(&self, path: &str, content: impl AsRef<[u8]>) {
+        vfs::create_node(&self.pool, self.vfs(), self.user_id, path, "file", Some(&base64::encode(content)), None).await.unwrap();
+    }
+
+    pub async fn read(&self, path: &str) -> Vec<u8> {
+        read_file(&self.pool, self.vfs(), self.user_id, path).await.unwrap()
+    }
+
+    /// Builds, under `root`:
+    ///
+    /// ```text
+    /// docs/
+    ///   notes.txt   "notes"
+    ///   old/
+    ///     draft.txt "draft"
+    /// src/
+    ///   main.rs     "fn main() {}"
+    /// readme.md     "readme"
+    /// ```
+    pub async fn sample_tree(&self, root: &str) {
+        self.mkdir(&format!("{}/docs", root)).await;
+        self.write(&format!("{}/docs/notes.txt", root), "notes").await;
+        self.mkdir(&format!("{}/docs/old", root)).await;
+        self.write(&format!("{}/docs/old/draft.txt", root), "draft").await;
+        self.mkdir(&format!("{}/src", root)).await;
+        self.write(&format!("{}/src/main.rs", root), "fn main() {}").await;
+        self.write(&format!("{}/readme.md", root), "readme").await;
+    }
+
+    pub async fn node_id(&self, path: &str) -> i64 {
+        vfs::stat_node(&self.pool, self.vfs(), self.user_id, path).await.unwrap().id
+    }
+
+    pub async fn disk_path(&self, path: &str) -> String {
+        let id = self.node_id(path).await;
+        sqlx::query_scalar("SELECT disk_path FROM files WHERE id = $1").bind(id).fetch_one(&self.pool).await.unwrap()
+    }
+
+    pub async fn count(&self, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table)).fetch_one(&self.pool).await.unwrap()
+    }
+
+    /// Blobs currently in the storage root.
+    pub fn blob_count(&self) -> usize {
+        std::fs::read_dir(&self.vfs().storage_root).map(|entries| entries.count()).unwrap_or(0)
+    }
+
+    /// Serves this environment's database and config on a local port, as `main` would.
+    pub async fn serve(&self) -> TestServer {
+        let state = Arc::new(AppState::new(self.pool.clone(), self.config.clone()));
+        let app = Router::new().route("/ws", get(crate::ws_handler)).with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        TestServer { url }
+    }
+}
+
+/// The whole of a file, read piece by piece as a download would.
+pub async fn read_file(pool: &DbPool, config: &VfsConfig, user_id: i64, path: &str) -> anyhow::Result<Vec<u8>> {
+    let mut file = vfs::open_file_from(pool, config, user_id, path, 0, None).await?;
+    let mut content = Vec::new();
+    loop {
+        let chunk = file.next_chunk(4096).await?;
+        if chunk.is_empty() {
+            return Ok(content);
+        }
+        content.extend(chunk);
+    }
+}
+
+/// The settings `Config::from_env` would produce with nothing set, minus the demo users,
+/// rate limiting and shell history, and with everything on disk kept under `dir`.
+pub fn test_config(dir: &Path, database_url: String) -> Config {
+    Config {
+        database_url,
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        tls: None,
+        log_protocol: false,
+        resume_grace: Duration::ZERO,
+        request_timeout: Duration::from_secs(30),
+        idle_timeout: Duration::ZERO,
+        idle_warning: Duration::ZERO,
+        undo_window: Duration::from_secs(300),
+        max_response_bytes: 8 * 1024 * 1024,
+        seed_demo_users: false,
+        default_role: "Standard".to_string(),
+        bootstrap_admin: Some(Credentials { username: USERNAME.to_string(), password: PASSWORD.to_string() }),
+        vfs: VfsConfig {
+            storage_root: dir.join("storage"),
+            storage_root_rewrite: None,
+            max_path_depth: 64,
+            umask: 0o022,
+            default_quota_bytes: None,
+            max_nodes_per_user: None,
+            compress_above: None,
+            template_dir: None,
+        },
+        pty: PtyConfig {
+            output_capacity: 64,
+            scrollback_bytes: 64 * 1024,
+            session_output_bytes: None,
+            osc7_passthrough: false,
+            history_dir: None,
+            rc_dir: dir.join("rc"),
+            startup_command: None,
+        },
+        backup: BackupConfig { interval: Duration::ZERO, dir: None, retention: 1 },
+        rate_limit: RateLimitConfig { heavy_requests: HashSet::new(), burst: 0, per_minute: 0 },
+    }
+}
+
+pub struct TestServer {
+    url: String,
+}
+
+impl TestServer {
+    pub async fn connect(&self) -> TestClient {
+        let (ws, _) = tokio_tungstenite::connect_async(self.url.as_str()).await.unwrap();
+        TestClient { ws, next_id: 0, backlog: VecDeque::new() }
+    }
+
+    /// A client already logged in as the bootstrap user.
+    pub async fn login(&self) -> TestClient {
+        let mut client = self.connect().await;
+        let response = client.request("login", json!({ "username": USERNAME, "password": PASSWORD })).await;
+        assert_eq!(response["type"], "loginSuccess", "{}", response);
+        client
+    }
+}
+
+/// One WebSocket connection. Frames that arrive while waiting for something else are kept
+/// for later `push`/`response` calls rather than dropped.
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    next_id: u64,
+    backlog: VecDeque<Value>,
+}
+
+impl TestClient {
+    /// Sends a request and returns its id without waiting for the response.
+    pub async fn send(&mut self, kind: &str, payload: Value) -> String {
+        self.next_id += 1;
+        let request_id = self.next_id.to_string();
+        let frame = json!({ "request_id": request_id, "type": kind, "payload": payload });
+        self.ws.send(Message::Text(frame.to_string())).await.unwrap();
+        request_id
+    }
+
+    pub async fn request(&mut self, kind: &str, payload: Value) -> Value {
+        let request_id = self.send(kind, payload).await;
+        self.response(&request_id).await
+    }
+
+    pub async fn response(&mut self, request_id: &str) -> Value {
+        self.next_matching(|frame| frame["request_id"] == request_id).await
+    }
+
+    /// The next push of the given `type`.
+    pub async fn push(&mut self, kind: &str) -> Value {
+        self.next_matching(|frame| frame.get("request_id").is_none() && frame["type"] == kind).await
+    }
+
+    /// Terminal output up to and including the first chunk containing `needle`.
+    pub async fn output_until(&mut self, needle: &str) -> String {
+        let mut output = String::new();
+        while !output.contains(needle) {
+            output.push_str(self.push("terminalOutput").await["payload"]["output"].as_str().unwrap());
+        }
+        output
+    }
+
+    async fn next_matching(&mut self, matches: impl Fn(&Value) -> bool) -> Value {
+        if let Some(index) = self.backlog.iter().position(&matches) {
+            return self.backlog.remove(index).unwrap();
+        }
+        loop {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.next_frame())
+                .await
+                .expect("timed out waiting for a frame")
+                .expect("connection closed");
+            if matches(&frame) {
+                return frame;
+            }
+            self.backlog.push_back(frame);
+        }
+    }
+
+    async fn next_frame(&mut self) -> Option<Value> {
+        while let Some(message) = self.ws.next().await {
+            if let Message::Text(text) = message.ok()? {
+                return Some(serde_json::from_str(&text).unwrap());
+            }
+        }
+        None
+    }
+}
@@ -1,201 +1,1995 @@
-use crate::db::DbPool;
-use crate::protocol::{FileNode, TrashedFileNode};
+use crate::config::VfsConfig;
+use crate::db::{Db, DbPool};
+use crate::protocol::{ContentEncoding, DescendantNode, FileNode, LineEnding, MoveResult, OpenableAs, StatEntry, TrashSort, TrashedFileNode, TreeNode};
 use anyhow::{anyhow, Result};
-use chrono::Utc;
-use sqlx::Row;
-use std::env;
+use chrono::{DateTime, Utc};
+use encoding_rs::Encoding;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Acquire, Transaction};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
-pub async fn list_directory(pool: &DbPool, user_id: i64, path_str: &str) -> Result<Vec<FileNode>> {
-    let parent_id = get_path_id(pool, user_id, Path::new(path_str)).await?;
-    let query = "SELECT name, node_type, size, updated_at FROM files WHERE owner_id = ? AND parent_id IS ? AND is_trashed = FALSE ORDER BY node_type DESC, name ASC";
-    let items = sqlx::query_as(query)
+const MAX_TREE_DEPTH: u32 = 16;
+const BINARY_SNIFF_BYTES: usize = 8192;
+/// Shown in the image viewer, recognised by their contents. SVG, which is text but better viewed
+/// than edited, is recognised separately.
+const VIEWABLE_IMAGE_FORMATS: &[image::ImageFormat] = &[
+    image::ImageFormat::Png,
+    image::ImageFormat::Jpeg,
+    image::ImageFormat::Gif,
+    image::ImageFormat::WebP,
+    image::ImageFormat::Bmp,
+    image::ImageFormat::Ico,
+    image::ImageFormat::Avif,
+];
+/// Enough of a file to recognise an image format, or an SVG root element behind an XML prolog.
+const IMAGE_SNIFF_BYTES: u64 = 512;
+const MAX_STAT_BATCH: usize = 1024;
+/// How much of a file `open_file_from` reads at a time to get to `start_offset`.
+const SKIP_CHUNK_BYTES: u64 = 64 * 1024;
+const MAX_THUMBNAIL_DIM: u32 = 512;
+/// Larger images aren't decoded for thumbnails; decoding needs several times this in memory.
+const MAX_THUMBNAIL_SOURCE_BYTES: i64 = 32 * 1024 * 1024;
+const MAX_TEMPLATE_NODES: usize = 1000;
+const BUNDLE_VERSION: u32 = 1;
+/// How much of a file `export_user` encodes at a time. A multiple of 3, so the base64 of each
+/// piece carries on from the last without padding.
+const EXPORT_PIECE_BYTES: usize = 48 * 1024;
+const MAX_MOVE_BATCH: usize = 1024;
+const MAX_COMPLETIONS: i64 = 100;
+/// Directories sort ahead of every other node kind, then everything by name.
+const LIST_DIRECTORY_QUERY: &str = "SELECT id, name, node_type, size, updated_at, EXISTS (SELECT 1 FROM files c WHERE c.parent_id = files.id AND c.is_trashed = FALSE) AS has_children FROM files WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND is_trashed = FALSE AND ($3 OR substr(name, 1, 1) != '.') ORDER BY CASE node_type WHEN 'dir' THEN 0 ELSE 1 END, name ASC";
+const MAX_RECURSIVE_LIST: u32 = 10_000;
+const MAX_ATTR_KEY_LEN: usize = 255;
+const MAX_ATTR_VALUE_BYTES: usize = 64 * 1024;
+const MAX_ATTRS_PER_FILE: i64 = 64;
+
+#[derive(Debug)]
+pub enum VfsError {
+    /// The path isn't valid UTF-8 or contains a NUL byte.
+    InvalidPath,
+    /// The path has more components than `MAX_PATH_DEPTH` allows.
+    PathTooDeep { max: usize },
+    /// A live sibling already has the requested name.
+    Conflict,
+    /// A file operation was attempted on a directory.
+    IsADirectory,
+    /// A path component that must be a directory is a file.
+    NotADirectory,
+    /// The node belongs to another user.
+    PermissionDenied,
+    /// A non-recursive operation targeted a directory that still has children.
+    DirectoryNotEmpty,
+    /// The write would take the user past their storage quota.
+    QuotaExceeded { quota: i64 },
+    /// The user already owns `MAX_NODES_PER_USER` nodes.
+    NodeLimitExceeded { limit: i64 },
+    /// The row's `disk_path` no longer exists, typically because `STORAGE_ROOT` moved.
+    ContentMissing,
+    /// The file is no longer at the revision the write was based on.
+    RevMismatch { current: i64 },
+    /// The server's disk (not the user's quota) has no room left.
+    StorageFull,
+    /// A resumed download's file has been written since the download started.
+    FileChanged { current: i64 },
+    /// The file isn't in a format the operation handles, e.g. a thumbnail of a non-image.
+    Unsupported,
+}
+
+impl VfsError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            VfsError::InvalidPath => "INVALID_PATH",
+            VfsError::PathTooDeep { .. } => "PATH_TOO_DEEP",
+            VfsError::Conflict => "CONFLICT",
+            VfsError::IsADirectory => "IS_A_DIRECTORY",
+            VfsError::NotADirectory => "NOT_A_DIRECTORY",
+            VfsError::PermissionDenied => "PERMISSION_DENIED",
+            VfsError::DirectoryNotEmpty => "DIRECTORY_NOT_EMPTY",
+            VfsError::QuotaExceeded { .. } => "QUOTA_EXCEEDED",
+            VfsError::NodeLimitExceeded { .. } => "NODE_LIMIT_EXCEEDED",
+            VfsError::ContentMissing => "CONTENT_MISSING",
+            VfsError::RevMismatch { .. } => "REV_MISMATCH",
+            VfsError::StorageFull => "STORAGE_FULL",
+            VfsError::FileChanged { .. } => "FILE_CHANGED",
+            VfsError::Unsupported => "UNSUPPORTED",
+        }
+    }
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VfsError::InvalidPath => write!(f, "Invalid path"),
+            VfsError::PathTooDeep { max } => write!(f, "Path exceeds the maximum depth of {} components", max),
+            VfsError::Conflict => write!(f, "A node with that name already exists"),
+            VfsError::IsADirectory => write!(f, "Node is a directory, not a file"),
+            VfsError::NotADirectory => write!(f, "Parent is a file, not a directory"),
+            VfsError::PermissionDenied => write!(f, "Permission denied"),
+            VfsError::DirectoryNotEmpty => write!(f, "Directory is not empty"),
+            VfsError::QuotaExceeded { quota } => write!(f, "Storage quota of {} bytes exceeded", quota),
+            VfsError::NodeLimitExceeded { limit } => write!(f, "Limit of {} files and directories reached", limit),
+            VfsError::ContentMissing => write!(f, "File contents are missing from storage"),
+            VfsError::RevMismatch { current } => write!(f, "File has changed since it was read (now at rev {})", current),
+            VfsError::StorageFull => write!(f, "The server is out of storage space; please contact an administrator"),
+            VfsError::FileChanged { current } => write!(f, "File has changed since the download started (now at rev {})", current),
+            VfsError::Unsupported => write!(f, "File format is not supported for this operation"),
+        }
+    }
+}
+
+impl std::error::Error for VfsError {}
+
+/// What a client is told about `error`: a `VfsError` or a message written for users passes
+/// through, while database and I/O failures, whose text can include SQL or host paths, become
+/// a generic `INTERNAL` error. Callers log the full error.
+pub fn client_error(error: &anyhow::Error) -> (String, Option<&'static str>) {
+    if let Some(vfs_error) = error.downcast_ref::<VfsError>() {
+        return (vfs_error.to_string(), Some(vfs_error.code()));
+    }
+    if error.chain().any(|cause| cause.is::<sqlx::Error>() || cause.is::<std::io::Error>()) {
+        return ("Internal server error".to_string(), Some("INTERNAL"));
+    }
+    (error.to_string(), None)
+}
+
+/// Turns a violation of the unique live-sibling-name index into `VfsError::Conflict`.
+fn map_conflict(e: sqlx::Error) -> anyhow::Error {
+    match &e {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => VfsError::Conflict.into(),
+        _ => e.into(),
+    }
+}
+
+/// Turns running out of disk into `VfsError::StorageFull`, so it isn't mistaken for the user's
+/// own quota.
+fn map_storage_error(e: std::io::Error) -> anyhow::Error {
+    match e.kind() {
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+            tracing::error!("Storage under STORAGE_ROOT is full: {}", e);
+            VfsError::StorageFull.into()
+        }
+        _ => e.into(),
+    }
+}
+
+/// A window into a listing. Without a `limit` everything from `offset` on is returned.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Page {
+    pub offset: u32,
+    pub limit: Option<u32>,
+}
+
+impl Page {
+    /// SQLite treats a negative LIMIT as "no limit", Postgres a NULL one.
+    fn sql_limit(&self) -> Option<i64> {
+        match self.limit {
+            Some(limit) => Some(limit.into()),
+            None if cfg!(feature = "sqlite") => Some(-1),
+            None => None,
+        }
+    }
+}
+
+pub async fn list_directory(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, include_hidden: bool, page: Page) -> Result<Vec<FileNode>> {
+    let parent_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?;
+    let query = format!("{} LIMIT $4 OFFSET $5", LIST_DIRECTORY_QUERY);
+    let items = sqlx::query_as(&query)
+        .bind(user_id)
+        .bind(parent_id)
+        .bind(include_hidden)
+        .bind(page.sql_limit())
+        .bind(i64::from(page.offset))
+        .fetch_all(pool)
+        .await?;
+    Ok(items)
+}
+
+/// The whole of a `list_directory` listing, read lazily from a cursor.
+pub async fn stream_directory<'a>(pool: &'a DbPool, config: &VfsConfig, user_id: i64, path_str: &str, include_hidden: bool) -> Result<BoxStream<'a, Result<FileNode, sqlx::Error>>> {
+    let parent_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?;
+    let rows = sqlx::query_as(LIST_DIRECTORY_QUERY)
         .bind(user_id)
         .bind(parent_id)
+        .bind(include_hidden)
+        .fetch(pool);
+    Ok(rows)
+}
+
+pub struct DirDelta {
+    pub version: i64,
+    pub changed: Vec<FileNode>,
+    pub removed: Vec<i64>,
+}
+
+/// The children of `path` that changed since `since_version` (see the `dir_changes` migration),
+/// with the directory's current version to pass next time. Version 0 gets every live child.
+/// Hidden entries are included, as the explorer filters them itself.
+pub async fn dir_delta(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, since_version: i64) -> Result<DirDelta> {
+    let dir_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?;
+    // One read transaction, so the version matches the snapshot the changes come from.
+    let mut tx = pool.begin().await?;
+    let (version,): (i64,) = sqlx::query_as("SELECT COALESCE(MAX(seq), 0) FROM dir_changes WHERE owner_id = $1 AND dir_id IS NOT DISTINCT FROM $2")
+        .bind(user_id)
+        .bind(dir_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    let changed = sqlx::query_as(
+        "SELECT id, name, node_type, size, updated_at, EXISTS (SELECT 1 FROM files c WHERE c.parent_id = files.id AND c.is_trashed = FALSE) AS has_children
+        FROM files WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND is_trashed = FALSE
+            AND id IN (SELECT node_id FROM dir_changes WHERE owner_id = $3 AND dir_id IS NOT DISTINCT FROM $4 AND seq > $5)
+        ORDER BY CASE node_type WHEN 'dir' THEN 0 ELSE 1 END, name ASC"
+    )
+    .bind(user_id)
+    .bind(dir_id)
+    .bind(user_id)
+    .bind(dir_id)
+    .bind(since_version)
+    .fetch_all(&mut *tx)
+    .await?;
+    let removed: Vec<(i64,)> = sqlx::query_as(
+        "SELECT node_id FROM dir_changes d WHERE owner_id = $1 AND dir_id IS NOT DISTINCT FROM $2 AND seq > $3
+            AND NOT EXISTS (SELECT 1 FROM files f WHERE f.id = d.node_id AND f.parent_id IS NOT DISTINCT FROM d.dir_id AND f.is_trashed = FALSE)"
+    )
+    .bind(user_id)
+    .bind(dir_id)
+    .bind(since_version)
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(DirDelta { version, changed, removed: removed.into_iter().map(|(id,)| id).collect() })
+}
+
+/// Children of `dir` whose names start with `prefix`, case-sensitively like the shell.
+/// Dotfiles only match a prefix that starts with a dot. A directory that doesn't exist has no
+/// completions.
+pub async fn complete_path(pool: &DbPool, config: &VfsConfig, user_id: i64, dir: &str, prefix: &str) -> Result<Vec<FileNode>> {
+    let dir_id = get_path_id(pool, config, user_id, Path::new(dir)).await?;
+    if dir_id.is_none() && dir != "/" {
+        return Ok(Vec::new());
+    }
+    let query = "SELECT id, name, node_type, size, updated_at, EXISTS (SELECT 1 FROM files c WHERE c.parent_id = files.id AND c.is_trashed = FALSE) AS has_children FROM files WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND is_trashed = FALSE AND substr(name, 1, length($3)) = $4 AND ($5 OR substr(name, 1, 1) != '.') ORDER BY name ASC LIMIT $6";
+    let items = sqlx::query_as(query)
+        .bind(user_id)
+        .bind(dir_id)
+        .bind(prefix)
+        .bind(prefix)
+        .bind(prefix.starts_with('.'))
+        .bind(MAX_COMPLETIONS)
         .fetch_all(pool)
         .await?;
     Ok(items)
 }
 
-pub async fn read_file_content(pool: &DbPool, user_id: i64, path_str: &str) -> Result<String> {
-    let (disk_path_str,): (String,) =
-        sqlx::query_as("SELECT disk_path FROM files WHERE id = ? AND owner_id = ? AND node_type = 'file'")
-            .bind(get_path_id(pool, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("File not found"))?)
-            .bind(user_id)
-            .fetch_one(pool)
-            .await?;
-    
-    let content = fs::read(disk_path_str).await?;
-    Ok(base64::encode(content))
+/// Every live descendant of `path_str` in path order. Hidden directories are skipped along
+/// with their contents when `include_hidden` is false. At most `MAX_RECURSIVE_LIST` entries
+/// come back per page.
+pub async fn list_descendants(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, include_hidden: bool, page: Page) -> Result<Vec<DescendantNode>> {
+    let root_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?;
+    let limit = page.limit.unwrap_or(MAX_RECURSIVE_LIST).min(MAX_RECURSIVE_LIST);
+    let items = sqlx::query_as(
+        "WITH RECURSIVE descendants(id, path) AS (
+            SELECT id, name FROM files
+            WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND is_trashed = FALSE AND ($3 OR substr(name, 1, 1) != '.')
+            UNION ALL
+            SELECT f.id, d.path || '/' || f.name FROM files f JOIN descendants d ON f.parent_id = d.id
+            WHERE f.is_trashed = FALSE AND ($4 OR substr(f.name, 1, 1) != '.')
+        )
+        SELECT d.path, f.node_type, f.size, f.updated_at
+        FROM descendants d JOIN files f ON f.id = d.id
+        ORDER BY d.path
+        LIMIT $5 OFFSET $6"
+    )
+    .bind(user_id)
+    .bind(root_id)
+    .bind(include_hidden)
+    .bind(include_hidden)
+    .bind(i64::from(limit))
+    .bind(i64::from(page.offset))
+    .fetch_all(pool)
+    .await?;
+    Ok(items)
+}
+
+#[derive(sqlx::FromRow)]
+struct TreeRow {
+    id: i64,
+    parent_id: Option<i64>,
+    name: String,
+    node_type: String,
+    size: i64,
+    updated_at: DateTime<Utc>,
+    child_count: i64,
+}
+
+pub async fn get_tree(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, max_depth: u32) -> Result<Vec<TreeNode>> {
+    let root_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?;
+    let max_depth = max_depth.clamp(1, MAX_TREE_DEPTH);
+    let rows: Vec<TreeRow> = sqlx::query_as(
+        "WITH RECURSIVE tree(id, depth) AS (
+            SELECT id, 1 FROM files WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND is_trashed = FALSE
+            UNION ALL
+            SELECT f.id, t.depth + 1 FROM files f JOIN tree t ON f.parent_id = t.id
+            WHERE f.is_trashed = FALSE AND t.depth < $3
+        )
+        SELECT f.id, f.parent_id, f.name, f.node_type, f.size, f.updated_at,
+            (SELECT COUNT(*) FROM files c WHERE c.parent_id = f.id AND c.is_trashed = FALSE) AS child_count
+        FROM tree t JOIN files f ON f.id = t.id
+        ORDER BY CASE f.node_type WHEN 'dir' THEN 0 ELSE 1 END, f.name ASC"
+    )
+    .bind(user_id)
+    .bind(root_id)
+    .bind(i64::from(max_depth))
+    .fetch_all(pool)
+    .await?;
+
+    let mut by_parent: HashMap<Option<i64>, Vec<TreeRow>> = HashMap::new();
+    for row in rows {
+        by_parent.entry(row.parent_id).or_default().push(row);
+    }
+    Ok(build_tree(root_id, &mut by_parent))
+}
+
+fn build_tree(parent_id: Option<i64>, by_parent: &mut HashMap<Option<i64>, Vec<TreeRow>>) -> Vec<TreeNode> {
+    by_parent
+        .remove(&parent_id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|row| TreeNode {
+            children: build_tree(Some(row.id), by_parent),
+            name: row.name,
+            node_type: row.node_type,
+            size: row.size,
+            updated_at: row.updated_at,
+            child_count: row.child_count,
+        })
+        .collect()
+}
+
+pub struct FileContent {
+    pub id: i64,
+    pub content: String,
+    pub encoding: ContentEncoding,
+    pub is_binary: bool,
+    pub rev: i64,
+    /// Of the content after any `source_encoding` conversion, i.e. of what `content` decodes to.
+    pub sha256: String,
+    /// Only computed when asked for, and never for binary files.
+    pub line_info: Option<LineInfo>,
+}
+
+pub struct LineInfo {
+    pub line_count: usize,
+    /// `None` when the content has no line breaks at all.
+    pub line_ending: Option<LineEnding>,
+}
+
+#[derive(Default)]
+pub struct ReadOptions<'a> {
+    pub encoding: ContentEncoding,
+    /// The `rev` the caller already has; an unchanged file reads as `NotModified`.
+    pub if_none_match: Option<i64>,
+    /// With a source encoding (a WHATWG label such as `windows-1252`), the stored bytes are
+    /// decoded from it and returned as UTF-8 text. Without one they're returned as stored.
+    pub source_encoding: Option<&'a str>,
+    pub line_info: bool,
+    /// Converts CRLF to LF in text files. `line_info` still describes the stored line endings.
+    pub normalize_eol: bool,
+}
+
+#[derive(Default)]
+pub struct WriteOptions<'a> {
+    /// Encoding to store the client's UTF-8 text in, as for `ReadOptions::source_encoding`.
+    pub source_encoding: Option<&'a str>,
+    /// Fails the write with `RevMismatch` if the file is no longer at this rev.
+    pub expected_rev: Option<i64>,
+    /// Converts CRLF to LF before storing, unless the content looks binary.
+    pub normalize_eol: bool,
+}
+
+pub enum ReadOutcome {
+    Content(FileContent),
+    /// The file is still at the revision the caller already has.
+    NotModified { id: i64, rev: i64 },
+}
+
+pub async fn read_file_content(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, options: ReadOptions<'_>) -> Result<ReadOutcome> {
+    let file_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("File not found"))?;
+    read_file_by_id(pool, user_id, file_id, options).await
+}
+
+/// Reads a file the client already knows the id of, so a concurrent rename between listing
+/// and reading can't make it open a different file.
+pub async fn read_file_by_id(pool: &DbPool, user_id: i64, file_id: i64, options: ReadOptions<'_>) -> Result<ReadOutcome> {
+    let source_encoding = options.source_encoding.map(lookup_encoding).transpose()?;
+    let (disk_path_str, is_binary, compressed, rev): (Option<String>, bool, bool, i64) =
+        sqlx::query_as("SELECT disk_path, is_binary, compressed, rev FROM files WHERE id = $1 AND owner_id = $2 AND is_trashed = FALSE")
+            .bind(file_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| anyhow!("File not found"))?;
+    let disk_path = disk_path_str.ok_or(VfsError::IsADirectory)?;
+    if options.if_none_match == Some(rev) {
+        return Ok(ReadOutcome::NotModified { id: file_id, rev });
+    }
+    
+    let mut content = load_blob(Path::new(&disk_path), compressed).await?;
+    if let Some(source_encoding) = source_encoding {
+        content = source_encoding.decode_without_bom_handling(&content).0.into_owned().into_bytes();
+    }
+    let line_info = (options.line_info && !is_binary).then(|| line_info(&content));
+    if options.normalize_eol && !is_binary {
+        content = normalize_eol(&content);
+    }
+    let sha256 = sha256_hex(&content);
+    let (content, encoding) = encode_content(content, options.encoding, is_binary);
+    Ok(ReadOutcome::Content(FileContent { id: file_id, content, encoding, is_binary, rev, sha256, line_info }))
+}
+
+/// A download opened by `open_file_from`. Each piece is read on a blocking thread as it's
+/// asked for, so the file is never in memory whole.
+pub struct FileReader {
+    pub rev: i64,
+    offset: u64,
+    /// Taken while a read is on the blocking thread; left empty if that read failed.
+    blob: Option<(Box<dyn Read + Send>, Sha256)>,
+}
+
+impl FileReader {
+    /// Where the next piece starts.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The next piece, of at most `max_len` bytes; empty at the end of the file.
+    pub async fn next_chunk(&mut self, max_len: usize) -> Result<Vec<u8>> {
+        let (mut blob, mut hasher) = self.blob.take().ok_or_else(|| anyhow!("An earlier read of this file failed"))?;
+        let (blob, hasher, chunk) = tokio::task::spawn_blocking(move || {
+            let mut chunk = Vec::with_capacity(max_len);
+            (&mut blob).take(max_len as u64).read_to_end(&mut chunk)?;
+            hasher.update(&chunk);
+            anyhow::Ok((blob, hasher, chunk))
+        })
+        .await??;
+        self.blob = Some((blob, hasher));
+        self.offset += chunk.len() as u64;
+        Ok(chunk)
+    }
+
+    /// The hex SHA-256 of the whole file, once `next_chunk` has come back empty.
+    pub fn sha256(&self) -> Option<String> {
+        self.blob.as_ref().map(|(_, hasher)| hex::encode(hasher.clone().finalize()))
+    }
+}
+
+/// Opens a file to read its raw bytes from `start_offset` on. With an `expected_rev`, a file
+/// that has moved on fails with `FileChanged` rather than splicing two versions together. The
+/// bytes before `start_offset` are still read, though not returned, so that `sha256` covers the
+/// whole file; compressed blobs have to be decoded that far regardless.
+pub async fn open_file_from(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, start_offset: u64, expected_rev: Option<i64>) -> Result<FileReader> {
+    let file_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("File not found"))?;
+    let (blob, rev) = loop {
+        let (disk_path, compressed, rev) = blob_row(pool, user_id, file_id).await?;
+        if expected_rev.is_some_and(|expected| expected != rev) {
+            return Err(VfsError::FileChanged { current: rev }.into());
+        }
+        let path = PathBuf::from(&disk_path);
+        let blob = tokio::task::spawn_blocking(move || open_blob(&path, compressed)).await??;
+        // A write may have replaced the blob between the query and the open. An open blob
+        // can't change, so if the row hasn't moved on since, the rev describes what was opened.
+        let (disk_path_now, _, rev_now) = blob_row(pool, user_id, file_id).await?;
+        if (disk_path_now, rev_now) == (disk_path, rev) {
+            break (blob, rev);
+        }
+    };
+    let mut reader = FileReader { rev, offset: 0, blob: Some((blob, Sha256::new())) };
+    while reader.offset < start_offset {
+        let skip = (start_offset - reader.offset).min(SKIP_CHUNK_BYTES) as usize;
+        if reader.next_chunk(skip).await?.is_empty() {
+            return Err(anyhow!("start_offset is past the end of the file"));
+        }
+    }
+    Ok(reader)
+}
+
+/// A live file's blob path, whether it's compressed, and its rev.
+async fn blob_row(pool: &DbPool, user_id: i64, file_id: i64) -> Result<(String, bool, i64)> {
+    let (disk_path, compressed, rev): (Option<String>, bool, i64) =
+        sqlx::query_as("SELECT disk_path, compressed, rev FROM files WHERE id = $1 AND owner_id = $2 AND is_trashed = FALSE")
+            .bind(file_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| anyhow!("File not found"))?;
+    Ok((disk_path.ok_or(VfsError::IsADirectory)?, compressed, rev))
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+fn normalize_eol(content: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(content.len());
+    for (i, &b) in content.iter().enumerate() {
+        if !(b == b'\r' && content.get(i + 1) == Some(&b'\n')) {
+            normalized.push(b);
+        }
+    }
+    normalized
+}
+
+pub struct Thumbnail {
+    pub rev: i64,
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// An image file scaled to fit `max_dim` (at most `MAX_THUMBNAIL_DIM`) on both sides, as PNG
+/// if it has transparency and JPEG otherwise. Images are recognised by their contents, not
+/// their name; anything else fails with `Unsupported`. Results are cached per file, size and rev.
+pub async fn thumbnail(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, max_dim: u32) -> Result<Thumbnail> {
+    let max_dim = max_dim.clamp(1, MAX_THUMBNAIL_DIM);
+    let file_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("File not found"))?;
+    let (disk_path_str, compressed, rev, size): (Option<String>, bool, i64, i64) =
+        sqlx::query_as("SELECT disk_path, compressed, rev, size FROM files WHERE id = $1 AND owner_id = $2 AND is_trashed = FALSE")
+            .bind(file_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| anyhow!("File not found"))?;
+    let disk_path = disk_path_str.ok_or(VfsError::IsADirectory)?;
+    let cached: Option<(String, i64, i64, Vec<u8>)> = sqlx::query_as("SELECT mime, width, height, data FROM thumbnails WHERE file_id = $1 AND max_dim = $2 AND rev = $3")
+        .bind(file_id)
+        .bind(i64::from(max_dim))
+        .bind(rev)
+        .fetch_optional(pool)
+        .await?;
+    if let Some((mime, width, height, data)) = cached {
+        return Ok(Thumbnail { rev, mime, width: width.try_into()?, height: height.try_into()?, data });
+    }
+    if size > MAX_THUMBNAIL_SOURCE_BYTES {
+        return Err(anyhow!("Image is too large to thumbnail"));
+    }
+
+    let content = load_blob(Path::new(&disk_path), compressed).await?;
+    let (mime, width, height, data) = tokio::task::spawn_blocking(move || scale_image(&content, max_dim)).await??;
+    sqlx::query(
+        "INSERT INTO thumbnails (file_id, max_dim, rev, mime, width, height, data) VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (file_id, max_dim) DO UPDATE SET rev = excluded.rev, mime = excluded.mime, width = excluded.width, height = excluded.height, data = excluded.data"
+    )
+    .bind(file_id)
+    .bind(i64::from(max_dim))
+    .bind(rev)
+    .bind(&mime)
+    .bind(i64::from(width))
+    .bind(i64::from(height))
+    .bind(&data)
+    .execute(pool)
+    .await?;
+    Ok(Thumbnail { rev, mime, width, height, data })
+}
+
+fn scale_image(content: &[u8], max_dim: u32) -> Result<(String, u32, u32, Vec<u8>)> {
+    let format = image::guess_format(content).map_err(|_| VfsError::Unsupported)?;
+    let image = image::load_from_memory_with_format(content, format).map_err(|e| anyhow!("Image could not be decoded: {}", e))?;
+    // Small images are sent as they are rather than scaled up.
+    let thumbnail = if image.width() <= max_dim && image.height() <= max_dim { image } else { image.thumbnail(max_dim, max_dim) };
+    let (width, height) = (thumbnail.width(), thumbnail.height());
+    // JPEG has no alpha channel, so transparent images stay PNG.
+    let (encoded, format, mime) = if thumbnail.color().has_alpha() {
+        (thumbnail, image::ImageFormat::Png, "image/png")
+    } else {
+        (image::DynamicImage::ImageRgb8(thumbnail.to_rgb8()), image::ImageFormat::Jpeg, "image/jpeg")
+    };
+    let mut data = std::io::Cursor::new(Vec::new());
+    encoded.write_to(&mut data, format)?;
+    Ok((mime.to_string(), width, height, data.into_inner()))
+}
+
+/// A final line without a trailing newline still counts as a line.
+fn line_info(content: &[u8]) -> LineInfo {
+    let mut line_count = 0;
+    let (mut lf, mut crlf) = (false, false);
+    for (i, &b) in content.iter().enumerate() {
+        if b == b'\n' {
+            line_count += 1;
+            if i > 0 && content[i - 1] == b'\r' {
+                crlf = true;
+            } else {
+                lf = true;
+            }
+        }
+    }
+    if content.last().is_some_and(|&b| b != b'\n') {
+        line_count += 1;
+    }
+    let line_ending = match (lf, crlf) {
+        (true, true) => Some(LineEnding::Mixed),
+        (true, false) => Some(LineEnding::Lf),
+        (false, true) => Some(LineEnding::Crlf),
+        (false, false) => None,
+    };
+    LineInfo { line_count, line_ending }
+}
+
+fn lookup_encoding(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| anyhow!("Unknown encoding '{}'", label))
+}
+
+/// Converts UTF-8 text from the client to `encoding`, refusing characters it can't represent
+/// rather than storing encoding_rs's numeric character reference substitutes.
+fn encode_from_utf8(content: Vec<u8>, encoding: &'static Encoding) -> Result<Vec<u8>> {
+    let text = String::from_utf8(content).map_err(|_| anyhow!("Content must be UTF-8 text to convert it to {}", encoding.name()))?;
+    let (bytes, _, had_errors) = encoding.encode(&text);
+    if had_errors {
+        return Err(anyhow!("Content has characters that can't be represented in {}", encoding.name()));
+    }
+    Ok(bytes.into_owned())
+}
+
+/// Writes file contents to disk, gzipped when they exceed `config.compress_above`. Returns
+/// whether they were compressed, for the row's `compressed` flag.
+///
+/// The blob is written beside `path` and renamed over it, so a failed write (e.g. a full disk)
+/// leaves the previous contents intact and the row, which is only updated afterwards, accurate.
+async fn store_blob(config: &VfsConfig, path: &Path, content: &[u8]) -> Result<bool> {
+    let gzipped = match config.compress_above {
+        Some(threshold) if content.len() > threshold => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content)?;
+            Some(encoder.finish()?)
+        }
+        _ => None,
+    };
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    if let Err(e) = fs::write(&partial, gzipped.as_deref().unwrap_or(content)).await {
+        let _ = fs::remove_file(&partial).await;
+        return Err(map_storage_error(e));
+    }
+    fs::rename(&partial, path).await?;
+    Ok(gzipped.is_some())
+}
+
+async fn load_blob(path: &Path, compressed: bool) -> Result<Vec<u8>> {
+    let stored = fs::read(path).await.map_err(|e| blob_error(path, e))?;
+    if !compressed {
+        return Ok(stored);
+    }
+    let mut content = Vec::new();
+    GzDecoder::new(stored.as_slice()).read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Opens a blob to read its content from the start, decompressing as it goes. It reads with
+/// blocking calls, so use it from `spawn_blocking`.
+fn open_blob(path: &Path, compressed: bool) -> Result<Box<dyn Read + Send>> {
+    let file = std::fs::File::open(path).map_err(|e| blob_error(path, e))?;
+    if compressed {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(std::io::BufReader::new(file)))
+    }
+}
+
+fn blob_error(path: &Path, e: std::io::Error) -> anyhow::Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        tracing::error!("Blob {:?} is missing; if STORAGE_ROOT was moved, set STORAGE_ROOT_REWRITE and run VerifyStorage.", path);
+        VfsError::ContentMissing.into()
+    } else {
+        e.into()
+    }
+}
+
+/// Text is only returned as UTF-8 when the client asked for it and the bytes are valid UTF-8;
+/// anything else falls back to base64 so binary content survives the JSON round-trip.
+fn encode_content(content: Vec<u8>, preferred: ContentEncoding, is_binary: bool) -> (String, ContentEncoding) {
+    if preferred == ContentEncoding::Utf8 && !is_binary {
+        match String::from_utf8(content) {
+            Ok(text) => (text, ContentEncoding::Utf8),
+            Err(e) => (base64::encode(e.into_bytes()), ContentEncoding::Base64),
+        }
+    } else {
+        (base64::encode(content), ContentEncoding::Base64)
+    }
+}
+
+/// Decides between the editor, the image viewer and refusing, from the start of the stored
+/// content, the binary flag and whether a read would fit in `max_response_bytes`. The name
+/// plays no part, so a misnamed image still opens as one. Images go out base64-encoded, text as is.
+pub async fn openable_as(pool: &DbPool, user_id: i64, entry: &StatEntry, max_response_bytes: usize) -> Result<OpenableAs> {
+    if entry.node_type == "dir" {
+        return Ok(OpenableAs::Directory);
+    }
+    let (disk_path, compressed): (Option<String>, bool) = sqlx::query_as("SELECT disk_path, compressed FROM files WHERE id = $1 AND owner_id = $2")
+        .bind(entry.id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| anyhow!("File not found"))?;
+    let disk_path = PathBuf::from(disk_path.ok_or(VfsError::IsADirectory)?);
+    let head = tokio::task::spawn_blocking(move || {
+        let mut head = Vec::new();
+        open_blob(&disk_path, compressed)?.take(IMAGE_SNIFF_BYTES).read_to_end(&mut head)?;
+        anyhow::Ok(head)
+    })
+    .await??;
+    let (kind, read_size) = if is_viewable_image(&head) {
+        (OpenableAs::Image, (entry.size as usize).div_ceil(3) * 4)
+    } else if entry.is_binary {
+        return Ok(OpenableAs::Binary);
+    } else {
+        (OpenableAs::Text, entry.size as usize)
+    };
+    Ok(if read_size > max_response_bytes { OpenableAs::TooLarge } else { kind })
+}
+
+/// Whether `head`, the start of a file, is an image a browser can show: one of
+/// `VIEWABLE_IMAGE_FORMATS`, or text whose first element is `<svg`.
+fn is_viewable_image(head: &[u8]) -> bool {
+    if image::guess_format(head).is_ok_and(|format| VIEWABLE_IMAGE_FORMATS.contains(&format)) {
+        return true;
+    }
+    if is_probably_binary(head) {
+        return false;
+    }
+    // Skip the BOM, the XML prolog, a doctype and any comments to reach the root element.
+    let mut rest = String::from_utf8_lossy(head).trim_start_matches('\u{feff}').to_string();
+    loop {
+        rest = rest.trim_start().to_string();
+        let skipped = if rest.starts_with("<?") {
+            rest.find("?>").map(|end| end + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 3)
+        } else if rest.starts_with("<!") {
+            rest.find('>').map(|end| end + 1)
+        } else {
+            break;
+        };
+        match skipped {
+            Some(end) => rest.drain(..end),
+            None => return false,
+        };
+    }
+    rest.starts_with("<svg")
+}
+
+/// Sniffs the start of `bytes`: any NUL, or more than 30% control characters other than
+/// common whitespace and escapes, marks the content as binary.
+pub fn is_probably_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample
+        .iter()
+        .filter(|&&b| (b < 0x20 && !matches!(b, b'\n' | b'\r' | b'\t' | 0x0c | 0x1b)) || b == 0x7f)
+        .count();
+    control * 10 > sample.len() * 3
+}
+
+pub async fn stat_node(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str) -> Result<StatEntry> {
+    let node_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("Node not found"))?;
+    fetch_stat(pool, user_id, node_id).await
+}
+
+/// Stats each path in order. Paths that don't resolve, including ones that could never be
+/// valid, come back as `None` instead of failing the batch.
+pub async fn stat_many(pool: &DbPool, config: &VfsConfig, user_id: i64, paths: &[String]) -> Result<Vec<Option<StatEntry>>> {
+    if paths.len() > MAX_STAT_BATCH {
+        return Err(anyhow!("At most {} paths can be stat'ed at once", MAX_STAT_BATCH));
+    }
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let node_id = match get_path_id(pool, config, user_id, Path::new(path)).await {
+            Ok(node_id) => node_id,
+            Err(e) if e.is::<VfsError>() => None,
+            Err(e) => return Err(e),
+        };
+        entries.push(match node_id {
+            Some(node_id) => Some(fetch_stat(pool, user_id, node_id).await?),
+            None => None,
+        });
+    }
+    Ok(entries)
+}
+
+async fn fetch_stat(pool: &DbPool, user_id: i64, node_id: i64) -> Result<StatEntry> {
+    let entry = sqlx::query_as(
+        "SELECT id, name, node_type, size, is_binary, rev, mode, created_at, updated_at FROM files WHERE id = $1 AND owner_id = $2"
+    )
+    .bind(node_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(entry)
+}
+
+/// With a `source_encoding`, the content is taken as UTF-8 text and stored in that encoding.
+/// With an `expected_rev`, the write is refused with `RevMismatch` if the file has moved on.
+pub async fn write_file_content(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, base64_content: &str, options: WriteOptions<'_>) -> Result<()> {
+    let file_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("File not found"))?;
+    write_file_by_id(pool, config, user_id, file_id, base64_content, options).await?;
+    Ok(())
+}
+
+/// The id-based counterpart of `write_file_content`. Returns the file's current path.
+pub async fn write_file_by_id(pool: &DbPool, config: &VfsConfig, user_id: i64, file_id: i64, base64_content: &str, options: WriteOptions<'_>) -> Result<String> {
+    let mut content = base64::decode(base64_content)?;
+    if options.normalize_eol && !is_probably_binary(&content) {
+        content = normalize_eol(&content);
+    }
+    if let Some(label) = options.source_encoding {
+        content = encode_from_utf8(content, lookup_encoding(label)?)?;
+    }
+
+    let (disk_path_str, path, rev, old_size): (Option<String>, String, i64, i64) = sqlx::query_as("SELECT disk_path, original_path, rev, size FROM files WHERE id = $1 AND owner_id = $2 AND is_trashed = FALSE")
+        .bind(file_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| anyhow!("File not found"))?;
+    if options.expected_rev.is_some_and(|expected| expected != rev) {
+        return Err(VfsError::RevMismatch { current: rev }.into());
+    }
+    
+    if let Some(disk_path) = disk_path_str {
+        // Only growth counts: the old contents are replaced, and a write that shrinks the file
+        // is allowed even over quota since it can only help.
+        let growth = content.len() as i64 - old_size;
+        if growth > 0 {
+            check_quota(pool, user_id, growth).await?;
+        }
+        let compressed = store_blob(config, Path::new(&disk_path), &content).await?;
+        // `size` stays the logical size so quotas don't depend on how well content compresses.
+        sqlx::query("UPDATE files SET size = $1, is_binary = $2, compressed = $3, rev = rev + 1, updated_at = $4 WHERE id = $5 AND owner_id = $6")
+            .bind(content.len() as i64)
+            .bind(is_probably_binary(&content))
+            .bind(compressed)
+            .bind(Utc::now())
+            .bind(file_id)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+        Ok(path)
+    } else {
+        Err(VfsError::IsADirectory.into())
+    }
+}
+
+pub async fn get_attrs(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str) -> Result<BTreeMap<String, String>> {
+    let node_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("Node not found"))?;
+    let rows: Vec<(String, String)> = sqlx::query_as("SELECT key, value FROM file_attrs WHERE file_id = $1")
+        .bind(node_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.into_iter().collect())
+}
+
+/// Sets or, with no `value`, removes one attribute.
+pub async fn set_attr(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, key: &str, value: Option<&str>) -> Result<()> {
+    if key.is_empty() || key.len() > MAX_ATTR_KEY_LEN {
+        return Err(anyhow!("Attribute names must be 1-{} bytes", MAX_ATTR_KEY_LEN));
+    }
+    let node_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("Node not found"))?;
+    let Some(value) = value else {
+        sqlx::query("DELETE FROM file_attrs WHERE file_id = $1 AND key = $2").bind(node_id).bind(key).execute(pool).await?;
+        return Ok(());
+    };
+    if value.len() > MAX_ATTR_VALUE_BYTES {
+        return Err(anyhow!("Attribute values must be at most {} bytes", MAX_ATTR_VALUE_BYTES));
+    }
+    let mut tx = pool.begin().await?;
+    let others: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM file_attrs WHERE file_id = $1 AND key != $2")
+        .bind(node_id)
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+    if others >= MAX_ATTRS_PER_FILE {
+        return Err(anyhow!("A node can have at most {} attributes", MAX_ATTRS_PER_FILE));
+    }
+    sqlx::query("INSERT INTO file_attrs (file_id, key, value) VALUES ($1, $2, $3) ON CONFLICT (file_id, key) DO UPDATE SET value = excluded.value")
+        .bind(node_id)
+        .bind(key)
+        .bind(value)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub struct StorageReport {
+    pub checked: u64,
+    pub rebased: u64,
+    pub missing: u64,
+}
+
+/// Checks that every file's blob exists, across all users. With `storage_root_rewrite` set,
+/// paths under that old root are first rebased onto `storage_root`, but only where the blob is
+/// actually found there, so rows are never pointed at nothing.
+pub async fn verify_storage(pool: &DbPool, config: &VfsConfig) -> Result<StorageReport> {
+    let rows: Vec<(i64, String)> = sqlx::query_as("SELECT id, disk_path FROM files WHERE disk_path IS NOT NULL ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    let mut report = StorageReport { checked: 0, rebased: 0, missing: 0 };
+    for (id, disk_path) in rows {
+        report.checked += 1;
+        if fs::try_exists(&disk_path).await? {
+            continue;
+        }
+        let rebased = config
+            .storage_root_rewrite
+            .as_deref()
+            .and_then(|old_root| Path::new(&disk_path).strip_prefix(old_root).ok())
+            .map(|rest| config.storage_root.join(rest));
+        match rebased {
+            Some(new_path) if fs::try_exists(&new_path).await? => {
+                sqlx::query("UPDATE files SET disk_path = $1 WHERE id = $2")
+                    .bind(new_path.to_string_lossy())
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+                report.rebased += 1;
+            }
+            _ => {
+                tracing::warn!("File {} has no blob at {}.", id, disk_path);
+                report.missing += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A user's live tree as written by `export_user`: JSON, gzipped. Paths are relative to the
+/// user's root and parents come before their children.
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    version: u32,
+    nodes: Vec<BundleNode>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleNode {
+    path: String,
+    node_type: String,
+    mode: u32,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    /// Base64 file contents; `None` for directories. `export_user` writes it last.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(default)]
+    attrs: BTreeMap<String, String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ExportRow {
+    id: i64,
+    path: String,
+    node_type: String,
+    #[sqlx(try_from = "i64")]
+    mode: u32,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    disk_path: Option<String>,
+    compressed: bool,
+}
+
+/// Packs every live node of `user_id` (not the trash) with contents, modes, timestamps and
+/// attributes into a bundle `import_user` can recreate elsewhere. The gzipped bundle goes out
+/// through `chunks` in pieces of about `chunk_len` bytes as it's written, and each file is read
+/// as it's packed, so neither the bundle nor any file is held in memory whole.
+pub async fn export_user(pool: &DbPool, user_id: i64, chunk_len: usize, chunks: mpsc::Sender<Vec<u8>>) -> Result<()> {
+    let rows: Vec<ExportRow> = sqlx::query_as(
+        "WITH RECURSIVE tree(id, path, depth) AS (
+            SELECT id, name, 0 FROM files WHERE owner_id = $1 AND parent_id IS NULL AND is_trashed = FALSE
+            UNION ALL
+            SELECT f.id, t.path || '/' || f.name, t.depth + 1 FROM files f JOIN tree t ON f.parent_id = t.id
+            WHERE f.is_trashed = FALSE
+        )
+        SELECT f.id, t.path, f.node_type, f.mode, f.created_at, f.updated_at, f.disk_path, f.compressed
+        FROM tree t JOIN files f ON f.id = t.id ORDER BY t.depth, t.path"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    // The bundle is one JSON object, written a node at a time on a blocking thread.
+    let sink = ChunkSink { pending: Vec::with_capacity(chunk_len), chunk_len, chunks };
+    let mut bundle = GzEncoder::new(sink, Compression::default());
+    bundle = write_blocking(bundle, |bundle| Ok(write!(bundle, "{{\"version\":{},\"nodes\":[", BUNDLE_VERSION)?)).await?;
+    for (i, row) in rows.into_iter().enumerate() {
+        let attrs: Vec<(String, String)> = sqlx::query_as("SELECT key, value FROM file_attrs WHERE file_id = $1").bind(row.id).fetch_all(pool).await?;
+        let node = BundleNode {
+            path: row.path,
+            node_type: row.node_type,
+            mode: row.mode,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            content: None,
+            attrs: attrs.into_iter().collect(),
+        };
+        let blob = row.disk_path.map(|disk_path| (PathBuf::from(disk_path), row.compressed));
+        bundle = write_blocking(bundle, move |bundle| {
+            if i > 0 {
+                bundle.write_all(b",")?;
+            }
+            write_bundle_node(bundle, &node, blob)
+        })
+        .await?;
+    }
+    write_blocking(bundle, |bundle| {
+        bundle.write_all(b"]}")?;
+        bundle.try_finish()?;
+        Ok(bundle.get_mut().flush()?)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Runs `write` against `out` on a blocking thread and hands `out` back.
+async fn write_blocking<W: Send + 'static>(mut out: W, write: impl FnOnce(&mut W) -> Result<()> + Send + 'static) -> Result<W> {
+    tokio::task::spawn_blocking(move || write(&mut out).map(|()| out)).await?
+}
+
+/// Writes `node`, whose `content` is left empty, followed by the contents of its blob, if any,
+/// base64-encoded as they're read.
+fn write_bundle_node(out: &mut impl Write, node: &BundleNode, blob: Option<(PathBuf, bool)>) -> Result<()> {
+    let fields = serde_json::to_vec(node)?;
+    let Some((path, compressed)) = blob else {
+        out.write_all(&fields)?;
+        return Ok(());
+    };
+    // Reopen the object serde_json closed to add `content` as its last field.
+    out.write_all(&fields[..fields.len() - 1])?;
+    out.write_all(b",\"content\":\"")?;
+    let mut blob = open_blob(&path, compressed)?;
+    let mut piece = Vec::with_capacity(EXPORT_PIECE_BYTES);
+    loop {
+        piece.clear();
+        (&mut blob).take(EXPORT_PIECE_BYTES as u64).read_to_end(&mut piece)?;
+        if piece.is_empty() {
+            break;
+        }
+        out.write_all(base64::encode(&piece).as_bytes())?;
+    }
+    out.write_all(b"\"}")?;
+    Ok(())
+}
+
+/// Collects what `export_user` writes into pieces of `chunk_len` bytes and sends each one on.
+/// Only for use on a blocking thread.
+struct ChunkSink {
+    pending: Vec<u8>,
+    chunk_len: usize,
+    chunks: mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for ChunkSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= self.chunk_len {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.pending, Vec::with_capacity(self.chunk_len));
+        self.chunks.blocking_send(chunk).map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Export abandoned"))
+    }
+}
+
+/// Recreates an `export_user` bundle under `dest_path` for `user_id`, with new ids and blobs,
+/// in one transaction. Directories that already exist are merged into; a file that already
+/// exists fails the whole import with `Conflict`. Returns the number of nodes created.
+pub async fn import_user(pool: &DbPool, config: &VfsConfig, user_id: i64, dest_path: &str, bundle: &[u8]) -> Result<u64> {
+    let mut json = Vec::new();
+    GzDecoder::new(bundle).read_to_end(&mut json).map_err(|e| anyhow!("Bundle isn't gzipped: {}", e))?;
+    let bundle: Bundle = serde_json::from_slice(&json).map_err(|e| anyhow!("Invalid bundle: {}", e))?;
+    if bundle.version != BUNDLE_VERSION {
+        return Err(anyhow!("Unsupported bundle version {}", bundle.version));
+    }
+
+    let user: Option<(i64,)> = sqlx::query_as("SELECT id FROM users WHERE id = $1").bind(user_id).fetch_optional(pool).await?;
+    if user.is_none() {
+        return Err(anyhow!("User not found"));
+    }
+    let dest = Path::new(dest_path);
+    let dest_id = get_path_id(pool, config, user_id, dest).await?;
+    if dest_id.is_none() && dest != Path::new("/") {
+        return Err(anyhow!("Destination directory not found"));
+    }
+    let mut nodes = Vec::with_capacity(bundle.nodes.len());
+    let mut total_size = 0;
+    for node in bundle.nodes {
+        let relative = PathBuf::from(&node.path);
+        if !relative.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+            return Err(VfsError::InvalidPath.into());
+        }
+        let path = dest.join(&relative);
+        validate_path(&path, config.max_path_depth)?;
+        let content = node.content.as_deref().map(base64::decode).transpose()?;
+        total_size += content.as_ref().map_or(0, |content| content.len() as i64);
+        nodes.push((relative, path.to_string_lossy().to_string(), content, node));
+    }
+    check_quota(pool, user_id, total_size).await?;
+    // Directories merged into existing ones don't add nodes, but they're counted here anyway.
+    check_node_limit(pool, config, user_id, nodes.len() as i64).await?;
+
+    let mut tx = pool.begin().await?;
+    if let Some(dest_id) = dest_id {
+        ensure_owned_dir(&mut tx, user_id, dest_id).await?;
+    }
+    let mut written = Vec::new();
+    let result = async {
+        let mut dir_ids = HashMap::from([(PathBuf::new(), dest_id)]);
+        let mut created = 0;
+        for (relative, path, content, node) in &nodes {
+            let parent_id = *dir_ids.get(relative.parent().unwrap_or(Path::new(""))).ok_or_else(|| anyhow!("Bundle lists {} before its parent", node.path))?;
+            let name = relative.file_name().and_then(OsStr::to_str).ok_or(VfsError::InvalidPath)?;
+            if node.node_type == "dir" {
+                let existing: Option<(i64, String)> = sqlx::query_as("SELECT id, node_type FROM files WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND name = $3 AND is_trashed = FALSE")
+                    .bind(user_id)
+                    .bind(parent_id)
+                    .bind(name)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+                match existing {
+                    Some((id, node_type)) if node_type == "dir" => {
+                        dir_ids.insert(relative.clone(), Some(id));
+                        continue;
+                    }
+                    Some(_) => return Err(VfsError::Conflict.into()),
+                    None => {}
+                }
+            } else if node.node_type != "file" || content.is_none() {
+                return Err(anyhow!("Invalid bundle entry {}", node.path));
+            }
+            let new_node = NewNode { parent_id, name, path, node_type: &node.node_type, content: content.as_deref().unwrap_or_default(), mode: node.mode & 0o777 };
+            let (id, disk_path) = insert_node(&mut tx, config, user_id, &new_node).await?;
+            written.push((id, disk_path));
+            sqlx::query("UPDATE files SET created_at = $1, updated_at = $2 WHERE id = $3")
+                .bind(node.created_at)
+                .bind(node.updated_at)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            for (key, value) in &node.attrs {
+                sqlx::query("INSERT INTO file_attrs (file_id, key, value) VALUES ($1, $2, $3)").bind(id).bind(key).bind(value).execute(&mut *tx).await?;
+            }
+            if node.node_type == "dir" {
+                dir_ids.insert(relative.clone(), Some(id));
+            }
+            created += 1;
+        }
+        tx.commit().await?;
+        Ok(created)
+    }
+    .await;
+    if result.is_err() {
+        remove_disk_files(written).await;
+    }
+    result
 }
 
-pub async fn write_file_content(pool: &DbPool, user_id: i64, path_str: &str, base64_content: &str) -> Result<()> {
-    let file_id = get_path_id(pool, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("File not found"))?;
-    let content = base64::decode(base64_content)?;
+/// A user's quota alongside the bytes they have stored, split into live files and those in
+/// the trash (including everything under a trashed directory).
+pub struct Usage {
+    pub quota_bytes: Option<i64>,
+    pub used_bytes: i64,
+    pub trash_bytes: i64,
+}
 
-    let (disk_path_str,): (Option<String>,) = sqlx::query_as("SELECT disk_path FROM files WHERE id = ?")
-        .bind(file_id)
-        .fetch_one(pool)
-        .await?;
-    
-    if let Some(disk_path) = disk_path_str {
-        fs::write(disk_path, &content).await?;
-        sqlx::query("UPDATE files SET size = ?, updated_at = ? WHERE id = ?")
-            .bind(content.len() as i64)
-            .bind(Utc::now())
-            .bind(file_id)
-            .execute(pool)
-            .await?;
-        Ok(())
-    } else {
-        Err(anyhow!("Node is a directory, not a file"))
+pub async fn current_usage(pool: &DbPool, user_id: i64) -> Result<Usage> {
+    let (quota_bytes, total, trash_bytes): (Option<i64>, i64, i64) = sqlx::query_as(
+        "WITH RECURSIVE trashed(id) AS (
+            SELECT id FROM files WHERE owner_id = $1 AND is_trashed = TRUE
+            UNION
+            SELECT f.id FROM files f JOIN trashed t ON f.parent_id = t.id
+        )
+        SELECT
+            (SELECT quota_bytes FROM users WHERE id = $2),
+            (SELECT CAST(COALESCE(SUM(size), 0) AS BIGINT) FROM files WHERE owner_id = $3),
+            (SELECT CAST(COALESCE(SUM(f.size), 0) AS BIGINT) FROM files f JOIN trashed t ON f.id = t.id)"
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(Usage { quota_bytes, used_bytes: total - trash_bytes, trash_bytes })
+}
+
+/// Trashed files still occupy disk, so they count against the quota until the trash is emptied.
+async fn check_quota(pool: &DbPool, user_id: i64, additional: i64) -> Result<()> {
+    let usage = current_usage(pool, user_id).await?;
+    match usage.quota_bytes {
+        Some(quota) if usage.used_bytes + usage.trash_bytes + additional > quota => Err(VfsError::QuotaExceeded { quota }.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Trashed nodes still have rows, so they count towards the limit like they do the quota.
+async fn check_node_limit(pool: &DbPool, config: &VfsConfig, user_id: i64, additional: i64) -> Result<()> {
+    let Some(limit) = config.max_nodes_per_user else {
+        return Ok(());
+    };
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM files WHERE owner_id = $1").bind(user_id).fetch_one(pool).await?;
+    if count + additional > limit {
+        return Err(VfsError::NodeLimitExceeded { limit }.into());
+    }
+    Ok(())
+}
+
+/// Mode for a new node when the request doesn't give one: 0666 for files and 0777 for
+/// directories, less the umask.
+pub fn default_mode(node_type: &str, umask: u32) -> u32 {
+    let base = if node_type == "dir" { 0o777 } else { 0o666 };
+    base & !umask
+}
+
+/// `mode` overrides the umask-derived default and is stored as given.
+pub async fn create_node(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, node_type: &str, base64_content: Option<&str>, mode: Option<u32>) -> Result<()> {
+    let content = match base64_content {
+        Some(encoded) => base64::decode(encoded)?,
+        None => Vec::new(),
+    };
+    if node_type != "file" && !content.is_empty() {
+        return Err(anyhow!("Only files can be created with content"));
+    }
+    let mode = match mode {
+        Some(mode) if mode > 0o777 => return Err(anyhow!("Mode must be between 0 and 0777")),
+        Some(mode) => mode,
+        None => default_mode(node_type, config.umask),
+    };
+
+    let path = Path::new(path_str);
+    validate_path(path, config.max_path_depth)?;
+    let name = path.file_name().and_then(|s| s.to_str()).ok_or_else(|| anyhow!("Invalid path or name"))?;
+    let parent_path = path.parent().unwrap_or(Path::new("/"));
+    let parent_id = get_path_id(pool, config, user_id, parent_path).await?;
+    check_quota(pool, user_id, content.len() as i64).await?;
+    check_node_limit(pool, config, user_id, 1).await?;
+
+    let mut tx = pool.begin().await?;
+    if let Some(parent_id) = parent_id {
+        ensure_owned_dir(&mut tx, user_id, parent_id).await?;
     }
+    let node = NewNode { parent_id, name, path: path_str, node_type, content: &content, mode };
+    let (_, disk_path) = insert_node(&mut tx, config, user_id, &node).await?;
+    if let Err(e) = tx.commit().await {
+        remove_disk_files(vec![(0, disk_path)]).await;
+        return Err(e.into());
+    }
+    Ok(())
 }
 
-pub async fn create_node(pool: &DbPool, user_id: i64, path_str: &str, node_type: &str) -> Result<()> {
+/// Creates a directory at `path_str` filled with a copy of the named template, a subdirectory
+/// of `config.template_dir`, all in one transaction. Template files get default modes.
+pub async fn create_from_template(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, template: &str, mode: Option<u32>) -> Result<()> {
+    let entries = read_template(config, template).await?;
+    let mode = match mode {
+        Some(mode) if mode > 0o777 => return Err(anyhow!("Mode must be between 0 and 0777")),
+        Some(mode) => mode,
+        None => default_mode("dir", config.umask),
+    };
+
     let path = Path::new(path_str);
+    validate_path(path, config.max_path_depth)?;
+    let mut paths = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let entry_path = path.join(&entry.path);
+        validate_path(&entry_path, config.max_path_depth)?;
+        paths.push(entry_path.to_string_lossy().to_string());
+    }
     let name = path.file_name().and_then(|s| s.to_str()).ok_or_else(|| anyhow!("Invalid path or name"))?;
     let parent_path = path.parent().unwrap_or(Path::new("/"));
-    let parent_id = get_path_id(pool, user_id, parent_path).await?;
+    let parent_id = get_path_id(pool, config, user_id, parent_path).await?;
+    let total_size = entries.iter().filter_map(|entry| entry.content.as_ref()).map(|content| content.len() as i64).sum();
+    check_quota(pool, user_id, total_size).await?;
+    check_node_limit(pool, config, user_id, entries.len() as i64 + 1).await?;
 
     let mut tx = pool.begin().await?;
+    if let Some(parent_id) = parent_id {
+        ensure_owned_dir(&mut tx, user_id, parent_id).await?;
+    }
+    // Blobs written before a failure are removed again, as the rows pointing at them roll back.
+    let mut written = Vec::new();
+    let result = async {
+        let root = NewNode { parent_id, name, path: path_str, node_type: "dir", content: &[], mode };
+        let mut dir_ids = HashMap::from([(PathBuf::new(), insert_node(&mut tx, config, user_id, &root).await?.0)]);
+        for (entry, entry_path) in entries.iter().zip(&paths) {
+            let parent = entry.path.parent().unwrap_or(Path::new(""));
+            let node_type = if entry.content.is_some() { "file" } else { "dir" };
+            let node = NewNode {
+                parent_id: dir_ids.get(parent).copied(),
+                name: entry.path.file_name().and_then(OsStr::to_str).ok_or(VfsError::InvalidPath)?,
+                path: entry_path,
+                node_type,
+                content: entry.content.as_deref().unwrap_or_default(),
+                mode: default_mode(node_type, config.umask),
+            };
+            let (id, disk_path) = insert_node(&mut tx, config, user_id, &node).await?;
+            written.push((id, disk_path));
+            if entry.content.is_none() {
+                dir_ids.insert(entry.path.clone(), id);
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        remove_disk_files(written).await;
+    }
+    result
+}
+
+struct NewNode<'a> {
+    parent_id: Option<i64>,
+    name: &'a str,
+    path: &'a str,
+    node_type: &'a str,
+    content: &'a [u8],
+    mode: u32,
+}
 
-    let disk_path = if node_type == "file" {
-        let storage_root = env::var("STORAGE_ROOT").unwrap_or_else(|_| "/tmp/cde_storage".to_string());
-        fs::create_dir_all(&storage_root).await?;
+/// Stores a file's blob and inserts the node's row, returning its id and `disk_path`. A blob
+/// whose row can't be inserted is removed again.
+async fn insert_node(tx: &mut Transaction<'_, Db>, config: &VfsConfig, user_id: i64, node: &NewNode<'_>) -> Result<(i64, Option<String>)> {
+    let (disk_path, compressed) = if node.node_type == "file" {
+        fs::create_dir_all(&config.storage_root).await.map_err(map_storage_error)?;
         let disk_filename = Uuid::new_v4().to_string();
-        let path = Path::new(&storage_root).join(disk_filename);
-        fs::write(&path, "").await?;
-        Some(path.to_str().unwrap().to_string())
+        let path = config.storage_root.join(disk_filename);
+        let compressed = store_blob(config, &path, node.content).await?;
+        (Some(path.to_str().unwrap().to_string()), compressed)
     } else {
-        None
+        (None, false)
     };
 
-    sqlx::query("INSERT INTO files (owner_id, parent_id, name, node_type, disk_path, original_path) VALUES (?, ?, ?, ?, ?, ?)")
+    let now = Utc::now();
+    let inserted = sqlx::query_scalar("INSERT INTO files (owner_id, parent_id, name, node_type, disk_path, size, is_binary, compressed, mode, original_path, created_at, updated_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING id")
         .bind(user_id)
-        .bind(parent_id)
-        .bind(name)
-        .bind(node_type)
-        .bind(disk_path)
-        .bind(path_str)
+        .bind(node.parent_id)
+        .bind(node.name)
+        .bind(node.node_type)
+        .bind(&disk_path)
+        .bind(node.content.len() as i64)
+        .bind(is_probably_binary(node.content))
+        .bind(compressed)
+        .bind(i64::from(node.mode))
+        .bind(node.path)
+        .bind(now)
+        .bind(now)
+        .fetch_one(&mut **tx)
+        .await;
+    match inserted {
+        Ok(id) => Ok((id, disk_path)),
+        Err(e) => {
+            remove_disk_files(vec![(0, disk_path)]).await;
+            Err(map_conflict(e))
+        }
+    }
+}
+
+/// A node of a template relative to its root. `content` is `None` for directories.
+struct TemplateEntry {
+    path: PathBuf,
+    content: Option<Vec<u8>>,
+}
+
+/// Reads a template from disk, parents before their children. Symlinks are followed; anything
+/// that isn't a file or directory is skipped.
+async fn read_template(config: &VfsConfig, name: &str) -> Result<Vec<TemplateEntry>> {
+    let template_dir = config.template_dir.as_ref().ok_or_else(|| anyhow!("No templates are configured"))?;
+    let mut components = Path::new(name).components();
+    let root = match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(_)), None) => template_dir.join(name),
+        _ => return Err(anyhow!("Unknown template '{}'", name)),
+    };
+    if !fs::metadata(&root).await.is_ok_and(|metadata| metadata.is_dir()) {
+        return Err(anyhow!("Unknown template '{}'", name));
+    }
+
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        let mut children = Vec::new();
+        let mut read_dir = fs::read_dir(root.join(&dir)).await?;
+        while let Some(child) = read_dir.next_entry().await? {
+            children.push(child);
+        }
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let path = dir.join(child.file_name());
+            let metadata = fs::metadata(child.path()).await?;
+            if metadata.is_dir() {
+                pending.push(path.clone());
+                entries.push(TemplateEntry { path, content: None });
+            } else if metadata.is_file() {
+                entries.push(TemplateEntry { path, content: Some(fs::read(child.path()).await?) });
+            }
+            if entries.len() > MAX_TEMPLATE_NODES {
+                return Err(anyhow!("Template '{}' has more than {} nodes", name, MAX_TEMPLATE_NODES));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Where a node sat before it was trashed or moved, which `undo_placement` puts it back to.
+#[derive(Debug, Clone)]
+pub struct NodePlacement {
+    pub id: i64,
+    pub parent_id: Option<i64>,
+    pub name: String,
+}
+
+/// Returns the trashed node's id, which `restore_node` accepts.
+/// Without `recursive`, a directory is only trashed if it has no live children.
+pub async fn trash_node(pool: &DbPool, config: &VfsConfig, user_id: i64, path_str: &str, recursive: bool) -> Result<NodePlacement> {
+    let node_id = get_path_id(pool, config, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("Node not found"))?;
+    // The emptiness guard is part of the UPDATE, so a child created concurrently either stops
+    // the trash or lands after it, never inside a directory trashed as empty.
+    let trashed: Option<(Option<i64>, String)> = sqlx::query_as(
+        "UPDATE files SET is_trashed = TRUE, trashed_at = $1
+        WHERE id = $2 AND owner_id = $3 AND ($4 OR NOT EXISTS (SELECT 1 FROM files WHERE parent_id = $5 AND is_trashed = FALSE))
+        RETURNING parent_id, name"
+    )
+    .bind(Utc::now())
+    .bind(node_id)
+    .bind(user_id)
+    .bind(recursive)
+    .bind(node_id)
+    .fetch_optional(pool)
+    .await?;
+    match trashed {
+        Some((parent_id, name)) => Ok(NodePlacement { id: node_id, parent_id, name }),
+        None if has_live_children(pool, node_id).await? => Err(VfsError::DirectoryNotEmpty.into()),
+        None => Err(anyhow!("Node not found")),
+    }
+}
+
+/// Puts a node back exactly where `placement` says, taking it out of the trash if it's there,
+/// whatever has happened to it since. Unlike a restore it doesn't pick another name or parent:
+/// if the name is taken it fails with `Conflict`, and if the parent is no longer live it fails.
+/// Returns the node's path before (`None` if it was trashed) and after.
+pub async fn undo_placement(pool: &DbPool, user_id: i64, placement: &NodePlacement) -> Result<(Option<String>, String)> {
+    let mut tx = pool.begin().await?;
+    let exists: Option<(i64,)> = sqlx::query_as("SELECT id FROM files WHERE id = $1 AND owner_id = $2").bind(placement.id).bind(user_id).fetch_optional(&mut *tx).await?;
+    if exists.is_none() {
+        return Err(anyhow!("Node not found"));
+    }
+    let old_path = live_path_of(&mut tx, user_id, placement.id).await?.map(|path| path.to_string_lossy().to_string());
+    let parent_path = match placement.parent_id {
+        Some(parent_id) => {
+            if is_within(&mut tx, parent_id, placement.id).await? {
+                return Err(anyhow!("The node's former parent is now inside it"));
+            }
+            live_path_of(&mut tx, user_id, parent_id).await?.ok_or_else(|| anyhow!("The node's former parent no longer exists"))?
+        }
+        None => PathBuf::from("/"),
+    };
+    let new_path = parent_path.join(&placement.name).to_string_lossy().to_string();
+    sqlx::query("UPDATE files SET is_trashed = FALSE, trashed_at = NULL, parent_id = $1, name = $2, original_path = $3, updated_at = $4 WHERE id = $5")
+        .bind(placement.parent_id)
+        .bind(&placement.name)
+        .bind(&new_path)
+        .bind(Utc::now())
+        .bind(placement.id)
         .execute(&mut *tx)
-        .await?;
-    
+        .await
+        .map_err(map_conflict)?;
     tx.commit().await?;
-    Ok(())
+    Ok((old_path, new_path))
 }
 
-pub async fn trash_node(pool: &DbPool, user_id: i64, path_str: &str) -> Result<()> {
-    let node_id = get_path_id(pool, user_id, Path::new(path_str)).await?.ok_or_else(|| anyhow!("Node not found"))?;
-    sqlx::query("UPDATE files SET is_trashed = TRUE, trashed_at = ? WHERE id = ? AND owner_id = ?")
-        .bind(Utc::now())
-        .bind(node_id)
+/// Returns one page of the user's trash and the total number of trashed items.
+pub async fn list_trash(pool: &DbPool, user_id: i64, sort: TrashSort, page: Page) -> Result<(Vec<TrashedFileNode>, i64)> {
+    // Ties fall back to id so pages don't overlap or skip items.
+    let order_by = match sort {
+        TrashSort::Name => "name ASC, id ASC",
+        TrashSort::OriginalPath => "original_path ASC, id ASC",
+        TrashSort::TrashedAt => "trashed_at DESC, id DESC",
+    };
+    let query = format!(
+        "SELECT id, name, original_path, trashed_at FROM files WHERE owner_id = $1 AND is_trashed = TRUE ORDER BY {} LIMIT $2 OFFSET $3",
+        order_by
+    );
+    let items = sqlx::query_as(&query)
         .bind(user_id)
-        .execute(pool)
+        .bind(page.sql_limit())
+        .bind(i64::from(page.offset))
+        .fetch_all(pool)
         .await?;
-    Ok(())
+    let (total,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM files WHERE owner_id = $1 AND is_trashed = TRUE")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok((items, total))
 }
 
-pub async fn list_trash(pool: &DbPool, user_id: i64) -> Result<Vec<TrashedFileNode>> {
-    let items = sqlx::query_as(
-        "SELECT id, name, original_path, trashed_at FROM files WHERE owner_id = ? AND is_trashed = TRUE ORDER BY trashed_at DESC"
+pub async fn restore_node(pool: &DbPool, user_id: i64, node_id: i64) -> Result<String> {
+    let mut tx = pool.begin().await?;
+    let restored_path = restore_in_tx(&mut tx, user_id, node_id).await?;
+    tx.commit().await?;
+    Ok(restored_path)
+}
+
+/// Restores everything in the user's trash, ancestors before descendants so nested items
+/// land back inside their restored parents. Returns the restored paths in that order.
+pub async fn restore_all(pool: &DbPool, user_id: i64) -> Result<Vec<String>> {
+    let mut tx = pool.begin().await?;
+    let trashed: Vec<(i64,)> = sqlx::query_as(
+        "WITH RECURSIVE ancestry(id, depth) AS (
+            SELECT id, 0 FROM files WHERE owner_id = $1 AND parent_id IS NULL
+            UNION ALL
+            SELECT f.id, a.depth + 1 FROM files f JOIN ancestry a ON f.parent_id = a.id
+        )
+        SELECT f.id FROM files f JOIN ancestry a ON f.id = a.id
+        WHERE f.is_trashed = TRUE ORDER BY a.depth, f.id"
     )
     .bind(user_id)
-    .fetch_all(pool)
+    .fetch_all(&mut *tx)
     .await?;
-    Ok(items)
+
+    let mut restored = Vec::with_capacity(trashed.len());
+    for (node_id,) in trashed {
+        restored.push(restore_in_tx(&mut tx, user_id, node_id).await?);
+    }
+    tx.commit().await?;
+    Ok(restored)
 }
 
-pub async fn restore_node(pool: &DbPool, user_id: i64, node_id: i64) -> Result<String> {
-    let (original_path,): (String,) = sqlx::query_as("SELECT original_path FROM files WHERE id = ? AND owner_id = ?")
-        .bind(node_id)
-        .bind(user_id)
-        .fetch_one(pool)
-        .await?;
-    
-    sqlx::query("UPDATE files SET is_trashed = FALSE, trashed_at = NULL WHERE id = ?")
+/// The node goes back under the parent it was trashed from, which its row still records, so
+/// renames and moves of that parent since don't matter. If the parent is no longer live (it
+/// was trashed itself, or is gone), it goes to the user's home directory instead.
+async fn restore_in_tx(tx: &mut Transaction<'_, Db>, user_id: i64, node_id: i64) -> Result<String> {
+    let (parent_id, name): (Option<i64>, String) =
+        sqlx::query_as("SELECT parent_id, name FROM files WHERE id = $1 AND owner_id = $2 AND is_trashed = TRUE")
+            .bind(node_id)
+            .bind(user_id)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(|| anyhow!("Node not found in trash"))?;
+
+    let live_parent = match parent_id {
+        Some(parent_id) => live_path_of(tx, user_id, parent_id).await?.map(|path| (Some(parent_id), path)),
+        None => Some((None, PathBuf::from("/"))),
+    };
+    let (parent_id, parent_path) = match live_parent {
+        Some(parent) => parent,
+        None => home_dir_of(tx, user_id).await?,
+    };
+
+    // A live node may have taken the name since this one was trashed; restore alongside it
+    // under a suffixed name rather than creating duplicate siblings.
+    let restored_name = available_name(tx, user_id, parent_id, &name).await?;
+    let restored_path = parent_path.join(&restored_name).to_string_lossy().to_string();
+
+    sqlx::query("UPDATE files SET is_trashed = FALSE, trashed_at = NULL, parent_id = $1, name = $2, original_path = $3 WHERE id = $4")
+        .bind(parent_id)
+        .bind(&restored_name)
+        .bind(&restored_path)
         .bind(node_id)
-        .execute(pool)
-        .await?;
+        .execute(&mut **tx)
+        .await
+        .map_err(map_conflict)?;
 
-    Ok(original_path)
+    Ok(restored_path)
 }
 
-pub async fn permanently_delete_node(pool: &DbPool, user_id: i64, node_id: i64) -> Result<()> {
-    let row = sqlx::query("SELECT disk_path FROM files WHERE id = ? AND owner_id = ? AND is_trashed = TRUE")
-        .bind(node_id)
+/// The current paths of whichever of `node_ids` belong to the user and are live.
+pub async fn live_paths(pool: &DbPool, user_id: i64, node_ids: &[i64]) -> Result<Vec<(i64, String)>> {
+    let mut tx = pool.begin().await?;
+    let mut paths = Vec::new();
+    for &node_id in node_ids {
+        if let Some(path) = live_path_of(&mut tx, user_id, node_id).await? {
+            paths.push((node_id, path.to_string_lossy().to_string()));
+        }
+    }
+    tx.commit().await?;
+    Ok(paths)
+}
+
+/// The current path of a node, or `None` if it or any ancestor is missing or trashed.
+async fn live_path_of(tx: &mut Transaction<'_, Db>, user_id: i64, node_id: i64) -> Result<Option<PathBuf>> {
+    let chain: Vec<(Option<i64>, String, bool)> = sqlx::query_as(
+        "WITH RECURSIVE chain(id, parent_id, name, is_trashed, depth) AS (
+            SELECT id, parent_id, name, is_trashed, 0 FROM files WHERE id = $1 AND owner_id = $2
+            UNION ALL
+            SELECT f.id, f.parent_id, f.name, f.is_trashed, c.depth + 1 FROM files f JOIN chain c ON f.id = c.parent_id
+        )
+        SELECT parent_id, name, is_trashed FROM chain ORDER BY depth DESC"
+    )
+    .bind(node_id)
+    .bind(user_id)
+    .fetch_all(&mut **tx)
+    .await?;
+    let reaches_root = chain.first().is_some_and(|(parent_id, _, _)| parent_id.is_none());
+    if !reaches_root || chain.iter().any(|(_, _, is_trashed)| *is_trashed) {
+        return Ok(None);
+    }
+    Ok(Some(chain.iter().fold(PathBuf::from("/"), |path, (_, name, _)| path.join(name))))
+}
+
+/// The user's live home directory, or the root if that's gone too.
+async fn home_dir_of(tx: &mut Transaction<'_, Db>, user_id: i64) -> Result<(Option<i64>, PathBuf)> {
+    let home: Option<(i64, String)> = sqlx::query_as(
+        "SELECT h.id, h.name FROM files r JOIN files h ON h.parent_id = r.id
+        WHERE r.owner_id = $1 AND r.parent_id IS NULL AND r.name = 'home' AND r.is_trashed = FALSE
+        AND h.name = (SELECT username FROM users WHERE id = $2) AND h.is_trashed = FALSE"
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(match home {
+        Some((id, username)) => (Some(id), Path::new("/home").join(username)),
+        None => (None, PathBuf::from("/")),
+    })
+}
+
+/// A name for a new node under `parent_path` that doesn't collide with a live sibling, numbered
+/// the same way restores are. It's only a suggestion: a create can still race another one.
+pub async fn suggest_name(pool: &DbPool, config: &VfsConfig, user_id: i64, parent_path: &str, base_name: &str) -> Result<String> {
+    let parent = Path::new(parent_path);
+    validate_path(&parent.join(base_name), config.max_path_depth)?;
+    let mut components = Path::new(base_name).components();
+    if !matches!((components.next(), components.next()), (Some(std::path::Component::Normal(_)), None)) {
+        return Err(VfsError::InvalidPath.into());
+    }
+    let parent_id = get_path_id(pool, config, user_id, parent).await?;
+    if parent_id.is_none() && parent != Path::new("/") {
+        return Err(anyhow!("Directory not found"));
+    }
+    let mut tx = pool.begin().await?;
+    if let Some(parent_id) = parent_id {
+        ensure_owned_dir(&mut tx, user_id, parent_id).await?;
+    }
+    let name = available_name(&mut tx, user_id, parent_id, base_name).await?;
+    tx.commit().await?;
+    Ok(name)
+}
+
+async fn available_name(tx: &mut Transaction<'_, Db>, user_id: i64, parent_id: Option<i64>, name: &str) -> Result<String> {
+    let mut candidate = name.to_string();
+    for attempt in 1.. {
+        let taken: Option<(i64,)> = sqlx::query_as(
+            "SELECT id FROM files WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND name = $3 AND is_trashed = FALSE",
+        )
         .bind(user_id)
-        .fetch_optional(pool)
+        .bind(parent_id)
+        .bind(&candidate)
+        .fetch_optional(&mut **tx)
         .await?;
-    
-    if let Some(row) = row {
-        if let Ok(Some(disk_path)) = row.try_get::<Option<String>, _>("disk_path") {
-            let _ = fs::remove_file(disk_path).await;
+        if taken.is_none() {
+            break;
         }
-        sqlx::query("DELETE FROM files WHERE id = ?").bind(node_id).execute(pool).await?;
+        candidate = numbered_name(name, attempt);
+    }
+    Ok(candidate)
+}
+
+fn numbered_name(name: &str, n: u32) -> String {
+    let path = Path::new(name);
+    match (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|s| s.to_str())) {
+        (Some(stem), Some(ext)) => format!("{} ({}).{}", stem, n, ext),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+/// Without `recursive`, a directory is only deleted if nothing, trashed or not, is left in it.
+pub async fn permanently_delete_node(pool: &DbPool, user_id: i64, node_id: i64, recursive: bool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let nodes = sqlx::query_as::<_, (i64, Option<String>)>(
+        "WITH RECURSIVE subtree(id) AS (
+            SELECT id FROM files WHERE id = $1 AND owner_id = $2 AND is_trashed = TRUE
+            UNION
+            SELECT f.id FROM files f JOIN subtree s ON f.parent_id = s.id
+        )
+        SELECT f.id, f.disk_path FROM files f JOIN subtree s ON f.id = s.id"
+    )
+    .bind(node_id)
+    .bind(user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    // Only the user's own trashed node gets this far, so the guard below can't reveal anything
+    // about other nodes. The subtree holds the node and all its descendants, trashed or not.
+    if nodes.is_empty() {
+        return Err(anyhow!("Node not found"));
     }
+    if !recursive && nodes.len() > 1 {
+        return Err(VfsError::DirectoryNotEmpty.into());
+    }
+
+    purge_nodes(&mut tx, &nodes).await?;
+    tx.commit().await?;
+    remove_disk_files(nodes).await;
     Ok(())
 }
 
-pub async fn empty_trash(pool: &DbPool, user_id: i64) -> Result<()> {
-     let trashed_files = sqlx::query_as::<_, (i64, Option<String>)>("SELECT id, disk_path FROM files WHERE owner_id = ? AND is_trashed = TRUE")
-        .bind(user_id)
-        .fetch_all(pool)
+async fn has_live_children(pool: &DbPool, node_id: i64) -> Result<bool> {
+    let (exists,): (bool,) = sqlx::query_as("SELECT EXISTS(SELECT 1 FROM files WHERE parent_id = $1 AND is_trashed = FALSE)")
+        .bind(node_id)
+        .fetch_one(pool)
         .await?;
-    
+    Ok(exists)
+}
+
+/// Safe to run concurrently, e.g. from two tabs: the second call waits for the first to
+/// commit and then finds nothing left to delete.
+pub async fn empty_trash(pool: &DbPool, user_id: i64) -> Result<()> {
     let mut tx = pool.begin().await?;
-    for (id, disk_path) in trashed_files {
-        if let Some(path) = disk_path {
-            let _ = fs::remove_file(path).await;
-        }
-        sqlx::query("DELETE FROM files WHERE id = ?").bind(id).execute(&mut *tx).await?;
-    }
+    // Reading the rows with a no-op UPDATE takes the write lock up front. A SELECT would open a
+    // read snapshot that SQLite refuses to upgrade once a concurrent call has committed, failing
+    // with "database is locked" instead of waiting.
+    let nodes = sqlx::query_as::<_, (i64, Option<String>)>(
+        "WITH RECURSIVE subtree(id) AS (
+            SELECT id FROM files WHERE owner_id = $1 AND is_trashed = TRUE
+            UNION
+            SELECT f.id FROM files f JOIN subtree s ON f.parent_id = s.id
+        )
+        UPDATE files SET disk_path = disk_path WHERE id IN (SELECT id FROM subtree) RETURNING id, disk_path"
+    )
+    .bind(user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    purge_nodes(&mut tx, &nodes).await?;
     tx.commit().await?;
+    remove_disk_files(nodes).await;
+    Ok(())
+}
+
+/// Deletes the given rows, deepest first so no child outlives its parent mid-transaction. Rows
+/// already removed by a cascade are skipped.
+async fn purge_nodes(tx: &mut Transaction<'_, Db>, nodes: &[(i64, Option<String>)]) -> Result<()> {
+    for (id, _) in nodes.iter().rev() {
+        sqlx::query("DELETE FROM files WHERE id = $1").bind(id).execute(&mut **tx).await?;
+    }
     Ok(())
 }
 
-pub async fn move_node(pool: &DbPool, user_id: i64, old_path_str: &str, new_path_str: &str) -> Result<()> {
+/// Blobs are only unlinked once their rows are gone for good, so a rolled back
+/// transaction never leaves a row pointing at a missing file.
+async fn remove_disk_files(nodes: Vec<(i64, Option<String>)>) {
+    for disk_path in nodes.into_iter().filter_map(|(_, disk_path)| disk_path) {
+        match fs::remove_file(&disk_path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("Failed to remove blob {}: {}", disk_path, e),
+        }
+    }
+}
+
+/// A move only rewrites metadata: the node keeps its `disk_path`, so file contents are never
+/// copied or renamed on disk.
+/// Returns where the node was, for undoing the move.
+pub async fn move_node(pool: &DbPool, config: &VfsConfig, user_id: i64, old_path_str: &str, new_path_str: &str) -> Result<NodePlacement> {
     let old_path = Path::new(old_path_str);
     let new_path = Path::new(new_path_str);
+    validate_path(new_path, config.max_path_depth)?;
 
-    let node_id = get_path_id(pool, user_id, old_path).await?.ok_or_else(|| anyhow!("Source not found"))?;
+    let node_id = get_path_id(pool, config, user_id, old_path).await?.ok_or_else(|| anyhow!("Source not found"))?;
     
     let new_parent_path = new_path.parent().unwrap_or(Path::new("/"));
     let new_name = new_path.file_name().and_then(|s| s.to_str()).ok_or_else(|| anyhow!("Invalid new path"))?;
-    let new_parent_id = get_path_id(pool, user_id, new_parent_path).await?;
+    let new_parent_id = get_path_id(pool, config, user_id, new_parent_path).await?;
+    if new_parent_id.is_none() && new_parent_path != Path::new("/") {
+        return Err(anyhow!("Destination directory not found"));
+    }
 
-    sqlx::query("UPDATE files SET parent_id = ?, name = ?, original_path = ?, updated_at = ? WHERE id = ? AND owner_id = ?")
+    let mut tx = pool.begin().await?;
+    if let Some(parent_id) = new_parent_id {
+        ensure_owned_dir(&mut tx, user_id, parent_id).await?;
+        if is_within(&mut tx, parent_id, node_id).await? {
+            return Err(anyhow!("A directory can't be moved into itself"));
+        }
+    }
+    let (old_parent_id, old_name): (Option<i64>, String) = sqlx::query_as("SELECT parent_id, name FROM files WHERE id = $1 AND owner_id = $2")
+        .bind(node_id)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE files SET parent_id = $1, name = $2, original_path = $3, updated_at = $4 WHERE id = $5 AND owner_id = $6")
         .bind(new_parent_id)
         .bind(new_name)
         .bind(new_path_str)
         .bind(Utc::now())
         .bind(node_id)
         .bind(user_id)
-        .execute(pool)
-        .await?;
-        
+        .execute(&mut *tx)
+        .await
+        .map_err(map_conflict)?;
+    tx.commit().await?;
+    Ok(NodePlacement { id: node_id, parent_id: old_parent_id, name: old_name })
+}
+
+/// Moves each of `src_paths` into `dest_dir`, keeping its name, in one transaction. Items
+/// fail individually (missing source, name collision, ...) without undoing the others;
+/// only a bad destination fails the whole batch.
+pub async fn move_nodes(pool: &DbPool, config: &VfsConfig, user_id: i64, src_paths: &[String], dest_dir: &str) -> Result<Vec<MoveResult>> {
+    if src_paths.len() > MAX_MOVE_BATCH {
+        return Err(anyhow!("At most {} nodes can be moved at once", MAX_MOVE_BATCH));
+    }
+    let dest = Path::new(dest_dir);
+    validate_path(dest, config.max_path_depth)?;
+    let dest_id = get_path_id(pool, config, user_id, dest).await?;
+    if dest_id.is_none() && dest != Path::new("/") {
+        return Err(anyhow!("Destination directory not found"));
+    }
+
+    // Sources are resolved up front: the transaction may hold the only pooled connection.
+    let mut sources = Vec::with_capacity(src_paths.len());
+    for src_path in src_paths {
+        sources.push(resolve_move_source(pool, config, user_id, src_path, dest).await);
+    }
+
+    let mut tx = pool.begin().await?;
+    if let Some(dest_id) = dest_id {
+        ensure_owned_dir(&mut tx, user_id, dest_id).await?;
+    }
+    let mut results = Vec::with_capacity(src_paths.len());
+    for (src_path, source) in src_paths.iter().zip(sources) {
+        let (new_path, error) = match source {
+            Ok((node_id, new_path)) => {
+                // Each item runs in a savepoint so a failure rolls back just that item.
+                let mut item_tx = tx.begin().await?;
+                match move_in_tx(&mut item_tx, user_id, node_id, &new_path, dest_id).await {
+                    Ok(()) => {
+                        item_tx.commit().await?;
+                        (Some(new_path), None)
+                    }
+                    Err(e) => {
+                        item_tx.rollback().await?;
+                        (Some(new_path), Some(e))
+                    }
+                }
+            }
+            Err(e) => (None, Some(e)),
+        };
+        if let Some(e) = &error {
+            tracing::debug!("Moving {} failed: {:#}", src_path, e);
+        }
+        let (error, code) = error.map(|e| client_error(&e)).unzip();
+        results.push(MoveResult { src_path: src_path.clone(), new_path, error, code: code.flatten() });
+    }
+    tx.commit().await?;
+    Ok(results)
+}
+
+/// Returns the source's id and its path once moved into `dest`.
+async fn resolve_move_source(pool: &DbPool, config: &VfsConfig, user_id: i64, src_path: &str, dest: &Path) -> Result<(i64, String)> {
+    let name = Path::new(src_path).file_name().ok_or_else(|| anyhow!("Invalid source path"))?;
+    let new_path = dest.join(name);
+    validate_path(&new_path, config.max_path_depth)?;
+    let node_id = get_path_id(pool, config, user_id, Path::new(src_path)).await?.ok_or_else(|| anyhow!("Source not found"))?;
+    Ok((node_id, new_path.to_string_lossy().to_string()))
+}
+
+async fn move_in_tx(tx: &mut Transaction<'_, Db>, user_id: i64, node_id: i64, new_path: &str, dest_id: Option<i64>) -> Result<()> {
+    if let Some(dest_id) = dest_id {
+        if is_within(tx, dest_id, node_id).await? {
+            return Err(anyhow!("A directory can't be moved into itself"));
+        }
+    }
+    let new_name = Path::new(new_path).file_name().and_then(|s| s.to_str()).ok_or_else(|| anyhow!("Invalid new path"))?;
+    sqlx::query("UPDATE files SET parent_id = $1, name = $2, original_path = $3, updated_at = $4 WHERE id = $5 AND owner_id = $6")
+        .bind(dest_id)
+        .bind(new_name)
+        .bind(new_path)
+        .bind(Utc::now())
+        .bind(node_id)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(map_conflict)?;
     Ok(())
 }
 
-async fn get_path_id(pool: &DbPool, user_id: i64, path: &Path) -> Result<Option<i64>> {
-    let components: Vec<&str> = path.to_str().unwrap_or("").split('/').filter(|&s| !s.is_empty()).collect();
-    let mut current_id: Option<i64> = None;
-    for component in components {
-        let result: Option<(i64,)> = sqlx::query_as(
-            "SELECT id FROM files WHERE owner_id = ? AND parent_id IS ? AND name = ? AND is_trashed = FALSE",
+/// Whether `node_id` is `ancestor_id` or lies somewhere beneath it, trashed or not. Parenting a
+/// node under such a node would make a cycle, which the path walks would never get out of.
+async fn is_within(tx: &mut Transaction<'_, Db>, node_id: i64, ancestor_id: i64) -> Result<bool> {
+    let (inside,): (bool,) = sqlx::query_as(
+        "WITH RECURSIVE up(id, parent_id) AS (
+            SELECT id, parent_id FROM files WHERE id = $1
+            UNION ALL
+            SELECT f.id, f.parent_id FROM files f JOIN up u ON f.id = u.parent_id
         )
+        SELECT EXISTS (SELECT 1 FROM up WHERE id = $2)"
+    )
+    .bind(node_id)
+    .bind(ancestor_id)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(inside)
+}
+
+/// Re-checks inside the transaction that `dir_id` is a live directory owned by `user_id`, so a
+/// node can never be reparented under someone else's tree.
+async fn ensure_owned_dir(tx: &mut Transaction<'_, Db>, user_id: i64, dir_id: i64) -> Result<()> {
+    let row: Option<(i64, String)> = sqlx::query_as("SELECT owner_id, node_type FROM files WHERE id = $1 AND is_trashed = FALSE")
+        .bind(dir_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+    match row {
+        Some((owner_id, _)) if owner_id != user_id => Err(VfsError::PermissionDenied.into()),
+        Some((_, node_type)) if node_type != "dir" => Err(VfsError::NotADirectory.into()),
+        Some(_) => Ok(()),
+        None => Err(anyhow!("Destination directory not found")),
+    }
+}
+
+/// Rejects paths that can't be stored or matched faithfully as SQL text rather than
+/// coercing them, which would silently resolve to a different node. Depth is capped too, as
+/// it bounds the recursive walk that resolves the path.
+fn validate_path(path: &Path, max: usize) -> Result<&str, VfsError> {
+    let path_str = path.to_str().filter(|s| !s.contains('\0')).ok_or(VfsError::InvalidPath)?;
+    if path_str.split('/').filter(|s| !s.is_empty()).count() > max {
+        return Err(VfsError::PathTooDeep { max });
+    }
+    Ok(path_str)
+}
+
+/// The `(depth, name)` of each element of the JSON array of path components bound as `$1`,
+/// counting depth from 0.
+#[cfg(feature = "sqlite")]
+const PATH_COMPONENTS: &str = "SELECT key, value FROM json_each($1)";
+#[cfg(feature = "postgres")]
+const PATH_COMPONENTS: &str = "SELECT ord - 1, value FROM json_array_elements_text($1::json) WITH ORDINALITY AS t(value, ord)";
+
+/// Resolves the whole path in one statement, so it sees a single snapshot: a concurrent move
+/// makes the path either resolve as before or not at all, never to a mix of old and new
+/// ancestors. Callers that then read or write by the returned id are unaffected by a move
+/// that lands in between; clients holding an id should prefer the `ById` requests.
+async fn get_path_id(pool: &DbPool, config: &VfsConfig, user_id: i64, path: &Path) -> Result<Option<i64>> {
+    let components: Vec<&str> = validate_path(path, config.max_path_depth)?.split('/').filter(|&s| !s.is_empty()).collect();
+    if components.is_empty() {
+        return Ok(None);
+    }
+    let query = format!(
+        "WITH RECURSIVE
+            parts(depth, name) AS ({}),
+            walk(depth, id) AS (
+                SELECT 0, f.id FROM parts p JOIN files f ON f.name = p.name
+                WHERE p.depth = 0 AND f.owner_id = $2 AND f.parent_id IS NULL AND f.is_trashed = FALSE
+                UNION ALL
+                SELECT w.depth + 1, f.id FROM walk w
+                JOIN parts p ON p.depth = w.depth + 1
+                JOIN files f ON f.parent_id = w.id AND f.name = p.name
+                WHERE f.owner_id = $3 AND f.is_trashed = FALSE
+            )
+        SELECT id FROM walk WHERE depth = $4",
+        PATH_COMPONENTS
+    );
+    let result: Option<(i64,)> = sqlx::query_as(&query)
+        .bind(serde_json::to_string(&components)?)
+        .bind(user_id)
         .bind(user_id)
-        .bind(current_id)
-        .bind(component)
+        .bind(components.len() as i64 - 1)
         .fetch_optional(pool)
         .await?;
-        
-        current_id = result.map(|(id,)| id);
-        if current_id.is_none() { return Ok(None); }
-    }
-    Ok(current_id)
+    Ok(result.map(|(id,)| id))
 }
 
 pub fn resolve_path(cwd: &Path, target: &str, home: &str) -> PathBuf {
@@ -209,10 +2003,10 @@ pub fn resolve_path(cwd: &Path, target: &str, home: &str) -> PathBuf {
         cwd.join(target)
     };
 
-    let mut components = Vec::new();
+    let mut components: Vec<&OsStr> = Vec::new();
     for component in new_path.components() {
         match component {
-            std::path::Component::Normal(name) => components.push(name.to_str().unwrap()),
+            std::path::Component::Normal(name) => components.push(name),
             std::path::Component::ParentDir => { components.pop(); },
             _ => {}
         }
@@ -224,3 +2018,6 @@ pub fn resolve_path(cwd: &Path, target: &str, home: &str) -> PathBuf {
     }
     result
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,490 @@
+use super::*;
+use crate::protocol::NodeKind;
+use crate::test_support::{read_file, TestEnv};
+use std::sync::Arc;
+
+fn code(error: &anyhow::Error) -> Option<&'static str> {
+    error.downcast_ref::<VfsError>().map(VfsError::code)
+}
+
+async fn names(env: &TestEnv, path: &str) -> Vec<String> {
+    list_directory(&env.pool, env.vfs(), env.user_id, path, true, Page::default()).await.unwrap().into_iter().map(|node| node.name).collect()
+}
+
+async fn read_text(env: &TestEnv, path: &str) -> String {
+    match read_file_content(&env.pool, env.vfs(), env.user_id, path, ReadOptions { encoding: ContentEncoding::Utf8, ..Default::default() }).await.unwrap() {
+        ReadOutcome::Content(file) => file.content,
+        ReadOutcome::NotModified { .. } => panic!("expected content"),
+    }
+}
+
+#[tokio::test]
+async fn create_and_list() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    assert_eq!(names(&env, "/home/tester").await, ["docs", "src", "readme.md"]);
+    assert_eq!(names(&env, "/home/tester/docs").await, ["old", "notes.txt"]);
+    let listing = list_directory(&env.pool, env.vfs(), env.user_id, "/home/tester/docs", true, Page::default()).await.unwrap();
+    assert!(listing[0].has_children);
+    assert_eq!((listing[1].kind, listing[1].size), (NodeKind::File, 5));
+    assert_eq!(env.blob_count(), 4);
+}
+
+#[tokio::test]
+async fn write_then_read() {
+    let env = TestEnv::new().await;
+    env.write("/home/tester/a.txt", "one").await;
+    let disk_path = env.disk_path("/home/tester/a.txt").await;
+    write_file_content(&env.pool, env.vfs(), env.user_id, "/home/tester/a.txt", &base64::encode("two!"), WriteOptions::default()).await.unwrap();
+    assert_eq!(read_text(&env, "/home/tester/a.txt").await, "two!");
+    assert_eq!(std::fs::read(&disk_path).unwrap(), b"two!");
+    let (size, rev): (i64, i64) = sqlx::query_as("SELECT size, rev FROM files WHERE disk_path = $1").bind(&disk_path).fetch_one(&env.pool).await.unwrap();
+    assert_eq!((size, rev), (4, 1));
+}
+
+#[tokio::test]
+async fn move_reparents_without_copying() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let disk_path = env.disk_path("/home/tester/docs/notes.txt").await;
+    move_node(&env.pool, env.vfs(), env.user_id, "/home/tester/docs", "/home/tester/src/docs").await.unwrap();
+    assert_eq!(names(&env, "/home/tester").await, ["src", "readme.md"]);
+    assert_eq!(read_text(&env, "/home/tester/src/docs/notes.txt").await, "notes");
+    assert_eq!(env.disk_path("/home/tester/src/docs/notes.txt").await, disk_path);
+    assert_eq!(env.blob_count(), 4);
+}
+
+#[tokio::test]
+async fn trash_restore_and_empty() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let nodes = env.count("files").await;
+    let docs = trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/docs", true).await.unwrap().id;
+    assert_eq!(names(&env, "/home/tester").await, ["src", "readme.md"]);
+    let e = stat_node(&env.pool, env.vfs(), env.user_id, "/home/tester/docs/notes.txt").await.unwrap_err();
+    assert_eq!(e.to_string(), "Node not found");
+
+    assert_eq!(restore_node(&env.pool, env.user_id, docs).await.unwrap(), "/home/tester/docs");
+    assert_eq!(read_text(&env, "/home/tester/docs/old/draft.txt").await, "draft");
+
+    trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/docs", true).await.unwrap();
+    empty_trash(&env.pool, env.user_id).await.unwrap();
+    assert_eq!(env.count("files").await, nodes - 4);
+    assert_eq!(env.count("files WHERE is_trashed").await, 0);
+    assert_eq!(env.blob_count(), 2);
+}
+
+#[tokio::test]
+async fn empty_trash_removes_whole_tree() {
+    let env = TestEnv::new().await;
+    env.mkdir("/home/tester/p").await;
+    env.sample_tree("/home/tester/p").await;
+    let nodes = env.count("files").await;
+    trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/p", true).await.unwrap();
+    empty_trash(&env.pool, env.user_id).await.unwrap();
+    assert_eq!(env.count("files").await, nodes - 8);
+    assert_eq!(env.blob_count(), 0);
+}
+
+#[tokio::test]
+async fn delete_directory_needs_recursive() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let nodes = env.count("files").await;
+    let id = trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/docs", true).await.unwrap().id;
+    let e = permanently_delete_node(&env.pool, env.user_id, id, false).await.unwrap_err();
+    assert_eq!(code(&e), Some("DIRECTORY_NOT_EMPTY"));
+    assert_eq!(env.count("files").await, nodes);
+    permanently_delete_node(&env.pool, env.user_id, id, true).await.unwrap();
+    assert_eq!(env.count("files").await, nodes - 4);
+    assert_eq!(env.blob_count(), 2);
+}
+
+#[tokio::test]
+async fn invalid_paths_rejected() {
+    use std::os::unix::ffi::OsStrExt;
+    let env = TestEnv::new().await;
+    let not_utf8 = Path::new(std::ffi::OsStr::from_bytes(b"/home/\xff\xfe"));
+    let e = get_path_id(&env.pool, env.vfs(), env.user_id, not_utf8).await.unwrap_err();
+    assert_eq!(code(&e), Some("INVALID_PATH"));
+    let e = get_path_id(&env.pool, env.vfs(), env.user_id, Path::new("/home/a\0b")).await.unwrap_err();
+    assert_eq!(code(&e), Some("INVALID_PATH"));
+    let e = create_node(&env.pool, env.vfs(), env.user_id, "/home/tester/a\0b", "file", None, None).await.unwrap_err();
+    assert_eq!(code(&e), Some("INVALID_PATH"));
+    assert_eq!(env.count("files WHERE name LIKE 'a%'").await, 0);
+}
+
+#[tokio::test]
+async fn restore_renames_on_collision() {
+    let env = TestEnv::new().await;
+    env.write("/home/tester/a.txt", "old").await;
+    let old = trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/a.txt", false).await.unwrap().id;
+    env.write("/home/tester/a.txt", "new").await;
+    assert_eq!(restore_node(&env.pool, env.user_id, old).await.unwrap(), "/home/tester/a (1).txt");
+    assert_eq!(names(&env, "/home/tester").await, ["a (1).txt", "a.txt"]);
+    assert_eq!(env.read("/home/tester/a.txt").await, b"new");
+    assert_eq!(env.read("/home/tester/a (1).txt").await, b"old");
+    let duplicates: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM (SELECT 1 FROM files WHERE NOT is_trashed GROUP BY parent_id, name HAVING COUNT(*) > 1) AS duplicates")
+        .fetch_one(&env.pool)
+        .await
+        .unwrap();
+    assert_eq!(duplicates, 0);
+}
+
+#[tokio::test]
+async fn cross_owner_parent_rejected() {
+    let env = TestEnv::new().await;
+    let other = env.add_user("other").await;
+    create_node(&env.pool, env.vfs(), other, "/shared", "dir", None, None).await.unwrap();
+    let shared: i64 = sqlx::query_scalar("SELECT id FROM files WHERE owner_id = $1").bind(other).fetch_one(&env.pool).await.unwrap();
+    let mut tx = env.pool.begin().await.unwrap();
+    let e = ensure_owned_dir(&mut tx, env.user_id, shared).await.unwrap_err();
+    assert_eq!(code(&e), Some("PERMISSION_DENIED"));
+    let e = ensure_owned_dir(&mut tx, other, env.node_id("/home/tester").await).await.unwrap_err();
+    assert_eq!(code(&e), Some("PERMISSION_DENIED"));
+    ensure_owned_dir(&mut tx, other, shared).await.unwrap();
+}
+
+#[tokio::test]
+async fn move_into_another_users_directory_refused() {
+    let env = TestEnv::new().await;
+    let other = env.add_user("other").await;
+    create_node(&env.pool, env.vfs(), other, "/shared", "dir", None, None).await.unwrap();
+    env.write("/home/tester/a.txt", "a").await;
+
+    let e = move_node(&env.pool, env.vfs(), env.user_id, "/home/tester/a.txt", "/shared/a.txt").await.unwrap_err();
+    assert_eq!(e.to_string(), "Destination directory not found");
+    let e = move_nodes(&env.pool, env.vfs(), env.user_id, &["/home/tester/a.txt".to_string()], "/shared").await.unwrap_err();
+    assert_eq!(e.to_string(), "Destination directory not found");
+    assert_eq!(env.read("/home/tester/a.txt").await, b"a");
+    assert!(list_directory(&env.pool, env.vfs(), other, "/shared", true, Page::default()).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn move_keeps_blob() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let disk_path = env.disk_path("/home/tester/readme.md").await;
+    move_node(&env.pool, env.vfs(), env.user_id, "/home/tester/readme.md", "/home/tester/docs/README").await.unwrap();
+    assert_eq!(env.disk_path("/home/tester/docs/README").await, disk_path);
+    let results = move_nodes(&env.pool, env.vfs(), env.user_id, &["/home/tester/docs/README".to_string()], "/home/tester/src").await.unwrap();
+    assert_eq!(results[0].error, None);
+    assert_eq!(env.disk_path("/home/tester/src/README").await, disk_path);
+    assert_eq!(env.read("/home/tester/src/README").await, b"readme");
+    assert_eq!(std::fs::read(&disk_path).unwrap(), b"readme");
+}
+
+#[tokio::test]
+async fn child_of_file_rejected() {
+    let env = TestEnv::new().await;
+    env.write("/home/tester/notes.txt", "notes").await;
+    env.write("/home/tester/b", "b").await;
+    let nodes = env.count("files").await;
+    let e = create_node(&env.pool, env.vfs(), env.user_id, "/home/tester/notes.txt/sub", "dir", None, None).await.unwrap_err();
+    assert_eq!(code(&e), Some("NOT_A_DIRECTORY"));
+    let e = move_node(&env.pool, env.vfs(), env.user_id, "/home/tester/b", "/home/tester/notes.txt/b").await.unwrap_err();
+    assert_eq!(code(&e), Some("NOT_A_DIRECTORY"));
+    assert_eq!(env.count("files").await, nodes);
+    assert_eq!(names(&env, "/home/tester").await, ["b", "notes.txt"]);
+}
+
+#[tokio::test]
+async fn move_into_itself_rejected() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let e = move_node(&env.pool, env.vfs(), env.user_id, "/home/tester/docs", "/home/tester/docs/old/docs").await.unwrap_err();
+    assert_eq!(e.to_string(), "A directory can't be moved into itself");
+    let sources = ["/home/tester/docs".to_string(), "/home/tester/readme.md".to_string()];
+    let results = move_nodes(&env.pool, env.vfs(), env.user_id, &sources, "/home/tester/docs/old").await.unwrap();
+    assert_eq!(results[0].error.as_deref(), Some("A directory can't be moved into itself"));
+    assert_eq!(results[1].error, None);
+    let results = move_nodes(&env.pool, env.vfs(), env.user_id, &sources[..1], "/home/tester/docs").await.unwrap();
+    assert!(results[0].error.is_some());
+    assert_eq!(names(&env, "/home/tester").await, ["docs", "src"]);
+    assert_eq!(names(&env, "/home/tester/docs/old").await, ["draft.txt", "readme.md"]);
+}
+
+#[tokio::test]
+async fn timestamps_round_trip() {
+    let env = TestEnv::new().await;
+    let before = Utc::now();
+    env.write("/home/tester/a.txt", "a").await;
+    let node = stat_node(&env.pool, env.vfs(), env.user_id, "/home/tester/a.txt").await.unwrap();
+    assert!((node.created_at - before).num_seconds().abs() < 5);
+    assert_eq!(node.created_at, node.updated_at);
+    // SQLite keeps the text as written; Postgres stores an instant with no offset to check.
+    #[cfg(feature = "sqlite")]
+    {
+        let raw: String = sqlx::query_scalar("SELECT created_at FROM files WHERE id = $1").bind(node.id).fetch_one(&env.pool).await.unwrap();
+        assert!(raw.ends_with("+00:00"), "{}", raw);
+    }
+}
+
+#[tokio::test]
+async fn concurrent_empty_trash() {
+    let env = TestEnv::on_disk().await;
+    let nodes = env.count("files").await;
+    for round in 0..10 {
+        env.mkdir("/home/tester/d").await;
+        for i in 0..10 {
+            env.write(&format!("/home/tester/d/f{}", i), "x").await;
+        }
+        trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/d", true).await.unwrap();
+        let (a, b) = tokio::join!(empty_trash(&env.pool, env.user_id), empty_trash(&env.pool, env.user_id));
+        a.unwrap_or_else(|e| panic!("round {}: {}", round, e));
+        b.unwrap_or_else(|e| panic!("round {}: {}", round, e));
+        assert_eq!(env.count("files").await, nodes);
+        assert_eq!(env.blob_count(), 0);
+    }
+}
+
+#[tokio::test]
+async fn restore_when_parent_gone() {
+    let env = TestEnv::new().await;
+    env.mkdir("/home/tester/p").await;
+    env.mkdir("/home/tester/p/q").await;
+    env.write("/home/tester/p/q/f", "f").await;
+    env.write("/home/tester/p/g", "g").await;
+
+    // Parent renamed after the trash: the restore follows it.
+    let f = trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/p/q/f", false).await.unwrap().id;
+    move_node(&env.pool, env.vfs(), env.user_id, "/home/tester/p/q", "/home/tester/p/r").await.unwrap();
+    assert_eq!(restore_node(&env.pool, env.user_id, f).await.unwrap(), "/home/tester/p/r/f");
+
+    // Parent trashed: restored to the home directory.
+    let g = trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/p/g", false).await.unwrap().id;
+    trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/p", true).await.unwrap();
+    assert_eq!(restore_node(&env.pool, env.user_id, g).await.unwrap(), "/home/tester/g");
+
+    // Home gone too: restored to the root.
+    trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/g", false).await.unwrap();
+    let home = trash_node(&env.pool, env.vfs(), env.user_id, "/home", true).await.unwrap().id;
+    assert_eq!(restore_node(&env.pool, env.user_id, g).await.unwrap(), "/g");
+    assert_eq!(env.read("/g").await, b"g");
+
+    // Restoring ancestors first lets nested items rejoin their parents.
+    restore_node(&env.pool, env.user_id, home).await.unwrap();
+    assert_eq!(restore_all(&env.pool, env.user_id).await.unwrap(), ["/home/tester/p"]);
+    assert_eq!(env.read("/home/tester/p/r/f").await, b"f");
+}
+
+#[tokio::test]
+async fn overwrite_counts_only_the_growth() {
+    let env = TestEnv::new().await;
+    sqlx::query("UPDATE users SET quota_bytes = 10 WHERE id = $1").bind(env.user_id).execute(&env.pool).await.unwrap();
+    env.write("/home/tester/a", "123456789").await;
+    async fn write(env: &TestEnv, content: &str) -> Result<()> {
+        write_file_content(&env.pool, env.vfs(), env.user_id, "/home/tester/a", &base64::encode(content), WriteOptions::default()).await.map(drop)
+    }
+    write(&env, "abcdefghi").await.unwrap();
+    write(&env, "abcdefghij").await.unwrap();
+    let e = write(&env, "abcdefghijk").await.unwrap_err();
+    assert_eq!(code(&e), Some("QUOTA_EXCEEDED"));
+    assert_eq!(env.read("/home/tester/a").await, b"abcdefghij");
+    // Already over a lowered quota, a write that shrinks the file still goes through.
+    sqlx::query("UPDATE users SET quota_bytes = 5 WHERE id = $1").bind(env.user_id).execute(&env.pool).await.unwrap();
+    write(&env, "abcdefgh").await.unwrap();
+}
+
+#[tokio::test]
+async fn database_errors_are_generic() {
+    let env = TestEnv::new().await;
+    env.write("/home/tester/f", "").await;
+    sqlx::query("DROP TABLE file_attrs").execute(&env.pool).await.unwrap();
+    let e = get_attrs(&env.pool, env.vfs(), env.user_id, "/home/tester/f").await.unwrap_err();
+    assert!(format!("{:#}", e).contains("file_attrs"));
+    assert_eq!(client_error(&e), ("Internal server error".to_string(), Some("INTERNAL")));
+    assert_eq!(client_error(&anyhow!("File not found")), ("File not found".to_string(), None));
+    assert_eq!(client_error(&VfsError::Conflict.into()).1, Some("CONFLICT"));
+}
+
+#[tokio::test]
+async fn reads_during_moves_see_whole_trees() {
+    let env = Arc::new(TestEnv::on_disk().await);
+    for name in ["a", "b"] {
+        env.mkdir(&format!("/home/tester/{}", name)).await;
+        env.write(&format!("/home/tester/{}/f", name), name).await;
+    }
+    let mover = {
+        let env = env.clone();
+        tokio::spawn(async move {
+            for _ in 0..100 {
+                for (from, to) in [("a", "t"), ("b", "a"), ("t", "b")] {
+                    move_node(&env.pool, env.vfs(), env.user_id, &format!("/home/tester/{}", from), &format!("/home/tester/{}", to)).await.unwrap();
+                }
+            }
+        })
+    };
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let env = env.clone();
+            tokio::spawn(async move {
+                for _ in 0..100 {
+                    for path in ["/home/tester/a/f", "/home/tester/b/f", "/home/tester/t/f"] {
+                        match read_file(&env.pool, env.vfs(), env.user_id, path).await {
+                            Ok(content) => assert!(content == b"a" || content == b"b", "{:?}", content),
+                            Err(e) => assert_eq!(e.to_string(), "File not found"),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    mover.await.unwrap();
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    assert_eq!(names(&env, "/home/tester").await, ["a", "b"]);
+    assert_eq!(env.read("/home/tester/a/f").await, b"a");
+}
+
+#[tokio::test]
+async fn directories_listed_first() {
+    let env = TestEnv::new().await;
+    for (name, kind) in [("a", "file"), ("b", "dir"), ("d", "dir"), ("e", "file")] {
+        create_node(&env.pool, env.vfs(), env.user_id, &format!("/home/tester/{}", name), kind, None, None).await.unwrap();
+    }
+    // Anything that isn't a directory sorts with the files, including kinds the schema
+    // doesn't allow yet.
+    let home = env.node_id("/home/tester").await;
+    let mut conn = env.pool.acquire().await.unwrap();
+    #[cfg(feature = "sqlite")]
+    let lift_check = "PRAGMA ignore_check_constraints = ON";
+    #[cfg(feature = "postgres")]
+    let lift_check = "ALTER TABLE files DROP CONSTRAINT files_node_type_check";
+    sqlx::query(lift_check).execute(&mut *conn).await.unwrap();
+    sqlx::query("INSERT INTO files (owner_id, parent_id, name, node_type, mode, original_path) VALUES ($1, $2, 'c', 'symlink', 511, '/home/tester/c')")
+        .bind(env.user_id)
+        .bind(home)
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+    drop(conn);
+    assert_eq!(names(&env, "/home/tester").await, ["b", "d", "a", "c", "e"]);
+    let tree = get_tree(&env.pool, env.vfs(), env.user_id, "/home/tester", 1).await.unwrap();
+    assert_eq!(tree.iter().map(|node| node.name.as_str()).collect::<Vec<_>>(), ["b", "d", "a", "c", "e"]);
+}
+
+#[tokio::test]
+async fn permanent_delete_only_reaches_own_trash() {
+    let env = TestEnv::new().await;
+    let other = env.add_user("other").await;
+    create_node(&env.pool, env.vfs(), other, "/full", "dir", None, None).await.unwrap();
+    create_node(&env.pool, env.vfs(), other, "/full/f", "file", None, None).await.unwrap();
+    create_node(&env.pool, env.vfs(), other, "/empty", "dir", None, None).await.unwrap();
+    let full = trash_node(&env.pool, env.vfs(), other, "/full", true).await.unwrap().id;
+    let empty = trash_node(&env.pool, env.vfs(), other, "/empty", true).await.unwrap().id;
+    env.sample_tree("/home/tester").await;
+    let live = env.node_id("/home/tester/docs").await;
+    let nodes = env.count("files").await;
+
+    // Whether or not the directory has children, someone else's or a live node isn't found.
+    for id in [full, empty, live] {
+        let e = permanently_delete_node(&env.pool, env.user_id, id, false).await.unwrap_err();
+        assert_eq!(e.to_string(), "Node not found");
+    }
+    assert_eq!(env.count("files").await, nodes);
+}
+
+#[tokio::test]
+async fn trash_without_recursive_keeps_non_empty_directories() {
+    let env = TestEnv::new().await;
+    env.sample_tree("/home/tester").await;
+    let e = trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/docs", false).await.unwrap_err();
+    assert_eq!(code(&e), Some("DIRECTORY_NOT_EMPTY"));
+    assert_eq!(env.count("files WHERE is_trashed").await, 0);
+    // Trashed children don't count.
+    trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/src/main.rs", false).await.unwrap();
+    trash_node(&env.pool, env.vfs(), env.user_id, "/home/tester/src", false).await.unwrap();
+    assert_eq!(names(&env, "/home/tester").await, ["docs", "readme.md"]);
+}
+
+#[tokio::test]
+async fn openable_as_goes_by_content() {
+    let mut env = TestEnv::new().await;
+    env.config.vfs.compress_above = Some(16);
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(2, 2).write_to(&mut png, image::ImageFormat::Png).unwrap();
+    env.write("/home/tester/photo.txt", png.get_ref()).await;
+    env.write("/home/tester/fake.png", "just some text").await;
+    env.write("/home/tester/icon", "<?xml version=\"1.0\"?>\n<!-- drawn by hand -->\n<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"10\" height=\"10\"></svg>").await;
+    env.write("/home/tester/data.bin", [0u8, 1, 2]).await;
+
+    let kind = |path: &'static str, max| {
+        let env = &env;
+        async move {
+            let entry = stat_node(&env.pool, env.vfs(), env.user_id, path).await.unwrap();
+            openable_as(&env.pool, env.user_id, &entry, max).await.unwrap()
+        }
+    };
+    assert_eq!(kind("/home/tester", 0).await, OpenableAs::Directory);
+    assert_eq!(kind("/home/tester/photo.txt", 1024).await, OpenableAs::Image);
+    assert_eq!(kind("/home/tester/photo.txt", 8).await, OpenableAs::TooLarge);
+    assert_eq!(kind("/home/tester/fake.png", 1024).await, OpenableAs::Text);
+    assert_eq!(kind("/home/tester/icon", 1024).await, OpenableAs::Image);
+    assert_eq!(kind("/home/tester/data.bin", 1024).await, OpenableAs::Binary);
+}
+
+#[tokio::test]
+async fn resumed_read_hashes_the_whole_file() {
+    let mut env = TestEnv::new().await;
+    env.config.vfs.compress_above = Some(16);
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    env.write("/home/tester/big.bin", &content).await;
+
+    let mut file = open_file_from(&env.pool, env.vfs(), env.user_id, "/home/tester/big.bin", 150_000, Some(0)).await.unwrap();
+    assert_eq!(file.offset(), 150_000);
+    let mut rest = Vec::new();
+    loop {
+        let chunk = file.next_chunk(8192).await.unwrap();
+        if chunk.is_empty() {
+            break;
+        }
+        rest.extend(chunk);
+    }
+    assert_eq!(rest, content[150_000..]);
+    assert_eq!(file.sha256().unwrap(), sha256_hex(&content));
+
+    let past_end = open_file_from(&env.pool, env.vfs(), env.user_id, "/home/tester/big.bin", 200_001, None).await;
+    assert!(past_end.is_err());
+    write_file_content(&env.pool, env.vfs(), env.user_id, "/home/tester/big.bin", &base64::encode("new"), WriteOptions::default()).await.unwrap();
+    let changed = open_file_from(&env.pool, env.vfs(), env.user_id, "/home/tester/big.bin", 150_000, Some(0)).await.err().unwrap();
+    assert_eq!(code(&changed), Some("FILE_CHANGED"));
+}
+
+#[tokio::test]
+async fn export_streams_a_bundle_import_can_read() {
+    let mut env = TestEnv::new().await;
+    env.config.vfs.compress_above = Some(16);
+    env.sample_tree("/home/tester").await;
+    // Noise, so the bundle doesn't compress into a single chunk.
+    let mut seed = 1u32;
+    let content: Vec<u8> = (0..100_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 24) as u8
+        })
+        .collect();
+    env.write("/home/tester/big.bin", &content).await;
+    set_attr(&env.pool, env.vfs(), env.user_id, "/home/tester/readme.md", "color", Some("red")).await.unwrap();
+
+    let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::channel(1);
+    let collect = async {
+        let mut chunks = Vec::new();
+        while let Some(chunk) = chunks_rx.recv().await {
+            chunks.push(chunk);
+        }
+        chunks
+    };
+    let (exported, chunks) = tokio::join!(export_user(&env.pool, env.user_id, 4096, chunks_tx), collect);
+    exported.unwrap();
+    assert!(chunks.len() > 1);
+
+    let other = env.add_user("other").await;
+    import_user(&env.pool, env.vfs(), other, "/", &chunks.concat()).await.unwrap();
+    let read = |path| read_file(&env.pool, env.vfs(), other, path);
+    assert_eq!(read("/home/tester/big.bin").await.unwrap(), content);
+    assert_eq!(read("/home/tester/docs/old/draft.txt").await.unwrap(), b"draft");
+    let attrs = get_attrs(&env.pool, env.vfs(), other, "/home/tester/readme.md").await.unwrap();
+    assert_eq!(attrs.get("color").map(String::as_str), Some("red"));
+}